@@ -0,0 +1,260 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::{AlertCondition, AlertRule, Alerts, Config, Smtp};
+use crate::db;
+use crate::senders;
+
+// Number of IPs or organisations named in an alert, the others are only counted
+const MAX_LISTED: usize = 20;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// What the reports imported for a domain contain
+#[derive(Debug, Default)]
+struct DomainImport {
+    sources: Vec<db::ImportedSource>,
+    new_reporters: Vec<String>,
+}
+
+fn listing(items: &[&str]) -> String {
+    let mut listing = items[..items.len().min(MAX_LISTED)].join(", ");
+    if items.len() > MAX_LISTED {
+        listing.push_str(&format!(" and {} more", items.len() - MAX_LISTED));
+    }
+    listing
+}
+
+// The message of the alert, if the rule is triggered by the import
+fn check(
+    rule: &AlertRule,
+    import: &DomainImport,
+    providers: &senders::Providers,
+) -> Option<String> {
+    let count: u64 = import.sources.iter().map(|s| s.count).sum();
+    let failed: u64 = import
+        .sources
+        .iter()
+        .map(|s| s.count.saturating_sub(s.dmarc_pass))
+        .sum();
+
+    match rule.condition {
+        AlertCondition::FailRate(percent) => {
+            (failed > 0 && failed * 100 >= percent * count).then(|| {
+                format!(
+                    "{} of {} reported messages ({}%) failed DMARC",
+                    failed,
+                    count,
+                    failed * 100 / count
+                )
+            })
+        }
+        AlertCondition::FailCount(threshold) => (failed > 0 && failed >= threshold)
+            .then(|| format!("{} reported messages failed DMARC", failed)),
+        AlertCondition::NewSender => {
+            let new: Vec<&str> = import
+                .sources
+                .iter()
+                .filter(|s| !s.known)
+                .filter(|s| match s.source_ip.parse::<IpAddr>() {
+                    Ok(ip) => providers.classify(&ip).is_none(),
+                    Err(_e) => true,
+                })
+                .map(|s| s.source_ip.as_str())
+                .collect();
+            (!new.is_empty())
+                .then(|| format!("{} new unknown sending IPs: {}", new.len(), listing(&new)))
+        }
+        AlertCondition::NewReporter => {
+            let new: Vec<&str> = import.new_reporters.iter().map(String::as_str).collect();
+            (!new.is_empty()).then(|| format!("New reporting organisations: {}", listing(&new)))
+        }
+    }
+}
+
+fn send_mail(smtp: &Smtp, alert: &db::Alert) -> Result<()> {
+    let mut builder = Message::builder()
+        .from(
+            smtp.from
+                .parse::<Mailbox>()
+                .with_context(|| format!("Invalid sender address '{}'", smtp.from))?,
+        )
+        .subject(format!("DMARC alert for {}: {}", alert.domain, alert.rule));
+    for to in &smtp.to {
+        builder = builder.to(to
+            .parse::<Mailbox>()
+            .with_context(|| format!("Invalid recipient address '{}'", to))?);
+    }
+    let message = builder.body(format!(
+        "{}\n\nDomain: {}\nRule: {}\n",
+        alert.message, alert.domain, alert.rule
+    ))?;
+
+    let transport = if smtp.port == 465 {
+        SmtpTransport::relay(&smtp.server)?
+    } else {
+        SmtpTransport::starttls_relay(&smtp.server)?
+    };
+    let mut transport = transport.port(smtp.port).timeout(Some(DELIVERY_TIMEOUT));
+    if let (Some(user), Some(password)) = (&smtp.user, &smtp.password) {
+        transport = transport.credentials(Credentials::new(user.clone(), password.clone()));
+    }
+    transport.build().send(&message)?;
+    Ok(())
+}
+
+fn post_webhook(agent: &ureq::Agent, url: &str, alert: &db::Alert) -> Result<()> {
+    agent.post(url).send_json(alert)?;
+    Ok(())
+}
+
+// A failed delivery is logged, the alert stays in the history either way
+fn deliver(alerts: &Alerts, alert: &db::Alert, log: &mut impl Write) -> Result<()> {
+    if let Some(smtp) = &alerts.smtp {
+        if let Err(e) = send_mail(smtp, alert) {
+            writeln!(log, "Failed to mail alert {}: {:#}", alert.id, e)?;
+        }
+    }
+    if !alerts.webhooks.is_empty() {
+        let agent = ureq::AgentBuilder::new()
+            .tls_connector(Arc::new(native_tls::TlsConnector::new()?))
+            .timeout(DELIVERY_TIMEOUT)
+            .build();
+        for url in &alerts.webhooks {
+            if let Err(e) = post_webhook(&agent, url, alert) {
+                writeln!(log, "Failed to post alert {} to {}: {:#}", alert.id, url, e)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Evaluates the rules against the reports imported since the given time
+pub fn evaluate(
+    config: &Config,
+    database: &dyn db::Storage,
+    since: i64,
+    log: &mut impl Write,
+) -> Result<()> {
+    let alerts = &config.alerts;
+    if alerts.rules.is_empty() {
+        return Ok(());
+    }
+    let providers = senders::load_providers(config)?;
+
+    let mut imports: BTreeMap<String, DomainImport> = BTreeMap::new();
+    for source in database.get_imported_sources(since)? {
+        imports
+            .entry(source.domain.clone())
+            .or_default()
+            .sources
+            .push(source);
+    }
+    for (domain, org_name) in database.get_new_reporters(since)? {
+        imports
+            .entry(domain)
+            .or_default()
+            .new_reporters
+            .push(org_name);
+    }
+
+    let now = Utc::now().timestamp();
+    for rule in &alerts.rules {
+        for (domain, import) in &imports {
+            if rule.domain.as_ref().is_some_and(|d| d != domain) {
+                continue;
+            }
+            if let Some(message) = check(rule, import, &providers) {
+                let mut alert = db::Alert {
+                    id: 0,
+                    rule: rule.name.clone(),
+                    domain: domain.clone(),
+                    message,
+                    created_at: now,
+                    acknowledged_by: None,
+                    acknowledged_at: None,
+                };
+                alert.id = database.insert_alert(&alert)?;
+                writeln!(log, "Alert for {}: {}", alert.domain, alert.message)?;
+                deliver(alerts, &alert, log)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(source_ip: &str, count: u64, dmarc_pass: u64, known: bool) -> db::ImportedSource {
+        db::ImportedSource {
+            domain: String::from("example.com"),
+            source_ip: String::from(source_ip),
+            count,
+            dmarc_pass,
+            known,
+        }
+    }
+
+    fn rule(condition: AlertCondition) -> AlertRule {
+        AlertRule {
+            name: String::from("test"),
+            condition,
+            domain: None,
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let providers = senders::Providers::bundled();
+        let import = DomainImport {
+            sources: vec![
+                source("192.0.2.1", 80, 80, true),
+                source("198.51.100.7", 20, 0, false),
+                // Google
+                source("209.85.220.41", 10, 10, false),
+            ],
+            new_reporters: vec![String::from("google.com")],
+        };
+        let check = |condition| check(&rule(condition), &import, &providers);
+
+        assert_eq!(
+            Some(String::from(
+                "20 of 110 reported messages (18%) failed DMARC"
+            )),
+            check(AlertCondition::FailRate(15))
+        );
+        assert_eq!(None, check(AlertCondition::FailRate(20)));
+        assert!(check(AlertCondition::FailCount(20)).is_some());
+        assert_eq!(None, check(AlertCondition::FailCount(21)));
+        assert_eq!(
+            Some(String::from("1 new unknown sending IPs: 198.51.100.7")),
+            check(AlertCondition::NewSender)
+        );
+        assert_eq!(
+            Some(String::from("New reporting organisations: google.com")),
+            check(AlertCondition::NewReporter)
+        );
+
+        let quiet = DomainImport {
+            sources: vec![source("192.0.2.1", 10, 10, true)],
+            new_reporters: Vec::new(),
+        };
+        for condition in [
+            AlertCondition::FailRate(0),
+            AlertCondition::FailCount(0),
+            AlertCondition::NewSender,
+            AlertCondition::NewReporter,
+        ] {
+            assert_eq!(None, super::check(&rule(condition), &quiet, &providers));
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use anyhow::Result;
+use log::{error, info};
+use publicsuffix::{List, Psl};
+use rocket::fairing::AdHoc;
+use std::sync::{Arc, OnceLock};
+
+use crate::db;
+use crate::report::{Record, Report, SpfResult};
+
+// Copy of https://publicsuffix.org/list/public_suffix_list.dat
+const PUBLIC_SUFFIX_LIST: &str = include_str!("public_suffix_list.dat");
+
+// Result of our own DMARC evaluation of a record
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Pass,
+    // DKIM or SPF passed, but not for a domain aligned with the header from domain
+    NotAligned,
+    Fail,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct Alignment {
+    pub dkim: bool,
+    pub spf: bool,
+    pub verdict: Verdict,
+    // The policy_evaluated results of the receiver differ from ours
+    pub disagrees: bool,
+}
+
+impl Alignment {
+    // Derives the verdict from the aligned identifiers of the record
+    pub fn new(record: &Record, dkim: bool, spf: bool) -> Self {
+        let authenticated = record
+            .auth_results_dkim
+            .iter()
+            .any(|d| d.result.as_deref() == Some("pass"))
+            || record
+                .auth_results_spf
+                .iter()
+                .any(|s| s.result.as_deref() == Some("pass") && is_mail_from(s));
+        let verdict = if dkim || spf {
+            Verdict::Pass
+        } else if authenticated {
+            Verdict::NotAligned
+        } else {
+            Verdict::Fail
+        };
+
+        Alignment {
+            dkim,
+            spf,
+            verdict,
+            disagrees: dkim != (record.policy_evaluated_dkim == "pass")
+                || spf != (record.policy_evaluated_spf == "pass"),
+        }
+    }
+}
+
+fn public_suffix_list() -> &'static List {
+    static LIST: OnceLock<List> = OnceLock::new();
+    LIST.get_or_init(|| {
+        PUBLIC_SUFFIX_LIST
+            .parse()
+            .expect("bundled public suffix list is valid")
+    })
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+}
+
+// The registered domain below the public suffix, see RFC 7489 section 3.2
+pub fn organizational_domain(domain: &str) -> String {
+    let domain = normalize(domain);
+    match public_suffix_list().domain(domain.as_bytes()) {
+        Some(org) => String::from_utf8_lossy(org.as_bytes()).into_owned(),
+        // public suffixes and invalid names are their own organizational domain
+        None => domain,
+    }
+}
+
+// Identifier alignment in strict ("s") or relaxed (any other value, the default) mode
+pub fn is_aligned(mode: Option<&str>, header_from: &str, domain: &str) -> bool {
+    let (header_from, domain) = (normalize(header_from), normalize(domain));
+    if header_from.is_empty() || domain.is_empty() {
+        return false;
+    }
+    if mode == Some("s") {
+        header_from == domain
+    } else {
+        organizational_domain(&header_from) == organizational_domain(&domain)
+    }
+}
+
+// Only the MAIL FROM identity is used by DMARC, a HELO result doesn't authenticate it
+fn is_mail_from(spf: &SpfResult) -> bool {
+    matches!(spf.scope.as_deref(), None | Some("mfrom"))
+}
+
+pub fn evaluate(report: &Report, record: &Record) -> Alignment {
+    let header_from = &record.identifiers_header_from;
+    let dkim = record.auth_results_dkim.iter().any(|d| {
+        d.result.as_deref() == Some("pass")
+            && is_aligned(
+                report.policy_adkim.as_deref(),
+                header_from,
+                d.domain.as_deref().unwrap_or_default(),
+            )
+    });
+    // Not every receiver repeats the MAIL FROM domain in the SPF result
+    let spf = record.auth_results_spf.iter().any(|s| {
+        let spf_domain = s
+            .domain
+            .as_deref()
+            .or(record.identifiers_envelope_from.as_deref());
+        s.result.as_deref() == Some("pass")
+            && is_mail_from(s)
+            && is_aligned(
+                report.policy_aspf.as_deref(),
+                header_from,
+                spf_domain.unwrap_or_default(),
+            )
+    });
+
+    Alignment::new(record, dkim, spf)
+}
+
+pub fn analyze(report: &mut Report) {
+    let alignments: Vec<Alignment> = report
+        .records
+        .iter()
+        .map(|record| evaluate(report, record))
+        .collect();
+    for (record, alignment) in report.records.iter_mut().zip(alignments) {
+        record.alignment = Some(alignment);
+    }
+}
+
+// Analyzes reports that were stored before the analysis existed, a report that cannot be
+// analyzed is tried again on the next start
+pub fn analyze_stored(database: &dyn db::Storage) -> Result<()> {
+    let ids = database.get_unanalyzed_report_ids()?;
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let mut analyzed = 0;
+    for id in &ids {
+        let result = database
+            .get_report_without_blob(*id)
+            .and_then(|report| match report {
+                Some(mut report) => {
+                    analyze(&mut report);
+                    database.set_alignment(&report)
+                }
+                // deleted in the meantime
+                None => Ok(()),
+            });
+        match result {
+            Ok(_o) => analyzed += 1,
+            Err(e) => error!("Could not analyze report {}: {:#}", id, e),
+        }
+    }
+    info!(
+        "Analyzed the alignment of {} of {} stored reports",
+        analyzed,
+        ids.len()
+    );
+    Ok(())
+}
+
+// Runs the analysis of stored reports in the background once the server is up
+pub fn fairing(database: Arc<dyn db::Storage>) -> AdHoc {
+    AdHoc::on_liftoff("Analysis of stored reports", move |_rocket| {
+        Box::pin(async move {
+            rocket::tokio::task::spawn_blocking(move || {
+                if let Err(e) = analyze_stored(database.as_ref()) {
+                    error!("Analysis of stored reports failed: {:#}", e);
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::fixture::ReportFixture;
+    use crate::report::DkimResult;
+
+    #[test]
+    fn test_organizational_domain() {
+        assert_eq!("example.com", organizational_domain("mail.Example.COM."));
+        assert_eq!("example.co.uk", organizational_domain("a.b.example.co.uk"));
+        assert_eq!("co.uk", organizational_domain("co.uk"));
+
+        assert!(is_aligned(None, "example.com", "bounce.example.com"));
+        assert!(!is_aligned(Some("s"), "example.com", "bounce.example.com"));
+        assert!(is_aligned(Some("s"), "example.com", "EXAMPLE.com"));
+        assert!(!is_aligned(None, "example.co.uk", "other.co.uk"));
+        assert!(!is_aligned(None, "example.com", ""));
+    }
+
+    #[test]
+    fn test_analyze() {
+        let mut report = ReportFixture::default().report();
+        report.policy_adkim = Some(String::from("s"));
+        let base = report.records[0].clone();
+        let record = |dkim: &str, spf: &str, auth_dkim: Option<&str>, auth_spf: Option<&str>| {
+            let mut record = base.clone();
+            record.policy_evaluated_dkim = String::from(dkim);
+            record.policy_evaluated_spf = String::from(spf);
+            record.auth_results_dkim = auth_dkim
+                .map(|domain| DkimResult {
+                    domain: Some(String::from(domain)),
+                    selector: None,
+                    result: Some(String::from("pass")),
+                    human_result: None,
+                })
+                .into_iter()
+                .collect();
+            record.auth_results_spf[0].domain = auth_spf.map(String::from);
+            record
+        };
+        let mut failed = record("fail", "fail", None, Some("example.com"));
+        failed.auth_results_spf[0].result = Some(String::from("fail"));
+        let mut envelope = record("fail", "pass", None, None);
+        envelope.identifiers_envelope_from = Some(String::from("bounce.example.com"));
+        let mut helo = record("fail", "fail", None, Some("example.com"));
+        helo.auth_results_spf[0].scope = Some(String::from("helo"));
+        report.records = vec![
+            record(
+                "pass",
+                "pass",
+                Some("mail.example.com"),
+                Some("bounce.example.com"),
+            ),
+            record(
+                "fail",
+                "fail",
+                Some("esp.example.net"),
+                Some("esp.example.net"),
+            ),
+            failed,
+            envelope,
+            helo,
+        ];
+        analyze(&mut report);
+        let alignments: Vec<Alignment> = report
+            .records
+            .into_iter()
+            .map(|record| record.alignment.unwrap())
+            .collect();
+
+        // strict DKIM alignment does not accept the subdomain, relaxed SPF alignment does
+        assert_eq!(
+            Alignment {
+                dkim: false,
+                spf: true,
+                verdict: Verdict::Pass,
+                disagrees: true,
+            },
+            alignments[0]
+        );
+        assert_eq!(Verdict::NotAligned, alignments[1].verdict);
+        assert!(!alignments[1].disagrees);
+        assert_eq!(Verdict::Fail, alignments[2].verdict);
+        // without an SPF domain the envelope from domain is used
+        assert!(alignments[3].spf);
+        assert_eq!(Verdict::Pass, alignments[3].verdict);
+        // a pass of the HELO identity is neither aligned nor authenticates the message
+        assert!(!alignments[4].spf);
+        assert_eq!(Verdict::Fail, alignments[4].verdict);
+    }
+}
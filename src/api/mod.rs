@@ -0,0 +1,289 @@
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::{Catcher, Request, Response, Route, State};
+use std::collections::HashMap;
+
+use crate::auth;
+use crate::config;
+use crate::db::{self, ReportFilter};
+use crate::report;
+use crate::{with_db, DbConn};
+
+const DEFAULT_PER_PAGE: u32 = 50;
+const MAX_PER_PAGE: u32 = 500;
+const DEFAULT_DAYS: u16 = 30;
+
+#[derive(Serialize)]
+struct ReportPage {
+    page: u32,
+    per_page: u32,
+    total: u32,
+    reports: Vec<report::Report>,
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    error: String,
+}
+
+struct Unauthorized {
+    basic: bool,
+}
+
+impl<'r> Responder<'r, 'static> for Unauthorized {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build_from(
+            Json(ApiError {
+                error: String::from("Authentication required"),
+            })
+            .respond_to(req)?,
+        )
+        .status(Status::Unauthorized)
+        .finalize();
+        // Tells clients that they may retry with basic authentication
+        if self.basic {
+            response.set_header(Header::new(
+                "WWW-Authenticate",
+                "Basic realm=\"DMARC Analyzer\"",
+            ));
+        }
+        Ok(response)
+    }
+}
+
+#[catch(400)]
+fn bad_request(_req: &Request) -> Json<ApiError> {
+    Json(ApiError {
+        error: String::from("Bad request"),
+    })
+}
+
+#[catch(401)]
+fn unauthorized(req: &Request) -> Unauthorized {
+    Unauthorized {
+        basic: req
+            .rocket()
+            .state::<config::Config>()
+            .map(|config| config.auth.basic)
+            .unwrap_or(false),
+    }
+}
+
+#[catch(403)]
+fn forbidden(_req: &Request) -> Json<ApiError> {
+    Json(ApiError {
+        error: String::from("Access denied"),
+    })
+}
+
+#[catch(404)]
+fn not_found(req: &Request) -> Json<ApiError> {
+    Json(ApiError {
+        error: format!("Not found: {}", req.uri()),
+    })
+}
+
+#[catch(500)]
+fn internal_error(_req: &Request) -> Json<ApiError> {
+    Json(ApiError {
+        error: String::from("Internal server error"),
+    })
+}
+
+#[get("/domains")]
+async fn domains(user: auth::User, db_conn: &State<DbConn>) -> Json<Vec<String>> {
+    with_db(db_conn, move |db_conn| {
+        Json(user.visible_domains(db_conn.get_domains().expect("get domains")))
+    })
+    .await
+}
+
+#[get("/domains/<domain>/reports?<page>&<per_page>&<account>")]
+async fn reports(
+    user: auth::User,
+    domain: String,
+    page: Option<u32>,
+    per_page: Option<u32>,
+    account: Option<String>,
+    db_conn: &State<DbConn>,
+) -> Result<Json<ReportPage>, Status> {
+    with_db(db_conn, move |db_conn| {
+        user.check_domain(&domain)?;
+        let page = page.unwrap_or(1).max(1);
+        let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+        let filter = ReportFilter {
+            domain,
+            account,
+            ..Default::default()
+        };
+
+        // A page beyond the range of the offset can't hold any report
+        let offset = (page - 1).checked_mul(per_page).ok_or(Status::BadRequest)?;
+        let reports = db_conn
+            .get_reports(&filter, offset, Some(per_page))
+            .expect("get reports");
+
+        Ok(Json(ReportPage {
+            page,
+            per_page,
+            total: db_conn.count_reports(&filter).expect("count reports"),
+            reports,
+        }))
+    })
+    .await
+}
+
+#[get("/reports/<id>")]
+async fn single_report(
+    user: auth::User,
+    id: i64,
+    db_conn: &State<DbConn>,
+) -> Result<Json<report::Report>, Status> {
+    with_db(db_conn, move |db_conn| {
+        let mut report = db_conn
+            .get_report(id)
+            .expect("get report")
+            .ok_or(Status::NotFound)?;
+        user.check_domain(report.policy_domain.as_deref().unwrap_or_default())?;
+        // the raw XML is not part of the API
+        report.blob = None;
+        Ok(Json(report))
+    })
+    .await
+}
+
+#[get("/stats?<days>")]
+async fn stats(
+    user: auth::User,
+    days: Option<u16>,
+    db_conn: &State<DbConn>,
+) -> Json<HashMap<String, db::BasicStats>> {
+    with_db(db_conn, move |db_conn| {
+        let mut stats = db_conn
+            .get_basic_stats(days.unwrap_or(DEFAULT_DAYS))
+            .expect("get basic stats");
+        user.retain_visible(&mut stats);
+        Json(stats)
+    })
+    .await
+}
+
+#[get("/stats/policy?<days>")]
+async fn policy_stats(
+    user: auth::User,
+    days: Option<u16>,
+    db_conn: &State<DbConn>,
+) -> Json<HashMap<String, HashMap<String, db::PolicyEvaluatedStats>>> {
+    with_db(db_conn, move |db_conn| {
+        let mut stats = db_conn
+            .get_policy_evaluated_stats(days.unwrap_or(DEFAULT_DAYS))
+            .expect("get policy evaluated stats");
+        user.retain_visible(&mut stats);
+        Json(stats)
+    })
+    .await
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![domains, reports, single_report, stats, policy_stats]
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![
+        bad_request,
+        unauthorized,
+        forbidden,
+        not_found,
+        internal_error
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::fixture::ReportFixture;
+    use rocket::local::blocking::Client;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_reports() {
+        let storage = db::sqlite::SqliteStorage::new(Path::new(":memory:")).unwrap();
+        for report_id in ["1", "2", "3"] {
+            let report = ReportFixture {
+                report_id,
+                ..Default::default()
+            }
+            .report();
+            db::Storage::insert_report(&storage, &report).unwrap();
+        }
+        let password_hash = auth::hash_password("secret").unwrap();
+        db::Storage::insert_user(&storage, "api", &password_hash, auth::ROLE_ADMIN, 0).unwrap();
+        let conn: DbConn = Arc::new(storage);
+        let config = config::Config {
+            database: config::Database::Sqlite,
+            db_path: PathBuf::from(":memory:"),
+            accounts: Vec::new(),
+            fetch_interval: 0,
+            auth: config::Auth {
+                basic: true,
+                ..Default::default()
+            },
+            providers_file: None,
+            enrichment: config::Enrichment::default(),
+            alerts: config::Alerts::default(),
+        };
+        let rocket = rocket::build()
+            .mount("/api/v1", routes())
+            .register("/api", catchers())
+            .manage(conn)
+            .manage(config);
+        let client = Client::tracked(rocket).unwrap();
+
+        let response = client.get("/api/v1/domains").dispatch();
+        assert_eq!(Status::Unauthorized, response.status());
+        assert!(response.headers().contains("WWW-Authenticate"));
+
+        // api:secret
+        let credentials = Header::new("Authorization", "Basic YXBpOnNlY3JldA==");
+        let response = client
+            .get("/api/v1/domains")
+            .header(credentials.clone())
+            .dispatch();
+        assert_eq!(Status::Ok, response.status());
+        assert_eq!(r#"["example.com"]"#, response.into_string().unwrap());
+
+        let response = client
+            .get("/api/v1/domains/example.com/reports?page=2&per_page=2")
+            .header(credentials.clone())
+            .dispatch();
+        let page: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(3, page["total"]);
+        assert_eq!(1, page["reports"].as_array().unwrap().len());
+        assert_eq!(serde_json::Value::Null, page["reports"][0]["blob"]);
+
+        // The offset of this page doesn't fit into the database query
+        let response = client
+            .get("/api/v1/domains/example.com/reports?page=4294967295&per_page=100")
+            .header(credentials.clone())
+            .dispatch();
+        assert_eq!(Status::BadRequest, response.status());
+
+        let id = page["reports"][0]["id"].as_i64().unwrap();
+        let response = client
+            .get(format!("/api/v1/reports/{}", id))
+            .header(credentials.clone())
+            .dispatch();
+        let report: serde_json::Value = response.into_json().unwrap();
+        assert_eq!("192.0.2.1", report["records"][0]["source_ip"]);
+
+        let response = client
+            .get("/api/v1/reports/4711")
+            .header(credentials)
+            .dispatch();
+        assert_eq!(Status::NotFound, response.status());
+        let error: serde_json::Value = response.into_json().unwrap();
+        assert!(error["error"].as_str().unwrap().starts_with("Not found"));
+    }
+}
@@ -0,0 +1,203 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::alerts;
+use crate::auth;
+use crate::config::arguments::UserCommand;
+use crate::config::Config;
+use crate::db;
+use crate::enrichment;
+use crate::local_import;
+use crate::report;
+use crate::scheduler;
+
+// Exit codes of the headless commands
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+
+fn exit_code(result: Result<()>) -> i32 {
+    match result {
+        Ok(_o) => EXIT_OK,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            EXIT_FAILURE
+        }
+    }
+}
+
+pub fn fetch(config: &Config, database: &dyn db::Storage) -> i32 {
+    let ip_databases = match enrichment::IpDatabases::open(config) {
+        Ok(ip_databases) => ip_databases,
+        Err(e) => return exit_code(Err(e)),
+    };
+    let run = scheduler::run_fetch(config, database, &ip_databases, scheduler::TRIGGER_CLI);
+    print!("{}", run.log);
+
+    exit_code(match run.error {
+        Some(error) => Err(anyhow!(error)),
+        None => Ok(()),
+    })
+}
+
+pub fn import(config: &Config, path: &Path, database: &dyn db::Storage) -> i32 {
+    let ip_databases = match enrichment::IpDatabases::open(config) {
+        Ok(ip_databases) => ip_databases,
+        Err(e) => return exit_code(Err(e)),
+    };
+    let started_at = chrono::Utc::now().timestamp();
+    let mut logbuf = Vec::new();
+    let imported =
+        local_import::LocalImport::new(path).import_reports(database, &ip_databases, &mut logbuf);
+    // reports imported before an error are enriched and checked as well
+    let enriched = enrichment::enrich(config, database, &ip_databases, &mut logbuf);
+    let evaluated = alerts::evaluate(config, database, started_at, &mut logbuf);
+    print!("{}", String::from_utf8_lossy(&logbuf));
+
+    let errors: Vec<String> = [imported, enriched, evaluated]
+        .into_iter()
+        .filter_map(|result| result.err())
+        .map(|e| format!("{:#}", e))
+        .collect();
+    exit_code(if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(errors.join("; ")))
+    })
+}
+
+pub fn enrich(config: &Config, database: &dyn db::Storage) -> i32 {
+    let mut logbuf = Vec::new();
+    let result = enrichment::IpDatabases::open(config)
+        .and_then(|ip_databases| enrichment::enrich(config, database, &ip_databases, &mut logbuf));
+    print!("{}", String::from_utf8_lossy(&logbuf));
+
+    exit_code(result)
+}
+
+pub fn stats(database: &dyn db::Storage, days: u16) -> i32 {
+    exit_code(print_stats(database, days))
+}
+
+fn print_stats(database: &dyn db::Storage, days: u16) -> Result<()> {
+    let stats = database.get_basic_stats(days)?;
+    let mut domains: Vec<&String> = stats.keys().collect();
+    domains.sort();
+
+    println!("Stats for the last {} days", days);
+    for domain in domains {
+        let s = &stats[domain];
+        println!(
+            "{}: DKIM passed {} failed {} -- SPF passed {} failed {}",
+            domain, s.dkim_passed, s.dkim_failed, s.spf_passed, s.spf_failed
+        );
+    }
+    Ok(())
+}
+
+pub fn export(database: &dyn db::Storage, domain: Option<String>, output: Option<PathBuf>) -> i32 {
+    exit_code(write_export(database, domain, output))
+}
+
+fn write_export(
+    database: &dyn db::Storage,
+    domain: Option<String>,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let domains = match domain {
+        Some(domain) => vec![domain],
+        None => database.get_domains()?,
+    };
+
+    // the raw XML is not loaded, all its data is contained in the export
+    let mut reports: Vec<report::Report> = Vec::new();
+    for domain in domains {
+        reports.extend(database.get_all_reports_for_domain(domain, None)?);
+    }
+
+    let json = serde_json::to_string_pretty(&reports)?;
+    match output {
+        Some(path) => {
+            let mut file = File::create(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            writeln!(file, "{}", json)?;
+        }
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+pub fn user(database: &dyn db::Storage, cmd: UserCommand) -> i32 {
+    exit_code(manage_user(database, cmd))
+}
+
+fn manage_user(database: &dyn db::Storage, cmd: UserCommand) -> Result<()> {
+    match cmd {
+        UserCommand::List => {
+            for user in database.get_users()? {
+                if user.role == auth::ROLE_ADMIN {
+                    println!("{} ({})", user.name, user.role);
+                } else {
+                    let domains = database.get_user_domains(user.id)?;
+                    println!("{} ({}): {}", user.name, user.role, domains.join(", "));
+                }
+            }
+        }
+        UserCommand::Add { name, role } => {
+            if database.get_user(&name)?.is_some() {
+                return Err(anyhow!("User {} already exists", name));
+            }
+            let password_hash = auth::hash_password(&read_password()?)?;
+            database.insert_user(&name, &password_hash, &role, chrono::Utc::now().timestamp())?;
+            println!("Added user {} with role {}", name, role);
+        }
+        UserCommand::Passwd { name } => {
+            let password_hash = auth::hash_password(&read_password()?)?;
+            if !database.set_user_password(&name, &password_hash)? {
+                return Err(anyhow!("No user {}", name));
+            }
+            println!("Changed password of user {}", name);
+        }
+        UserCommand::Role { name, role } => {
+            if !database.set_user_role(&name, &role)? {
+                return Err(anyhow!("No user {}", name));
+            }
+            println!("User {} has the role {} now", name, role);
+        }
+        UserCommand::Grant { name, domain } => {
+            let user = get_user(database, &name)?;
+            database.add_user_domain(user.id, &domain)?;
+            println!("User {} has access to {} now", name, domain);
+        }
+        UserCommand::Revoke { name, domain } => {
+            let user = get_user(database, &name)?;
+            if !database.remove_user_domain(user.id, &domain)? {
+                return Err(anyhow!("User {} has no access to {}", name, domain));
+            }
+            println!("Removed the access of user {} to {}", name, domain);
+        }
+        UserCommand::Delete { name } => {
+            if !database.delete_user(&name)? {
+                return Err(anyhow!("No user {}", name));
+            }
+            println!("Deleted user {}", name);
+        }
+    }
+    Ok(())
+}
+
+fn get_user(database: &dyn db::Storage, name: &str) -> Result<db::User> {
+    database
+        .get_user(name)?
+        .ok_or_else(|| anyhow!("No user {}", name))
+}
+
+fn read_password() -> Result<String> {
+    // Prompts on a terminal, otherwise the first line of stdin is taken
+    let password = rpassword::prompt_password_stderr("Password: ")?;
+    if password.is_empty() {
+        return Err(anyhow!("The password must not be empty"));
+    }
+    Ok(password)
+}
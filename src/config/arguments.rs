@@ -15,22 +15,129 @@ pub struct Opt {
     pub db_path: Option<PathBuf>,
 
     #[structopt(long)]
-    /// Imap user
+    /// Imap user of the default account
     pub user: Option<String>,
 
     #[structopt(long)]
-    /// Imap password
+    /// Imap password of the default account
     pub password: Option<String>,
 
     #[structopt(long)]
-    /// Imap server
+    /// Imap server of the default account
     pub server: Option<String>,
 
     #[structopt(long)]
-    /// Imap server port. Defaults to '993'
+    /// Imap server port of the default account. Defaults to '993'
     pub port: Option<u16>,
 
     #[structopt(long)]
     /// The IMAP folder where to place report mails once processed.
     pub store_folder: Option<String>,
+
+    #[structopt(long)]
+    /// The IMAP folder where to place mails that could not be imported.
+    pub quarantine_folder: Option<String>,
+
+    #[structopt(subcommand)]
+    /// What to do. Defaults to 'serve'
+    pub cmd: Option<Command>,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+pub enum Command {
+    /// Start the web interface
+    Serve,
+
+    /// Fetch reports from all configured IMAP accounts and exit
+    Fetch,
+
+    /// Import reports from a local directory, Maildir or mbox file and exit
+    Import {
+        #[structopt(parse(from_os_str))]
+        /// File or directory to import
+        path: PathBuf,
+    },
+
+    /// Look up the host names and autonomous systems of new source IPs and exit
+    Enrich,
+
+    /// Print DKIM and SPF statistics per domain
+    Stats {
+        #[structopt(long, default_value = "30")]
+        /// Only take reports of the last number of days into account
+        days: u16,
+    },
+
+    /// Export reports as JSON
+    Export {
+        #[structopt(long)]
+        /// Only export reports of this domain
+        domain: Option<String>,
+
+        #[structopt(long, parse(from_os_str))]
+        /// File to write the export to. Defaults to stdout
+        output: Option<PathBuf>,
+    },
+
+    /// Manage the users of the web interface
+    User {
+        #[structopt(subcommand)]
+        cmd: UserCommand,
+    },
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+pub enum UserCommand {
+    /// List all users
+    List,
+
+    /// Add a user, the password is read from stdin
+    Add {
+        #[structopt(long, default_value = "viewer", possible_values = crate::auth::ROLES)]
+        /// Admins see all domains and run the tasks, viewers only see the domains assigned to them
+        role: String,
+
+        /// Name of the user
+        name: String,
+    },
+
+    /// Change the password of a user, the password is read from stdin
+    Passwd {
+        /// Name of the user
+        name: String,
+    },
+
+    /// Change the role of a user
+    Role {
+        /// Name of the user
+        name: String,
+
+        #[structopt(possible_values = crate::auth::ROLES)]
+        /// The new role
+        role: String,
+    },
+
+    /// Give a user access to the reports of a domain
+    Grant {
+        /// Name of the user
+        name: String,
+
+        /// The domain
+        domain: String,
+    },
+
+    /// Remove the access of a user to the reports of a domain
+    Revoke {
+        /// Name of the user
+        name: String,
+
+        /// The domain
+        domain: String,
+    },
+
+    /// Delete a user
+    Delete {
+        /// Name of the user
+        name: String,
+    },
 }
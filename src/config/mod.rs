@@ -1,21 +1,141 @@
 pub mod arguments;
 use configparser::ini::Ini;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::PathBuf;
 use structopt::StructOpt;
 
-#[derive(Debug, PartialEq)]
-pub struct Config {
-    pub db_path: std::path::PathBuf,
+const DEFAULT_ACCOUNT: &str = "default";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Account {
+    pub name: String,
     pub server: String,
     pub port: u16,
     pub user: String,
     pub password: String,
     pub store_folder: String,
+    pub quarantine_folder: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Database {
+    Sqlite,
+    // connection string of the PostgreSQL server
+    Postgres(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Auth {
+    // Without authentication every visitor has full access
+    pub enabled: bool,
+    // Accept HTTP basic authentication of local users, e.g. for API clients
+    pub basic: bool,
+    // Header in which a reverse proxy passes the name of the user it authenticated
+    pub proxy_header: Option<String>,
+    // Only requests from these addresses may set the proxy header
+    pub trusted_proxies: Vec<IpAddr>,
+    // Hours until a login session expires
+    pub session_hours: u64,
+    // Only send the cookies over HTTPS, for servers behind a TLS terminating proxy
+    pub secure_cookies: bool,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            basic: false,
+            proxy_header: None,
+            trusted_proxies: vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()],
+            session_hours: 24,
+            secure_cookies: false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Enrichment {
+    // Resolve the host names of source IPs, off by default as it queries the DNS servers
+    pub reverse_dns: bool,
+    // MaxMind ASN database (.mmdb) or iptoasn.com TSV file
+    pub asn_db: Option<PathBuf>,
+    // MaxMind country or city database (.mmdb) to locate the source IPs of imported records
+    pub country_db: Option<PathBuf>,
+    // Hours until the information about an IP is looked up again
+    pub ttl_hours: u64,
+}
+
+impl Default for Enrichment {
+    fn default() -> Self {
+        Self {
+            reverse_dns: false,
+            asn_db: None,
+            country_db: None,
+            ttl_hours: 168,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AlertCondition {
+    // Percentage of the newly reported messages of a domain that failed DMARC
+    FailRate(u64),
+    // Number of newly reported messages of a domain that failed DMARC
+    FailCount(u64),
+    // Source IPs that did not send for the domain before and belong to no known provider
+    NewSender,
+    // Organisations that did not report about the domain before
+    NewReporter,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct AlertRule {
+    pub name: String,
+    pub condition: AlertCondition,
+    // Applies to all domains if not set
+    pub domain: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Smtp {
+    pub server: String,
+    // 465 uses TLS, any other port STARTTLS
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Alerts {
+    pub rules: Vec<AlertRule>,
+    // Alerts are mailed if an SMTP server is configured
+    pub smtp: Option<Smtp>,
+    // URLs the alerts are posted to as JSON
+    pub webhooks: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Config {
+    pub database: Database,
+    pub db_path: std::path::PathBuf,
+    pub accounts: Vec<Account>,
+    // Minutes between two background fetches, 0 disables them
+    pub fetch_interval: u64,
+    pub auth: Auth,
+    // Replaces the bundled list of mail provider networks
+    pub providers_file: Option<PathBuf>,
+    pub enrichment: Enrichment,
+    pub alerts: Alerts,
 }
 
 impl Config {
     pub fn new() -> Self {
-        let args = arguments::Opt::from_args();
+        Self::from_args(&arguments::Opt::from_args())
+    }
+
+    pub fn from_args(args: &arguments::Opt) -> Self {
         let mut config_path = String::from("config.cfg");
 
         if args.config.is_some() {
@@ -25,7 +145,7 @@ impl Config {
         let mut config_file = Ini::new();
         config_file.load(config_path.as_str()).unwrap();
 
-        Self::merge_config_options(&config_file, &args)
+        Self::merge_config_options(&config_file, args)
     }
 
     fn merge_config_options(config_file: &Ini, args: &arguments::Opt) -> Self {
@@ -36,6 +156,194 @@ impl Config {
                     .unwrap_or_else(|| String::from("data.db")),
             )
         });
+
+        let database = match config_file.get("global", "database").as_deref() {
+            None | Some("sqlite") => Database::Sqlite,
+            Some("postgres") => Database::Postgres(
+                config_file
+                    .get("global", "postgres_url")
+                    .expect("No postgres_url specified!"),
+            ),
+            Some(other) => panic!("Unknown database '{}'!", other),
+        };
+
+        let fetch_interval = config_file
+            .getuint("global", "fetch_interval")
+            .unwrap()
+            .unwrap_or(0);
+
+        let auth = Self::auth(config_file);
+
+        let providers_file = config_file
+            .get("global", "providers_file")
+            .map(PathBuf::from);
+
+        let enrichment = Self::enrichment(config_file);
+
+        let alerts = Self::alerts(config_file);
+
+        let mut accounts = Vec::new();
+
+        // The plain [account] section and the command line arguments make up the default account
+        let sections = config_file.sections();
+        if sections.iter().any(|s| s == "account") || args.server.is_some() {
+            accounts.push(Self::default_account(config_file, args));
+        }
+
+        let mut account_sections: Vec<&String> = sections
+            .iter()
+            .filter(|s| s.starts_with("account."))
+            .collect();
+        account_sections.sort();
+        for section in account_sections {
+            let name = section.trim_start_matches("account.");
+            // Reports of the default account are stored under this name already
+            if name == DEFAULT_ACCOUNT {
+                panic!(
+                    "The account name '{}' is reserved for the [account] section!",
+                    DEFAULT_ACCOUNT
+                );
+            }
+            accounts.push(Self::account_from_section(config_file, section, name));
+        }
+
+        Self {
+            database,
+            db_path,
+            accounts,
+            fetch_interval,
+            auth,
+            providers_file,
+            enrichment,
+            alerts,
+        }
+    }
+
+    fn auth(config_file: &Ini) -> Auth {
+        let default = Auth::default();
+        let trusted_proxies = match config_file.get("auth", "trusted_proxies") {
+            Some(proxies) => proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .map(|proxy| {
+                    proxy
+                        .parse()
+                        .unwrap_or_else(|_e| panic!("Invalid trusted proxy '{}'!", proxy))
+                })
+                .collect(),
+            None => default.trusted_proxies,
+        };
+
+        Auth {
+            enabled: config_file
+                .getbool("auth", "enabled")
+                .unwrap()
+                .unwrap_or(default.enabled),
+            basic: config_file
+                .getbool("auth", "basic")
+                .unwrap()
+                .unwrap_or(default.basic),
+            proxy_header: config_file.get("auth", "proxy_header"),
+            trusted_proxies,
+            session_hours: config_file
+                .getuint("auth", "session_hours")
+                .unwrap()
+                .unwrap_or(default.session_hours),
+            secure_cookies: config_file
+                .getbool("auth", "secure_cookies")
+                .unwrap()
+                .unwrap_or(default.secure_cookies),
+        }
+    }
+
+    fn enrichment(config_file: &Ini) -> Enrichment {
+        let default = Enrichment::default();
+        Enrichment {
+            reverse_dns: config_file
+                .getbool("enrichment", "reverse_dns")
+                .unwrap()
+                .unwrap_or(default.reverse_dns),
+            asn_db: config_file.get("enrichment", "asn_db").map(PathBuf::from),
+            country_db: config_file
+                .get("enrichment", "country_db")
+                .map(PathBuf::from),
+            ttl_hours: config_file
+                .getuint("enrichment", "ttl_hours")
+                .unwrap()
+                .unwrap_or(default.ttl_hours),
+        }
+    }
+
+    fn alerts(config_file: &Ini) -> Alerts {
+        let list = |key: &str| -> Vec<String> {
+            config_file
+                .get("alerts", key)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(String::from)
+                .collect()
+        };
+
+        let smtp = config_file.get("alerts", "smtp_server").map(|server| Smtp {
+            server,
+            port: config_file
+                .getuint("alerts", "smtp_port")
+                .unwrap()
+                .unwrap_or(587) as u16,
+            user: config_file.get("alerts", "smtp_user"),
+            password: config_file.get("alerts", "smtp_password"),
+            from: config_file
+                .get("alerts", "mail_from")
+                .expect("No mail_from specified for alerts!"),
+            to: list("mail_to"),
+        });
+
+        let sections = config_file.sections();
+        let mut rule_sections: Vec<&String> = sections
+            .iter()
+            .filter(|s| s.starts_with("alert."))
+            .collect();
+        rule_sections.sort();
+        let rules = rule_sections
+            .into_iter()
+            .map(|section| Self::alert_rule_from_section(config_file, section))
+            .collect();
+
+        Alerts {
+            rules,
+            smtp,
+            webhooks: list("webhooks"),
+        }
+    }
+
+    fn alert_rule_from_section(config_file: &Ini, section: &str) -> AlertRule {
+        let name = section.trim_start_matches("alert.");
+        let threshold = || {
+            config_file
+                .getuint(section, "threshold")
+                .unwrap()
+                .unwrap_or_else(|| panic!("No threshold specified for alert rule '{}'!", name))
+        };
+        let condition = match config_file.get(section, "condition").as_deref() {
+            Some("fail_rate") => AlertCondition::FailRate(threshold()),
+            Some("fail_count") => AlertCondition::FailCount(threshold()),
+            Some("new_sender") => AlertCondition::NewSender,
+            Some("new_reporter") => AlertCondition::NewReporter,
+            Some(other) => panic!("Unknown condition '{}' of alert rule '{}'!", other, name),
+            None => panic!("No condition specified for alert rule '{}'!", name),
+        };
+
+        AlertRule {
+            name: String::from(name),
+            condition,
+            domain: config_file.get(section, "domain"),
+        }
+    }
+
+    fn default_account(config_file: &Ini, args: &arguments::Opt) -> Account {
         let server = args.server.clone().unwrap_or_else(|| {
             config_file
                 .get("account", "server")
@@ -62,14 +370,49 @@ impl Config {
                 .get("account", "store_folder")
                 .unwrap_or_else(|| String::from("processed"))
         });
+        let quarantine_folder = args.quarantine_folder.clone().unwrap_or_else(|| {
+            config_file
+                .get("account", "quarantine_folder")
+                .unwrap_or_else(|| String::from("quarantine"))
+        });
 
-        Self {
-            db_path,
+        Account {
+            name: String::from(DEFAULT_ACCOUNT),
             server,
             port,
             user,
             password,
             store_folder,
+            quarantine_folder,
+        }
+    }
+
+    fn account_from_section(config_file: &Ini, section: &str, name: &str) -> Account {
+        let server = config_file
+            .get(section, "server")
+            .unwrap_or_else(|| panic!("No server specified for account '{}'!", name));
+        let port = config_file.getuint(section, "port").unwrap().unwrap_or(993) as u16;
+        let user = config_file
+            .get(section, "user")
+            .unwrap_or_else(|| panic!("No user specified for account '{}'", name));
+        let password = config_file
+            .get(section, "password")
+            .unwrap_or_else(|| panic!("No password specified for account '{}'", name));
+        let store_folder = config_file
+            .get(section, "store_folder")
+            .unwrap_or_else(|| String::from("processed"));
+        let quarantine_folder = config_file
+            .get(section, "quarantine_folder")
+            .unwrap_or_else(|| String::from("quarantine"));
+
+        Account {
+            name: String::from(name),
+            server,
+            port,
+            user,
+            password,
+            store_folder,
+            quarantine_folder,
         }
     }
 }
@@ -99,15 +442,27 @@ mod tests {
             user: None,
             password: None,
             store_folder: None,
+            quarantine_folder: None,
+            cmd: None,
         };
         assert_eq!(
             Config {
+                database: Database::Sqlite,
                 db_path: PathBuf::from("data.db"),
-                server: String::from("testserver.com"),
-                port: 993,
-                user: String::from("foo"),
-                password: String::from("bar"),
-                store_folder: String::from("processed"),
+                accounts: vec![Account {
+                    name: String::from("default"),
+                    server: String::from("testserver.com"),
+                    port: 993,
+                    user: String::from("foo"),
+                    password: String::from("bar"),
+                    store_folder: String::from("processed"),
+                    quarantine_folder: String::from("quarantine"),
+                }],
+                fetch_interval: 0,
+                auth: Auth::default(),
+                providers_file: None,
+                enrichment: Enrichment::default(),
+                alerts: Alerts::default(),
             },
             Config::merge_config_options(&cf_file, &args)
         );
@@ -116,14 +471,25 @@ mod tests {
         cf_file.set("global", "db_path", Some(String::from("mydata.db")));
         cf_file.set("account", "store_folder", Some(String::from("finished")));
         cf_file.set("account", "port", Some(String::from("123")));
+        cf_file.set("global", "fetch_interval", Some(String::from("60")));
         assert_eq!(
             Config {
+                database: Database::Sqlite,
                 db_path: PathBuf::from("mydata.db"),
-                server: String::from("testserver.com"),
-                port: 123,
-                user: String::from("foo"),
-                password: String::from("bar"),
-                store_folder: String::from("finished"),
+                accounts: vec![Account {
+                    name: String::from("default"),
+                    server: String::from("testserver.com"),
+                    port: 123,
+                    user: String::from("foo"),
+                    password: String::from("bar"),
+                    store_folder: String::from("finished"),
+                    quarantine_folder: String::from("quarantine"),
+                }],
+                fetch_interval: 60,
+                auth: Auth::default(),
+                providers_file: None,
+                enrichment: Enrichment::default(),
+                alerts: Alerts::default(),
             },
             Config::merge_config_options(&cf_file, &args)
         );
@@ -133,21 +499,268 @@ mod tests {
             config: None,
             db_path: Some(PathBuf::from("foobar.db")),
             server: Some(String::from("newserver.foo")),
-            port: Some(888 as u16),
+            port: Some(888_u16),
             user: Some(String::from("newuser")),
             password: Some(String::from("newpassword")),
             store_folder: Some(String::from("newstorefolder")),
+            quarantine_folder: Some(String::from("newquarantine")),
+            cmd: None,
         };
         assert_eq!(
             Config {
+                database: Database::Sqlite,
                 db_path: PathBuf::from("foobar.db"),
-                server: String::from("newserver.foo"),
-                port: 888,
-                user: String::from("newuser"),
-                password: String::from("newpassword"),
-                store_folder: String::from("newstorefolder"),
+                accounts: vec![Account {
+                    name: String::from("default"),
+                    server: String::from("newserver.foo"),
+                    port: 888,
+                    user: String::from("newuser"),
+                    password: String::from("newpassword"),
+                    store_folder: String::from("newstorefolder"),
+                    quarantine_folder: String::from("newquarantine"),
+                }],
+                fetch_interval: 60,
+                auth: Auth::default(),
+                providers_file: None,
+                enrichment: Enrichment::default(),
+                alerts: Alerts::default(),
             },
             Config::merge_config_options(&cf_file, &allargs)
         );
     }
+
+    #[test]
+    fn test_postgres_database() {
+        let mut cf_file = Ini::new();
+        cf_file.set("global", "database", Some(String::from("postgres")));
+        cf_file.set(
+            "global",
+            "postgres_url",
+            Some(String::from("host=db.example.com user=dmarc")),
+        );
+        let args = arguments::Opt {
+            config: None,
+            db_path: None,
+            server: None,
+            port: None,
+            user: None,
+            password: None,
+            store_folder: None,
+            quarantine_folder: None,
+            cmd: None,
+        };
+        assert_eq!(
+            Database::Postgres(String::from("host=db.example.com user=dmarc")),
+            Config::merge_config_options(&cf_file, &args).database
+        );
+    }
+
+    #[test]
+    fn test_auth() {
+        let mut cf_file = Ini::new();
+        cf_file.set("auth", "basic", Some(String::from("true")));
+        cf_file.set("auth", "proxy_header", Some(String::from("X-Remote-User")));
+        cf_file.set("auth", "secure_cookies", Some(String::from("true")));
+        cf_file.set(
+            "auth",
+            "trusted_proxies",
+            Some(String::from("10.0.0.1, fd00::1")),
+        );
+        assert_eq!(
+            Auth {
+                enabled: true,
+                basic: true,
+                proxy_header: Some(String::from("X-Remote-User")),
+                trusted_proxies: vec!["10.0.0.1".parse().unwrap(), "fd00::1".parse().unwrap()],
+                session_hours: 24,
+                secure_cookies: true,
+            },
+            Config::auth(&cf_file)
+        );
+    }
+
+    #[test]
+    fn test_enrichment() {
+        let mut cf_file = Ini::new();
+        cf_file.set("enrichment", "reverse_dns", Some(String::from("true")));
+        cf_file.set("enrichment", "asn_db", Some(String::from("asn.mmdb")));
+        assert_eq!(
+            Enrichment {
+                reverse_dns: true,
+                asn_db: Some(PathBuf::from("asn.mmdb")),
+                country_db: None,
+                ttl_hours: 168,
+            },
+            Config::enrichment(&cf_file)
+        );
+    }
+
+    #[test]
+    fn test_alerts() {
+        let mut cf_file = Ini::new();
+        cf_file.set(
+            "alerts",
+            "smtp_server",
+            Some(String::from("mail.server.com")),
+        );
+        cf_file.set(
+            "alerts",
+            "mail_from",
+            Some(String::from("dmarc@server.com")),
+        );
+        cf_file.set(
+            "alerts",
+            "mail_to",
+            Some(String::from("admin@server.com, postmaster@server.com")),
+        );
+        cf_file.set(
+            "alerts",
+            "webhooks",
+            Some(String::from("https://hooks.server.com/dmarc")),
+        );
+        cf_file.set(
+            "alert.failures",
+            "condition",
+            Some(String::from("fail_rate")),
+        );
+        cf_file.set("alert.failures", "threshold", Some(String::from("10")));
+        cf_file.set(
+            "alert.failures",
+            "domain",
+            Some(String::from("example.com")),
+        );
+        cf_file.set(
+            "alert.senders",
+            "condition",
+            Some(String::from("new_sender")),
+        );
+
+        let alerts = Config::alerts(&cf_file);
+        assert_eq!(
+            Some(Smtp {
+                server: String::from("mail.server.com"),
+                port: 587,
+                user: None,
+                password: None,
+                from: String::from("dmarc@server.com"),
+                to: vec![
+                    String::from("admin@server.com"),
+                    String::from("postmaster@server.com")
+                ],
+            }),
+            alerts.smtp
+        );
+        assert_eq!(
+            vec![String::from("https://hooks.server.com/dmarc")],
+            alerts.webhooks
+        );
+        assert_eq!(
+            vec![
+                AlertRule {
+                    name: String::from("failures"),
+                    condition: AlertCondition::FailRate(10),
+                    domain: Some(String::from("example.com")),
+                },
+                AlertRule {
+                    name: String::from("senders"),
+                    condition: AlertCondition::NewSender,
+                    domain: None,
+                },
+            ],
+            alerts.rules
+        );
+    }
+
+    #[test]
+    fn test_multiple_accounts() {
+        let mut cf_file = Ini::new();
+        cf_file.set("account.work", "user", Some(String::from("dmarc")));
+        cf_file.set("account.work", "password", Some(String::from("secret")));
+        cf_file.set(
+            "account.work",
+            "server",
+            Some(String::from("mail.work.com")),
+        );
+        cf_file.set("account.private", "user", Some(String::from("me")));
+        cf_file.set("account.private", "password", Some(String::from("pass")));
+        cf_file.set(
+            "account.private",
+            "server",
+            Some(String::from("mail.me.com")),
+        );
+        cf_file.set("account.private", "port", Some(String::from("1993")));
+        cf_file.set(
+            "account.private",
+            "store_folder",
+            Some(String::from("done")),
+        );
+        let args = arguments::Opt {
+            config: None,
+            db_path: None,
+            server: None,
+            port: None,
+            user: None,
+            password: None,
+            store_folder: None,
+            quarantine_folder: None,
+            cmd: None,
+        };
+        assert_eq!(
+            Config {
+                database: Database::Sqlite,
+                db_path: PathBuf::from("data.db"),
+                accounts: vec![
+                    Account {
+                        name: String::from("private"),
+                        server: String::from("mail.me.com"),
+                        port: 1993,
+                        user: String::from("me"),
+                        password: String::from("pass"),
+                        store_folder: String::from("done"),
+                        quarantine_folder: String::from("quarantine"),
+                    },
+                    Account {
+                        name: String::from("work"),
+                        server: String::from("mail.work.com"),
+                        port: 993,
+                        user: String::from("dmarc"),
+                        password: String::from("secret"),
+                        store_folder: String::from("processed"),
+                        quarantine_folder: String::from("quarantine"),
+                    },
+                ],
+                fetch_interval: 0,
+                auth: Auth::default(),
+                providers_file: None,
+                enrichment: Enrichment::default(),
+                alerts: Alerts::default(),
+            },
+            Config::merge_config_options(&cf_file, &args)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "reserved")]
+    fn test_reserved_account_name() {
+        let mut cf_file = Ini::new();
+        cf_file.set("account.default", "user", Some(String::from("dmarc")));
+        cf_file.set("account.default", "password", Some(String::from("secret")));
+        cf_file.set(
+            "account.default",
+            "server",
+            Some(String::from("mail.example.com")),
+        );
+        let args = arguments::Opt {
+            config: None,
+            db_path: None,
+            server: None,
+            port: None,
+            user: None,
+            password: None,
+            store_folder: None,
+            quarantine_folder: None,
+            cmd: None,
+        };
+        Config::merge_config_options(&cf_file, &args);
+    }
 }
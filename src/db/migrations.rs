@@ -0,0 +1,456 @@
+use log::{info, warn};
+use rusqlite::{params, Connection, Result, Transaction, TransactionBehavior};
+
+// Schema migrations in the order they are applied. The schema version of a database, stored in
+// its user_version, is the number of migrations applied to it. Released migrations must never be
+// changed, new ones are appended at the end.
+const MIGRATIONS: &[&str] = &[
+    // 1: layout of version 0.4.0, databases of that version have user_version 0 but already
+    // contain these tables
+    "CREATE TABLE IF NOT EXISTS report (
+        id                    INTEGER PRIMARY KEY,
+        report_id             TEXT NOT NULL,
+        blob                  BLOB NOT NULL,
+        org_name              TEXT NOT NULL,
+        email                 TEXT NOT NULL,
+        extra_contact_info    TEXT,
+        date_begin            INTEGER NOT NULL,
+        date_end              INTEGER NOT NULL,
+        policy_domain         TEXT NOT NULL,
+        policy_adkim          TEXT,
+        policy_aspf           TEXT,
+        policy_p              TEXT,
+        policy_sp             TEXT,
+        policy_pct            INTEGER NOT NULL
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS report_id_index on report (report_id);
+    CREATE TABLE IF NOT EXISTS record (
+        id                      INTEGER PRIMARY KEY,
+        report                  TEXT NOT NULL,
+        source_ip               TEXT NOT NULL,
+        count                   INTEGER NOT NULL,
+        policy_ev_disposition   TEXT NOT NULL,
+        policy_ev_dkim         TEXT NOT NULL,
+        policy_ev_spf          TEXT NOT NULL,
+        identifier_header_from  TEXT NOT_NULL,
+        auth_dkim_domain        TEXT,
+        auth_dkim_result        TEXT,
+        auth_dkim_selector      TEXT,
+        auth_spf_domain         TEXT,
+        auth_spf_result         TEXT
+    );
+    CREATE TABLE IF NOT EXISTS domains (
+        id                  INTEGER PRIMARY KEY,
+        domain              TEXT NOT NULL
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS domain_index on domains (domain);",
+    // 2: multiple accounts
+    "ALTER TABLE report ADD COLUMN account TEXT;",
+    // 3: full aggregate report schema
+    "ALTER TABLE report ADD COLUMN policy_fo TEXT;
+    ALTER TABLE report ADD COLUMN policy_np TEXT;
+    ALTER TABLE record ADD COLUMN identifier_envelope_to TEXT;
+    ALTER TABLE record ADD COLUMN identifier_envelope_from TEXT;
+    ALTER TABLE record ADD COLUMN auth_spf_scope TEXT;
+    CREATE TABLE report_error (
+        id                  INTEGER PRIMARY KEY,
+        report              TEXT NOT NULL,
+        error               TEXT NOT NULL
+    );
+    CREATE TABLE policy_override_reason (
+        id                  INTEGER PRIMARY KEY,
+        record              INTEGER NOT NULL,
+        type                TEXT NOT NULL,
+        comment             TEXT
+    );",
+    // 4: forensic reports
+    "CREATE TABLE forensic_report (
+        id                      INTEGER PRIMARY KEY,
+        message_id              TEXT,
+        account                 TEXT,
+        blob                    BLOB,
+        feedback_type           TEXT NOT NULL,
+        user_agent              TEXT,
+        arrival_date            TEXT,
+        received_at             INTEGER NOT NULL,
+        source_ip               TEXT,
+        reported_domain         TEXT,
+        original_mail_from      TEXT,
+        original_rcpt_to        TEXT,
+        auth_failure            TEXT,
+        authentication_results  TEXT,
+        delivery_result         TEXT,
+        dkim_domain             TEXT,
+        dkim_selector           TEXT,
+        identity_alignment      TEXT,
+        original_from           TEXT,
+        original_subject        TEXT
+    );
+    CREATE UNIQUE INDEX forensic_message_id_index on forensic_report (message_id);
+    CREATE TABLE forensic_header (
+        id                  INTEGER PRIMARY KEY,
+        forensic_report     INTEGER NOT NULL,
+        position            INTEGER NOT NULL,
+        name                TEXT NOT NULL,
+        value               TEXT NOT NULL
+    );",
+    // 5: all DKIM signatures of a record, the auth_dkim_* columns of the record table are only
+    // kept for older databases
+    "CREATE TABLE dkim_result (
+        id                  INTEGER PRIMARY KEY,
+        record              INTEGER NOT NULL,
+        domain              TEXT,
+        selector            TEXT,
+        result              TEXT
+    );
+    INSERT INTO dkim_result (record, domain, selector, result)
+        SELECT id, auth_dkim_domain, auth_dkim_selector, auth_dkim_result
+        FROM record
+        WHERE auth_dkim_domain IS NOT NULL OR auth_dkim_result IS NOT NULL;",
+    // 6: quarantine of failed imports
+    "CREATE TABLE failed_import (
+        id                  INTEGER PRIMARY KEY,
+        account             TEXT,
+        message_id          TEXT,
+        reason              TEXT NOT NULL,
+        created_at          INTEGER NOT NULL,
+        raw                 BLOB NOT NULL
+    );",
+    // 7: incremental IMAP fetching
+    "CREATE TABLE imap_state (
+        account             TEXT NOT NULL,
+        folder              TEXT NOT NULL,
+        uid_validity        INTEGER NOT NULL,
+        last_uid            INTEGER NOT NULL,
+        PRIMARY KEY (account, folder)
+    );",
+    // 8: fetch history
+    "CREATE TABLE fetch_run (
+        id                  INTEGER PRIMARY KEY,
+        trigger             TEXT NOT NULL,
+        started_at          INTEGER NOT NULL,
+        finished_at         INTEGER,
+        imported            INTEGER NOT NULL DEFAULT 0,
+        error               TEXT,
+        log                 TEXT NOT NULL DEFAULT ''
+    );",
+    // 9: integer foreign keys, reports reference their domain and are unique per organisation
+    "INSERT OR IGNORE INTO domains (domain) SELECT DISTINCT policy_domain FROM report;
+    ALTER TABLE report ADD COLUMN domain_id INTEGER REFERENCES domains (id);
+    UPDATE report SET domain_id = (SELECT id FROM domains WHERE domain = report.policy_domain);
+    DROP INDEX report_id_index;
+    CREATE UNIQUE INDEX report_org_report_id_index on report (org_name, report_id);
+    CREATE INDEX report_domain_date_index on report (domain_id, date_begin);
+    CREATE INDEX report_date_index on report (date_begin);
+
+    CREATE TABLE record_new (
+        id                      INTEGER PRIMARY KEY,
+        report                  INTEGER NOT NULL REFERENCES report (id) ON DELETE CASCADE,
+        source_ip               TEXT NOT NULL,
+        count                   INTEGER NOT NULL,
+        policy_ev_disposition   TEXT NOT NULL,
+        policy_ev_dkim          TEXT NOT NULL,
+        policy_ev_spf           TEXT NOT NULL,
+        identifier_header_from  TEXT NOT NULL,
+        identifier_envelope_to  TEXT,
+        identifier_envelope_from TEXT,
+        auth_spf_domain         TEXT,
+        auth_spf_scope          TEXT,
+        auth_spf_result         TEXT
+    );
+    INSERT INTO record_new
+        SELECT record.id, report.id, record.source_ip, record.count,
+            record.policy_ev_disposition, record.policy_ev_dkim, record.policy_ev_spf,
+            coalesce(record.identifier_header_from, ''), record.identifier_envelope_to,
+            record.identifier_envelope_from, record.auth_spf_domain, record.auth_spf_scope,
+            record.auth_spf_result
+        FROM record
+        JOIN report ON report.report_id = record.report;
+    DROP TABLE record;
+    ALTER TABLE record_new RENAME TO record;
+    CREATE INDEX record_report_index on record (report);
+
+    CREATE TABLE dkim_result_new (
+        id                  INTEGER PRIMARY KEY,
+        record              INTEGER NOT NULL REFERENCES record (id) ON DELETE CASCADE,
+        domain              TEXT,
+        selector            TEXT,
+        result              TEXT
+    );
+    INSERT INTO dkim_result_new
+        SELECT id, record, domain, selector, result
+        FROM dkim_result
+        WHERE record IN (SELECT id FROM record);
+    DROP TABLE dkim_result;
+    ALTER TABLE dkim_result_new RENAME TO dkim_result;
+    CREATE INDEX dkim_result_record_index on dkim_result (record);
+
+    CREATE TABLE policy_override_reason_new (
+        id                  INTEGER PRIMARY KEY,
+        record              INTEGER NOT NULL REFERENCES record (id) ON DELETE CASCADE,
+        type                TEXT NOT NULL,
+        comment             TEXT
+    );
+    INSERT INTO policy_override_reason_new
+        SELECT id, record, type, comment
+        FROM policy_override_reason
+        WHERE record IN (SELECT id FROM record);
+    DROP TABLE policy_override_reason;
+    ALTER TABLE policy_override_reason_new RENAME TO policy_override_reason;
+    CREATE INDEX policy_override_reason_record_index on policy_override_reason (record);
+
+    CREATE TABLE report_error_new (
+        id                  INTEGER PRIMARY KEY,
+        report              INTEGER NOT NULL REFERENCES report (id) ON DELETE CASCADE,
+        error               TEXT NOT NULL
+    );
+    INSERT INTO report_error_new
+        SELECT report_error.id, report.id, report_error.error
+        FROM report_error
+        JOIN report ON report.report_id = report_error.report;
+    DROP TABLE report_error;
+    ALTER TABLE report_error_new RENAME TO report_error;
+    CREATE INDEX report_error_report_index on report_error (report);
+
+    CREATE TABLE forensic_header_new (
+        id                  INTEGER PRIMARY KEY,
+        forensic_report     INTEGER NOT NULL REFERENCES forensic_report (id) ON DELETE CASCADE,
+        position            INTEGER NOT NULL,
+        name                TEXT NOT NULL,
+        value               TEXT NOT NULL
+    );
+    INSERT INTO forensic_header_new
+        SELECT id, forensic_report, position, name, value
+        FROM forensic_header
+        WHERE forensic_report IN (SELECT id FROM forensic_report);
+    DROP TABLE forensic_header;
+    ALTER TABLE forensic_header_new RENAME TO forensic_header;
+    CREATE INDEX forensic_header_report_index on forensic_header (forensic_report);",
+    // 10: report ids are unique per reporting organisation and its contact address
+    "DROP INDEX report_org_report_id_index;
+    CREATE UNIQUE INDEX report_unique_index on report (org_name, email, report_id);",
+    // 11: users of the web interface and their login sessions
+    "CREATE TABLE users (
+        id                  INTEGER PRIMARY KEY,
+        name                TEXT NOT NULL UNIQUE,
+        password_hash       TEXT NOT NULL,
+        created_at          INTEGER NOT NULL
+    );
+    CREATE TABLE session (
+        token_hash          TEXT PRIMARY KEY,
+        user_id             INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        expires_at          INTEGER NOT NULL
+    );
+    CREATE INDEX session_user_index on session (user_id);",
+    // 12: roles and the domains a user may see, existing users keep their full access
+    "ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'admin';
+    CREATE TABLE user_domain (
+        user_id             INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        domain_id           INTEGER NOT NULL REFERENCES domains (id) ON DELETE CASCADE,
+        PRIMARY KEY (user_id, domain_id)
+    );",
+    // 13: identifier alignment computed by the analysis, NULL until existing records are analyzed
+    "ALTER TABLE record ADD COLUMN dkim_aligned INTEGER;
+    ALTER TABLE record ADD COLUMN spf_aligned INTEGER;",
+    // 14: cached reverse DNS and autonomous system of source IPs
+    "CREATE TABLE ip_info (
+        ip                  TEXT PRIMARY KEY,
+        hostname            TEXT,
+        asn                 INTEGER,
+        as_org              TEXT,
+        looked_up_at        INTEGER NOT NULL
+    );",
+    // 15: country of the source IP, only known for records imported with a country database
+    "ALTER TABLE record ADD COLUMN country TEXT;",
+    // 16: alerts and the import time of reports, older reports are never new to the alert rules
+    "ALTER TABLE report ADD COLUMN imported_at INTEGER;
+    CREATE TABLE alert (
+        id                  INTEGER PRIMARY KEY,
+        rule                TEXT NOT NULL,
+        domain              TEXT NOT NULL,
+        message             TEXT NOT NULL,
+        created_at          INTEGER NOT NULL,
+        acknowledged_by     TEXT,
+        acknowledged_at     INTEGER
+    );",
+    // 17: all SPF results of a record and the human readable DKIM result, the auth_spf_* columns
+    // of the record table are only kept for older databases
+    "ALTER TABLE dkim_result ADD COLUMN human_result TEXT;
+    CREATE TABLE spf_result (
+        id                  INTEGER PRIMARY KEY,
+        record              INTEGER NOT NULL REFERENCES record (id) ON DELETE CASCADE,
+        domain              TEXT,
+        scope               TEXT,
+        result              TEXT
+    );
+    CREATE INDEX spf_result_record_index on spf_result (record);
+    INSERT INTO spf_result (record, domain, scope, result)
+        SELECT id, auth_spf_domain, auth_spf_scope, auth_spf_result
+        FROM record
+        WHERE auth_spf_domain IS NOT NULL OR auth_spf_result IS NOT NULL;",
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+pub fn version(conn: &Connection) -> Result<u32> {
+    conn.query_row("PRAGMA user_version", params![], |row| row.get(0))
+}
+
+pub fn is_empty(conn: &Connection) -> Result<bool> {
+    conn.query_row(
+        "SELECT count(*) = 0 FROM sqlite_master WHERE type = 'table'",
+        params![],
+        |row| row.get(0),
+    )
+}
+
+pub fn migrate(conn: &Connection) -> Result<()> {
+    let current = version(conn)?;
+    if current > latest_version() {
+        warn!(
+            "Database schema version {} is newer than the supported version {}",
+            current,
+            latest_version()
+        );
+        return Ok(());
+    }
+
+    // Every migration is applied in its own transaction together with the new version
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let new_version = index + 1;
+        info!("Migrating database to schema version {}", new_version);
+        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        tx.execute_batch(migration)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {}", new_version))?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::sqlite::SqliteStorage;
+    use crate::db::Storage;
+
+    // Database as created by version 0.4.0, with one report
+    const FIXTURE_0_4_0: &str = "
+        CREATE TABLE report (
+            id                    INTEGER PRIMARY KEY,
+            report_id             TEXT NOT NULL,
+            blob                  BLOB NOT NULL,
+            org_name              TEXT NOT NULL,
+            email                 TEXT NOT NULL,
+            extra_contact_info    TEXT,
+            date_begin            INTEGER NOT NULL,
+            date_end              INTEGER NOT NULL,
+            policy_domain         TEXT NOT NULL,
+            policy_adkim          TEXT,
+            policy_aspf           TEXT,
+            policy_p              TEXT,
+            policy_sp             TEXT,
+            policy_pct            INTEGER NOT NULL
+        );
+        CREATE UNIQUE INDEX report_id_index on report (report_id);
+        CREATE TABLE record (
+            id                      INTEGER PRIMARY KEY,
+            report                  TEXT NOT NULL,
+            source_ip               TEXT NOT NULL,
+            count                   INTEGER NOT NULL,
+            policy_ev_disposition   TEXT NOT NULL,
+            policy_ev_dkim         TEXT NOT NULL,
+            policy_ev_spf          TEXT NOT NULL,
+            identifier_header_from  TEXT NOT_NULL,
+            auth_dkim_domain        TEXT,
+            auth_dkim_result        TEXT,
+            auth_dkim_selector      TEXT,
+            auth_spf_domain         TEXT,
+            auth_spf_result         TEXT
+        );
+        CREATE TABLE domains (
+            id                  INTEGER PRIMARY KEY,
+            domain              TEXT NOT NULL
+        );
+        CREATE UNIQUE INDEX domain_index on domains (domain);
+        INSERT INTO domains (domain) VALUES ('example.com');
+        INSERT INTO report VALUES (1, '4711', X'3c666565646261636b2f3e', 'google.com',
+            'noreply-dmarc-support@google.com', NULL, 1600000000, 1600086399, 'example.com',
+            'r', 'r', 'none', 'none', 100);
+        INSERT INTO record VALUES (1, '4711', '192.0.2.1', 3, 'none', 'pass', 'fail',
+            'example.com', 'example.com', 'pass', 'mail', 'example.com', 'softfail');";
+
+    #[test]
+    fn test_migrate_from_0_4_0() {
+        // The backups and journal files are removed along with the directory
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dmarc.db");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(FIXTURE_0_4_0).unwrap();
+        }
+
+        let db = SqliteStorage::new(&path).unwrap();
+        let conn = db.writer.lock().unwrap();
+        assert_eq!(latest_version(), version(&conn).unwrap());
+
+        let (account, dkim_domain, dkim_selector): (Option<String>, String, String) = conn
+            .query_row(
+                "SELECT report.account, dkim_result.domain, dkim_result.selector
+                FROM report
+                JOIN record ON record.report = report.id
+                JOIN dkim_result ON dkim_result.record = record.id",
+                params![],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(None, account);
+        assert_eq!("example.com", dkim_domain);
+        assert_eq!("mail", dkim_selector);
+        let spf_result: String = conn
+            .query_row("SELECT result FROM spf_result", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!("softfail", spf_result);
+        drop(conn);
+        assert_eq!(
+            3,
+            db.get_basic_stats(12000).unwrap()["example.com"].dkim_passed
+        );
+        assert_eq!(vec![1], db.get_unanalyzed_report_ids().unwrap());
+        crate::analysis::analyze_stored(&db).unwrap();
+        assert!(db.get_unanalyzed_report_ids().unwrap().is_empty());
+
+        // The original file is kept next to the migrated one
+        let backups: Vec<std::path::PathBuf> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|p| {
+                p.to_string_lossy()
+                    .starts_with(&format!("{}.backup-", path.to_string_lossy()))
+            })
+            .collect();
+        assert_eq!(1, backups.len());
+        let backup = Connection::open(&backups[0]).unwrap();
+        assert_eq!(0, version(&backup).unwrap());
+
+        // Migrating again is a no-op
+        drop(db);
+        let db = SqliteStorage::new(&path).unwrap();
+        assert_eq!(1, db.get_domains().unwrap().len());
+
+        // Records and their authentication results are removed together with the report
+        let conn = db.writer.lock().unwrap();
+        conn.execute("DELETE FROM report", params![]).unwrap();
+        let remaining: i64 = conn
+            .query_row(
+                "SELECT (SELECT count(*) FROM record) + (SELECT count(*) FROM dkim_result)
+                    + (SELECT count(*) FROM spf_result)",
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(0, remaining);
+    }
+}
@@ -1,514 +1,312 @@
+mod migrations;
+pub mod postgres;
+pub mod sqlite;
+
+use crate::config::{Config, Database};
 use crate::report;
-use log::info;
-use rusqlite::{params, Connection, Result, Transaction, TransactionBehavior};
+use crate::report::forensic;
+use anyhow::Result;
 use std::collections::HashMap;
-use std::path::Path;
-
-use std::sync::Mutex;
-
-#[derive(Debug)]
-pub struct DB {
-    conn: Mutex<Connection>,
-}
 
 #[derive(Debug, Serialize)]
 pub struct BasicStats {
-    dkim_passed: u32,
-    spf_passed: u32,
-    dkim_failed: u32,
-    spf_failed: u32,
+    pub dkim_passed: u64,
+    pub spf_passed: u64,
+    pub dkim_failed: u64,
+    pub spf_failed: u64,
 }
 
 #[derive(Debug, Serialize)]
 pub struct PolicyEvaluatedStats {
     date: String,
-    pass: u32,
-    dkim_fail: u32,
-    spf_fail: u32,
+    pass: u64,
+    // Failed records that are counted as not aligned are left out of these
+    dkim_fail: u64,
+    spf_fail: u64,
+    // DKIM or SPF passed, but not for an aligned domain, these are not counted as failed
+    not_aligned: u64,
 }
 
-impl DB {
-    pub fn new(db_path: &Path) -> Result<Self> {
-        let conn = Connection::open(db_path).expect("Error opening database");
-
-        Self::init_db(&conn)?;
-
-        Ok(Self {
-            conn: Mutex::new(conn),
-        })
-    }
-
-    fn init_db(conn: &Connection) -> Result<()> {
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS report (
-                id                    INTEGER PRIMARY KEY,
-                report_id             TEXT NOT NULL,
-                blob                  BLOB NOT NULL,
-                org_name              TEXT NOT NULL,
-                email                 TEXT NOT NULL,
-                extra_contact_info    TEXT,
-                date_begin            INTEGER NOT NULL,
-                date_end              INTEGER NOT NULL,
-                policy_domain         TEXT NOT NULL,
-                policy_adkim          TEXT,
-                policy_aspf           TEXT,
-                policy_p              TEXT,
-                policy_sp             TEXT,
-                policy_pct            INTEGER NOT NULL
-                  )",
-            params![],
-        )?;
-
-        conn.execute(
-            "CREATE UNIQUE INDEX IF NOT EXISTS report_id_index
-        on report (report_id)",
-            params![],
-        )?;
-
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS record (
-                id                      INTEGER PRIMARY KEY,
-                report                  TEXT NOT NULL,
-                source_ip               TEXT NOT NULL,
-                count                   INTEGER NOT NULL,
-                policy_ev_disposition   TEXT NOT NULL,
-                policy_ev_dkim         TEXT NOT NULL,
-                policy_ev_spf          TEXT NOT NULL,
-                identifier_header_from  TEXT NOT_NULL,
-                auth_dkim_domain        TEXT,
-                auth_dkim_result        TEXT,
-                auth_dkim_selector      TEXT,
-                auth_spf_domain         TEXT,
-                auth_spf_result         TEXT
-            )",
-            params![],
-        )?;
-
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS domains (
-                id                  INTEGER PRIMARY KEY,
-                domain              TEXT NOT NULL
-                )",
-            params![],
-        )?;
-
-        conn.execute(
-            "CREATE UNIQUE INDEX IF NOT EXISTS domain_index
-        on domains (domain)",
-            params![],
-        )?;
-
-        Ok(())
-    }
-
-    pub fn insert_report(&self, report: &report::Report) -> Result<()> {
-        let conn = &self.conn.lock().expect("Could not get DB lock");
-        conn.execute(
-            "INSERT OR IGNORE INTO domains (domain) VALUES (?1)",
-            params![report.policy_domain.clone()],
-        )?;
-
-        match conn.execute(
-            "SELECT report_id FROM report WHERE report_id = '?1'",
-            params![report.report_id],
-        ) {
-            Ok(_num) => {
-                info!("Report {} already exists", report.report_id);
-                return Ok(());
-            }
-            Err(_r) => {}
-        }
-
-        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Deferred)?;
-        tx.execute(
-            "INSERT INTO report (
-                report_id,
-                blob,
-                org_name,
-                email,
-                extra_contact_info,
-                date_begin,
-                date_end,
-                policy_domain,
-                policy_adkim,
-                policy_aspf,
-                policy_p,
-                policy_sp,
-                policy_pct
-            )
-            VALUES
-                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
-            params![
-                report.report_id,
-                report.blob,
-                report.org_name,
-                report.email,
-                report.extra_contact_info,
-                report.date_begin,
-                report.date_end,
-                report.policy_domain,
-                report.policy_adkim,
-                report.policy_aspf,
-                report.policy_p,
-                report.policy_sp,
-                report.policy_pct,
-            ],
-        )?;
-
-        for record in &report.records {
-            tx.execute(
-                "INSERT INTO record (
-                report,
-                source_ip,
-                count,
-                policy_ev_disposition,
-                policy_ev_dkim,
-                policy_ev_spf,
-                identifier_header_from,
-                auth_dkim_domain,
-                auth_dkim_result,
-                auth_dkim_selector,
-                auth_spf_domain,
-                auth_spf_result
-                )
-                VALUES
-                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
-                params![
-                    report.report_id,
-                    record.source_ip,
-                    record.count,
-                    record.policy_evaluated_disposition,
-                    record.policy_evaluated_dkim,
-                    record.policy_evaluated_spf,
-                    record.identifiers_header_from,
-                    record.auth_results_dkim_domain,
-                    record.auth_results_dkim_result,
-                    record.auth_results_dkim_selector,
-                    record.auth_results_spf_domain,
-                    record.auth_results_spf_result,
-                ],
-            )?;
-        }
-
-        tx.commit()?;
-
-        Ok(())
-    }
-
-    pub fn get_domains(&self) -> Result<Vec<String>> {
-        let conn = &self.conn.lock().expect("Could not get DB lock");
-        let mut stmt = conn.prepare("SELECT domain FROM domains ORDER BY domain")?;
-
-        let rows = stmt.query_map(params![], |row| row.get(0))?;
-
-        let mut domains = Vec::new();
-        for domain_result in rows {
-            domains.push(domain_result?);
-        }
-        Ok(domains)
-    }
-
-    pub fn get_basic_stats(&self, last_days: u16) -> Result<HashMap<String, BasicStats>> {
-        let domains = Self::get_domains(self)?;
-
-        #[derive(Debug)]
-        struct ResRow {
-            count: u32,
-            domain: String,
-        }
+// Messages of a domain per country of the source IP, None for unknown countries
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CountryStats {
+    pub country: Option<String>,
+    pub count: u64,
+    // Neither DKIM nor SPF passed
+    pub dmarc_fail: u64,
+}
 
-        let mut stats = HashMap::new();
-        for domain in domains {
-            stats.entry(domain).or_insert(BasicStats {
-                dkim_passed: 0,
-                spf_passed: 0,
-                dkim_failed: 0,
-                spf_failed: 0,
-            });
-        }
+#[derive(Debug, Serialize)]
+pub struct FailedImport {
+    pub id: i64,
+    pub account: Option<String>,
+    pub message_id: Option<String>,
+    pub reason: String,
+    pub created_at: i64,
+    #[serde(skip)]
+    pub raw: Option<Vec<u8>>,
+}
 
-        let conn = &self.conn.lock().expect("Could not get DB lock");
-        let mut stmt = conn.prepare(
-            "SELECT
-                report.policy_domain,
-                sum(record.count)
-            FROM report
-            LEFT OUTER JOIN record
-            ON record.report = report.report_id
-            WHERE record.policy_ev_dkim = 'pass'
-            AND date(report.date_begin, 'unixepoch') >= date('now', ?)
-            GROUP BY report.policy_domain",
-        )?;
-        let rows = stmt.query_map(params![format!("-{} days", last_days)], |row| {
-            Ok(ResRow {
-                count: row.get(1)?,
-                domain: row.get(0)?,
-            })
-        })?;
+#[derive(Debug, Serialize)]
+pub struct FetchRun {
+    pub id: i64,
+    pub trigger: String,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    pub imported: u32,
+    pub error: Option<String>,
+    pub log: String,
+}
 
-        for row in rows {
-            let d = row?;
-            if let Some(cur) = stats.get_mut(&d.domain) {
-                cur.dkim_passed = d.count;
-            }
-        }
+#[derive(Debug, Serialize)]
+pub struct User {
+    pub id: i64,
+    pub name: String,
+    pub role: String,
+    #[serde(skip)]
+    pub password_hash: String,
+    pub created_at: i64,
+}
 
-        let mut stmt = conn.prepare(
-            "SELECT
-                report.policy_domain,
-                sum(record.count)
-            FROM report
-            LEFT OUTER JOIN record
-            ON record.report = report.report_id
-            WHERE record.policy_ev_spf = 'pass'
-            AND date(report.date_begin, 'unixepoch') >= date('now', ?)
-            GROUP BY report.policy_domain",
-        )?;
-        let rows = stmt.query_map(params![format!("-{} days", last_days)], |row| {
-            Ok(ResRow {
-                count: row.get(1)?,
-                domain: row.get(0)?,
-            })
-        })?;
+// Records of a domain summed up per source IP, the counts are numbers of messages
+#[derive(Debug)]
+pub struct SenderRow {
+    pub source_ip: String,
+    // Sums over all records of the time range, which may exceed 32 bits
+    pub count: u64,
+    // DKIM or SPF passed
+    pub dmarc_pass: u64,
+    pub dkim_pass: u64,
+    pub spf_pass: u64,
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
 
-        for row in rows {
-            let d = row?;
-            if let Some(cur) = stats.get_mut(&d.domain) {
-                cur.spf_passed = d.count;
-            }
-        }
+// Cached host name and autonomous system of a source IP
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct IpInfo {
+    pub ip: String,
+    pub hostname: Option<String>,
+    pub asn: Option<u32>,
+    pub as_org: Option<String>,
+    pub looked_up_at: i64,
+}
 
-        let mut stmt = conn.prepare(
-            "SELECT
-                report.policy_domain,
-                sum(record.count)
-            FROM report
-            LEFT OUTER JOIN record
-            ON record.report = report.report_id
-            WHERE record.policy_ev_dkim != 'pass'
-            AND date(report.date_begin, 'unixepoch') >= date('now', ?)
-            GROUP BY report.policy_domain",
-        )?;
-        let rows = stmt.query_map(params![format!("-{} days", last_days)], |row| {
-            Ok(ResRow {
-                count: row.get(1)?,
-                domain: row.get(0)?,
-            })
-        })?;
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Alert {
+    pub id: i64,
+    // Name of the rule that raised the alert
+    pub rule: String,
+    pub domain: String,
+    pub message: String,
+    pub created_at: i64,
+    pub acknowledged_by: Option<String>,
+    pub acknowledged_at: Option<i64>,
+}
 
-        for row in rows {
-            let d = row?;
-            if let Some(cur) = stats.get_mut(&d.domain) {
-                cur.dkim_failed = d.count;
-            }
-        }
+// Records of the reports imported since a point in time, summed up per domain and source IP
+#[derive(Debug)]
+pub struct ImportedSource {
+    pub domain: String,
+    pub source_ip: String,
+    pub count: u64,
+    // DKIM or SPF passed
+    pub dmarc_pass: u64,
+    // Also sent messages in reports imported before
+    pub known: bool,
+}
 
-        let mut stmt = conn.prepare(
-            "SELECT
-                report.policy_domain,
-                sum(record.count)
-            FROM report
-            LEFT OUTER JOIN record
-            ON record.report = report.report_id
-            WHERE record.policy_ev_spf != 'pass'
-            AND date(report.date_begin, 'unixepoch') >= date('now', ?)
-            GROUP BY report.policy_domain",
-        )?;
-        let rows = stmt.query_map(params![format!("-{} days", last_days)], |row| {
-            Ok(ResRow {
-                count: row.get(1)?,
-                domain: row.get(0)?,
-            })
-        })?;
+// Values of the DMARC result filter
+pub const DMARC_PASS: &str = "pass";
+pub const DMARC_FAIL: &str = "fail";
+
+// Columns the report list can be sorted by
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReportSort {
+    #[default]
+    Date,
+    Organisation,
+    Account,
+    Count,
+}
 
-        for row in rows {
-            let d = row?;
-            if let Some(cur) = stats.get_mut(&d.domain) {
-                cur.spf_failed = d.count;
-            }
+impl ReportSort {
+    pub const ALL: &'static [ReportSort] = &[
+        ReportSort::Date,
+        ReportSort::Organisation,
+        ReportSort::Account,
+        ReportSort::Count,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReportSort::Date => "date",
+            ReportSort::Organisation => "org",
+            ReportSort::Account => "account",
+            ReportSort::Count => "count",
         }
-
-        Ok(stats)
     }
 
-    pub fn get_all_reports_for_domain(&self, domain: String) -> Result<Vec<report::Report>> {
-        let mut reports: Vec<report::Report> = Vec::new();
-
-        let report_ids = Self::get_report_ids_for_domain(self, domain)?;
-
-        for report in report_ids {
-            let id = report;
-            reports.push(Self::get_report(self, id)?);
-        }
-
-        Ok(reports)
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|sort| sort.name() == name)
     }
+}
 
-    fn get_report_ids_for_domain(&self, domain: String) -> Result<Vec<String>> {
-        let conn = &self.conn.lock().expect("Could not get DB lock");
-
-        let mut stmt = conn.prepare(
-            "SELECT report_id
-                     FROM report
-                     WHERE policy_domain = ?
-                     ORDER BY date_begin DESC",
-        )?;
-        let reports_iter = stmt.query_map(params![domain], |row| Ok(row.get(0)))?;
-
-        let mut report_ids: Vec<String> = Vec::new();
-        for report in reports_iter {
-            let id = report??;
-            report_ids.push(id);
-        }
+#[derive(Debug, Default)]
+pub struct ReportFilter {
+    pub domain: String,
+    pub account: Option<String>,
+    // Part of the name of the reporting organisation
+    pub org_name: Option<String>,
+    // Beginning of the source IP of any record of the report
+    pub source_ip: Option<String>,
+    // Range of the report begin as unix timestamps, the end is exclusive
+    pub date_from: Option<i64>,
+    pub date_to: Option<i64>,
+    // DMARC_PASS keeps reports where all records passed, DMARC_FAIL those with a failed record
+    pub dmarc_result: Option<String>,
+    pub sort: ReportSort,
+    pub ascending: bool,
+}
 
-        Ok(report_ids)
+impl ReportFilter {
+    // The same for both backends, the column names are never taken from user input
+    fn order_by(&self) -> String {
+        let column = match self.sort {
+            ReportSort::Date => "report.date_begin",
+            ReportSort::Organisation => "report.org_name",
+            ReportSort::Account => "report.account",
+            ReportSort::Count => "(SELECT sum(count) FROM record WHERE record.report = report.id)",
+        };
+        let direction = if self.ascending { "ASC" } else { "DESC" };
+        format!("{} {}, report.id {}", column, direction, direction)
     }
 
-    pub fn get_report(&self, report_id: String) -> Result<report::Report> {
-        let conn = &self.conn.lock().expect("Could not get DB lock");
-
-        let mut records: Vec<report::Record> = Vec::new();
-
-        let mut stmt = conn.prepare(
-            "SELECT
-            source_ip,
-            count,
-            policy_ev_disposition,
-            policy_ev_dkim,
-            policy_ev_spf,
-            identifier_header_from,
-            auth_dkim_domain,
-            auth_dkim_result,
-            auth_dkim_selector,
-            auth_spf_domain,
-            auth_spf_result
-            FROM record
-            WHERE report = ?",
-        )?;
-
-        let record_iter = stmt.query_map(params![report_id], |row| {
-            Ok(report::Record {
-                source_ip: row.get(0)?,
-                count: row.get(1)?,
-                policy_evaluated_disposition: row.get(2)?,
-                policy_evaluated_dkim: row.get(3)?,
-                policy_evaluated_spf: row.get(4)?,
-                identifiers_header_from: row.get(5)?,
-                auth_results_dkim_domain: row.get(6)?,
-                auth_results_dkim_result: row.get(7)?,
-                auth_results_dkim_selector: row.get(8)?,
-                auth_results_spf_domain: row.get(9)?,
-                auth_results_spf_result: row.get(10)?,
-            })
-        })?;
-
-        for record in record_iter {
-            records.push(record?);
-        }
-
-        conn.query_row(
-            "SELECT
-                blob,
-                org_name,
-                email,
-                extra_contact_info,
-                date_begin,
-                date_end,
-                policy_domain,
-                policy_adkim,
-                policy_aspf,
-                policy_p,
-                policy_sp,
-                policy_pct
-            FROM report
-            WHERE report_id = ?",
-            params![report_id],
-            |row| {
-                Ok(report::Report {
-                    blob: row.get(0)?,
-                    org_name: row.get(1)?,
-                    email: row.get(2)?,
-                    extra_contact_info: row.get(3)?,
-                    report_id: report_id.clone(),
-                    date_begin: row.get(4)?,
-                    date_end: row.get(5)?,
-                    policy_domain: row.get(6)?,
-                    policy_adkim: row.get(7)?,
-                    policy_aspf: row.get(8)?,
-                    policy_p: row.get(9)?,
-                    policy_sp: row.get(10)?,
-                    policy_pct: row.get(11)?,
-                    records,
-                })
-            },
+    // LIKE patterns for the org_name and source_ip filters, their text is matched literally
+    fn like_patterns(&self) -> (Option<String>, Option<String>) {
+        let escape = |text: &String| {
+            text.replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        };
+        (
+            self.org_name
+                .as_ref()
+                .map(|org_name| format!("%{}%", escape(org_name))),
+            self.source_ip
+                .as_ref()
+                .map(|source_ip| format!("{}%", escape(source_ip))),
         )
     }
+}
 
-    pub fn get_policy_evaluated_stats(
+pub trait Storage: Send + Sync {
+    fn insert_report(&self, report: &report::Report) -> Result<bool>;
+    fn insert_forensic_report(&self, report: &forensic::ForensicReport) -> Result<bool>;
+    fn get_forensic_reports(&self, domain: Option<String>)
+        -> Result<Vec<forensic::ForensicReport>>;
+    fn get_imap_state(&self, account: &str, folder: &str) -> Result<Option<(u32, u32)>>;
+    fn set_imap_state(
+        &self,
+        account: &str,
+        folder: &str,
+        uid_validity: u32,
+        last_uid: u32,
+    ) -> Result<()>;
+    fn start_fetch_run(&self, trigger: &str, started_at: i64) -> Result<i64>;
+    fn finish_fetch_run(&self, run: &FetchRun) -> Result<()>;
+    fn get_fetch_runs(&self, limit: u32) -> Result<Vec<FetchRun>>;
+    fn insert_failed_import(&self, failed: &FailedImport) -> Result<i64>;
+    fn get_failed_imports(&self) -> Result<Vec<FailedImport>>;
+    // None if there is no failed import with the id
+    fn get_failed_import(&self, id: i64) -> Result<Option<FailedImport>>;
+    fn delete_failed_import(&self, id: i64) -> Result<()>;
+    fn insert_user(
+        &self,
+        name: &str,
+        password_hash: &str,
+        role: &str,
+        created_at: i64,
+    ) -> Result<i64>;
+    fn get_user(&self, name: &str) -> Result<Option<User>>;
+    fn get_users(&self) -> Result<Vec<User>>;
+    // Returns false if there is no user with the name
+    fn set_user_password(&self, name: &str, password_hash: &str) -> Result<bool>;
+    fn set_user_role(&self, name: &str, role: &str) -> Result<bool>;
+    fn delete_user(&self, name: &str) -> Result<bool>;
+    // Domains a user has access to, regardless of the role
+    fn get_user_domains(&self, user_id: i64) -> Result<Vec<String>>;
+    fn add_user_domain(&self, user_id: i64, domain: &str) -> Result<()>;
+    fn remove_user_domain(&self, user_id: i64, domain: &str) -> Result<bool>;
+    // Sessions are looked up by the hash of their token, expired ones are removed on insert
+    fn insert_session(&self, token_hash: &str, user_id: i64, expires_at: i64) -> Result<()>;
+    fn get_session_user(&self, token_hash: &str, now: i64) -> Result<Option<User>>;
+    fn delete_session(&self, token_hash: &str) -> Result<()>;
+    fn get_domains(&self) -> Result<Vec<String>>;
+    // Accounts that fetched reports for the domain
+    fn get_accounts(&self, domain: &str) -> Result<Vec<String>>;
+    fn get_basic_stats(&self, last_days: u16) -> Result<HashMap<String, BasicStats>>;
+    fn count_reports(&self, filter: &ReportFilter) -> Result<u32>;
+    // Reports matching the filter, the raw XML is only loaded by get_report
+    fn get_reports(
+        &self,
+        filter: &ReportFilter,
+        offset: u32,
+        limit: Option<u32>,
+    ) -> Result<Vec<report::Report>>;
+    fn get_all_reports_for_domain(
+        &self,
+        domain: String,
+        account: Option<String>,
+    ) -> Result<Vec<report::Report>> {
+        let filter = ReportFilter {
+            domain,
+            account,
+            ..Default::default()
+        };
+        self.get_reports(&filter, 0, None)
+    }
+    // None if there is no report with the id
+    fn get_report(&self, id: i64) -> Result<Option<report::Report>>;
+    // Like get_report, but without the raw XML
+    fn get_report_without_blob(&self, id: i64) -> Result<Option<report::Report>>;
+    // Reports with records that have no alignment yet
+    fn get_unanalyzed_report_ids(&self) -> Result<Vec<i64>>;
+    // Stores the alignment of the records of a loaded report
+    fn set_alignment(&self, report: &report::Report) -> Result<()>;
+    fn get_policy_evaluated_stats(
         &self,
         last_days: u16,
-    ) -> Result<HashMap<String, HashMap<String, PolicyEvaluatedStats>>> {
-        let domains = Self::get_domains(self)?;
-        let conn = &self.conn.lock().expect("Could not get DB lock");
-
-        let mut result = HashMap::new();
-
-        struct RowResult {
-            date: String,
-            dkim: String,
-            spf: String,
-            count: u32,
-        }
-
-        for domain in &domains {
-            let mut stmt = conn.prepare(
-                "SELECT
-                    report.policy_domain,
-                    date(report.date_begin, 'unixepoch'),
-                    record.policy_ev_dkim,
-                    record.policy_ev_spf,
-                    record.count
-                FROM report
-                LEFT OUTER JOIN record
-                ON record.report = report.report_id
-                WHERE
-                    date(report.date_begin, 'unixepoch') >= date('now', ?)
-                AND
-                    report.policy_domain = ?",
-            )?;
-            let rows = stmt.query_map(params![format!("-{} days", last_days), domain], |row| {
-                Ok(RowResult {
-                    date: row.get(1)?,
-                    dkim: row.get(2)?,
-                    spf: row.get(3)?,
-                    count: row.get(4)?,
-                })
-            })?;
-
-            let mut data = HashMap::new();
+    ) -> Result<HashMap<String, HashMap<String, PolicyEvaluatedStats>>>;
+    // Per domain, the countries with the most messages first
+    fn get_country_stats(&self, last_days: u16) -> Result<HashMap<String, Vec<CountryStats>>>;
+    // Reports that began within the range, the end is exclusive
+    fn get_senders(
+        &self,
+        domain: &str,
+        date_from: Option<i64>,
+        date_to: Option<i64>,
+    ) -> Result<Vec<SenderRow>>;
+    // Source IPs of records that were never looked up or before the given time, oldest first
+    fn get_stale_ips(&self, looked_up_before: i64, limit: u32) -> Result<Vec<String>>;
+    fn set_ip_info(&self, info: &IpInfo) -> Result<()>;
+    // Cached information of the IPs, unknown IPs are missing in the map
+    fn get_ip_info(&self, ips: &[String]) -> Result<HashMap<String, IpInfo>>;
+    fn get_imported_sources(&self, since: i64) -> Result<Vec<ImportedSource>>;
+    // Domains and reporting organisations of reports imported since, that did not report before
+    fn get_new_reporters(&self, since: i64) -> Result<Vec<(String, String)>>;
+    fn insert_alert(&self, alert: &Alert) -> Result<i64>;
+    // The newest alerts first
+    fn get_alerts(&self, limit: u32) -> Result<Vec<Alert>>;
+    // None if there is no alert with the id
+    fn get_alert(&self, id: i64) -> Result<Option<Alert>>;
+    // Returns false if the alert does not exist or was acknowledged already
+    fn acknowledge_alert(&self, id: i64, user: &str, at: i64) -> Result<bool>;
+}
 
-            for row in rows {
-                let d = row?;
-                data.entry(d.date.clone()).or_insert(PolicyEvaluatedStats {
-                    date: d.date.clone(),
-                    pass: 0,
-                    dkim_fail: 0,
-                    spf_fail: 0,
-                });
-                if let Some(cur) = data.get_mut(&d.date) {
-                    if d.dkim == "pass" && d.spf == "pass" {
-                        cur.pass += 2 * d.count
-                    }
-                    if d.dkim == "fail" {
-                        cur.dkim_fail += d.count
-                    }
-                    if d.spf == "fail" {
-                        cur.spf_fail += d.count
-                    }
-                }
-            }
-            result.insert(domain.clone(), data);
-        }
-        Ok(result)
+pub fn open(config: &Config) -> Result<Box<dyn Storage>> {
+    match &config.database {
+        Database::Sqlite => Ok(Box::new(sqlite::SqliteStorage::new(&config.db_path)?)),
+        Database::Postgres(url) => Ok(Box::new(postgres::PostgresStorage::new(url)?)),
     }
 }
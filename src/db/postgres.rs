@@ -0,0 +1,1680 @@
+use super::{
+    Alert, BasicStats, CountryStats, FailedImport, FetchRun, ImportedSource, IpInfo,
+    PolicyEvaluatedStats, ReportFilter, SenderRow, Storage, User,
+};
+use crate::analysis;
+use crate::report;
+use crate::report::forensic;
+use ::postgres::{Client, Config, NoTls, Row};
+use anyhow::Result;
+use chrono::Utc;
+use log::info;
+use std::collections::HashMap;
+
+// Schema migrations in the order they are applied, the number of applied migrations is stored in
+// the schema_version table
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE domains (
+        id                      BIGSERIAL PRIMARY KEY,
+        domain                  TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE report (
+        id                      BIGSERIAL PRIMARY KEY,
+        report_id               TEXT NOT NULL,
+        account                 TEXT,
+        blob                    BYTEA NOT NULL,
+        org_name                TEXT NOT NULL,
+        email                   TEXT NOT NULL,
+        extra_contact_info      TEXT,
+        date_begin              BIGINT NOT NULL,
+        date_end                BIGINT NOT NULL,
+        policy_domain           TEXT NOT NULL,
+        policy_adkim            TEXT,
+        policy_aspf             TEXT,
+        policy_p                TEXT,
+        policy_sp               TEXT,
+        policy_pct              SMALLINT,
+        policy_fo               TEXT,
+        policy_np               TEXT,
+        domain_id               BIGINT REFERENCES domains (id),
+        UNIQUE (org_name, email, report_id)
+    );
+    CREATE INDEX report_domain_date_index on report (domain_id, date_begin);
+    CREATE INDEX report_date_index on report (date_begin);
+    CREATE TABLE record (
+        id                      BIGSERIAL PRIMARY KEY,
+        report                  BIGINT NOT NULL REFERENCES report (id) ON DELETE CASCADE,
+        source_ip               TEXT NOT NULL,
+        count                   INTEGER NOT NULL,
+        policy_ev_disposition   TEXT NOT NULL,
+        policy_ev_dkim          TEXT NOT NULL,
+        policy_ev_spf           TEXT NOT NULL,
+        identifier_header_from  TEXT NOT NULL,
+        identifier_envelope_to  TEXT,
+        identifier_envelope_from TEXT,
+        auth_spf_domain         TEXT,
+        auth_spf_scope          TEXT,
+        auth_spf_result         TEXT
+    );
+    CREATE INDEX record_report_index on record (report);
+    CREATE TABLE dkim_result (
+        id                      BIGSERIAL PRIMARY KEY,
+        record                  BIGINT NOT NULL REFERENCES record (id) ON DELETE CASCADE,
+        domain                  TEXT,
+        selector                TEXT,
+        result                  TEXT
+    );
+    CREATE INDEX dkim_result_record_index on dkim_result (record);
+    CREATE TABLE policy_override_reason (
+        id                      BIGSERIAL PRIMARY KEY,
+        record                  BIGINT NOT NULL REFERENCES record (id) ON DELETE CASCADE,
+        type                    TEXT NOT NULL,
+        comment                 TEXT
+    );
+    CREATE INDEX policy_override_reason_record_index on policy_override_reason (record);
+    CREATE TABLE report_error (
+        id                      BIGSERIAL PRIMARY KEY,
+        report                  BIGINT NOT NULL REFERENCES report (id) ON DELETE CASCADE,
+        error                   TEXT NOT NULL
+    );
+    CREATE INDEX report_error_report_index on report_error (report);
+    CREATE TABLE forensic_report (
+        id                      BIGSERIAL PRIMARY KEY,
+        message_id              TEXT UNIQUE,
+        account                 TEXT,
+        blob                    BYTEA,
+        feedback_type           TEXT NOT NULL,
+        user_agent              TEXT,
+        arrival_date            TEXT,
+        received_at             BIGINT NOT NULL,
+        source_ip               TEXT,
+        reported_domain         TEXT,
+        original_mail_from      TEXT,
+        original_rcpt_to        TEXT,
+        auth_failure            TEXT,
+        authentication_results  TEXT,
+        delivery_result         TEXT,
+        dkim_domain             TEXT,
+        dkim_selector           TEXT,
+        identity_alignment      TEXT,
+        original_from           TEXT,
+        original_subject        TEXT
+    );
+    CREATE TABLE forensic_header (
+        id                      BIGSERIAL PRIMARY KEY,
+        forensic_report         BIGINT NOT NULL REFERENCES forensic_report (id) ON DELETE CASCADE,
+        position                INTEGER NOT NULL,
+        name                    TEXT NOT NULL,
+        value                   TEXT NOT NULL
+    );
+    CREATE INDEX forensic_header_report_index on forensic_header (forensic_report);
+    CREATE TABLE failed_import (
+        id                      BIGSERIAL PRIMARY KEY,
+        account                 TEXT,
+        message_id              TEXT,
+        reason                  TEXT NOT NULL,
+        created_at              BIGINT NOT NULL,
+        raw                     BYTEA NOT NULL
+    );
+    CREATE TABLE imap_state (
+        account                 TEXT NOT NULL,
+        folder                  TEXT NOT NULL,
+        uid_validity            BIGINT NOT NULL,
+        last_uid                BIGINT NOT NULL,
+        PRIMARY KEY (account, folder)
+    );
+    CREATE TABLE fetch_run (
+        id                      BIGSERIAL PRIMARY KEY,
+        trigger                 TEXT NOT NULL,
+        started_at              BIGINT NOT NULL,
+        finished_at             BIGINT,
+        imported                BIGINT NOT NULL DEFAULT 0,
+        error                   TEXT,
+        log                     TEXT NOT NULL DEFAULT ''
+    );",
+    // 2: users of the web interface and their login sessions
+    "CREATE TABLE users (
+        id                      BIGSERIAL PRIMARY KEY,
+        name                    TEXT NOT NULL UNIQUE,
+        password_hash           TEXT NOT NULL,
+        created_at              BIGINT NOT NULL
+    );
+    CREATE TABLE session (
+        token_hash              TEXT PRIMARY KEY,
+        user_id                 BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        expires_at              BIGINT NOT NULL
+    );
+    CREATE INDEX session_user_index on session (user_id);",
+    // 3: roles and the domains a user may see, existing users keep their full access
+    "ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'admin';
+    CREATE TABLE user_domain (
+        user_id                 BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        domain_id               BIGINT NOT NULL REFERENCES domains (id) ON DELETE CASCADE,
+        PRIMARY KEY (user_id, domain_id)
+    );",
+    // 4: identifier alignment computed by the analysis, NULL until existing records are analyzed
+    "ALTER TABLE record ADD COLUMN dkim_aligned BOOLEAN;
+    ALTER TABLE record ADD COLUMN spf_aligned BOOLEAN;",
+    // 5: cached reverse DNS and autonomous system of source IPs
+    "CREATE TABLE ip_info (
+        ip                      TEXT PRIMARY KEY,
+        hostname                TEXT,
+        asn                     BIGINT,
+        as_org                  TEXT,
+        looked_up_at            BIGINT NOT NULL
+    );",
+    // 6: country of the source IP, only known for records imported with a country database
+    "ALTER TABLE record ADD COLUMN country TEXT;",
+    // 7: alerts and the import time of reports, older reports are never new to the alert rules
+    "ALTER TABLE report ADD COLUMN imported_at BIGINT;
+    CREATE TABLE alert (
+        id                      BIGSERIAL PRIMARY KEY,
+        rule                    TEXT NOT NULL,
+        domain                  TEXT NOT NULL,
+        message                 TEXT NOT NULL,
+        created_at              BIGINT NOT NULL,
+        acknowledged_by         TEXT,
+        acknowledged_at         BIGINT
+    );",
+    // 8: all SPF results of a record and the human readable DKIM result, the auth_spf_* columns
+    // of the record table are only kept for older databases
+    "ALTER TABLE dkim_result ADD COLUMN human_result TEXT;
+    CREATE TABLE spf_result (
+        id                      BIGSERIAL PRIMARY KEY,
+        record                  BIGINT NOT NULL REFERENCES record (id) ON DELETE CASCADE,
+        domain                  TEXT,
+        scope                   TEXT,
+        result                  TEXT
+    );
+    CREATE INDEX spf_result_record_index on spf_result (record);
+    INSERT INTO spf_result (record, domain, scope, result)
+        SELECT id, auth_spf_domain, auth_spf_scope, auth_spf_result
+        FROM record
+        WHERE auth_spf_domain IS NOT NULL OR auth_spf_result IS NOT NULL;",
+];
+
+// Reports matching a ReportFilter, its fields are bound to $1 to $7 in their order
+const REPORT_FILTER: &str = "FROM report
+    JOIN domains ON domains.id = report.domain_id
+    WHERE domains.domain = $1
+    AND ($2::text IS NULL OR report.account = $2)
+    AND ($3::text IS NULL OR report.org_name ILIKE $3 ESCAPE '\\')
+    AND ($4::text IS NULL OR EXISTS (
+        SELECT 1 FROM record WHERE record.report = report.id AND record.source_ip LIKE $4 ESCAPE '\\'
+    ))
+    AND ($5::bigint IS NULL OR report.date_begin >= $5)
+    AND ($6::bigint IS NULL OR report.date_begin < $6)
+    AND ($7::text IS NULL OR ($7 = 'fail') = EXISTS (
+        SELECT 1 FROM record WHERE record.report = report.id
+        AND record.policy_ev_dkim IS DISTINCT FROM 'pass'
+        AND record.policy_ev_spf IS DISTINCT FROM 'pass'
+    ))";
+
+// Message counts and their sums, which are bigints and may exceed 32 bits
+fn get_count(row: &Row, index: usize) -> Result<u64> {
+    Ok(u64::try_from(row.get::<_, i64>(index))?)
+}
+
+// Start of the day, in UTC, a number of days ago as unix timestamp
+const DAYS_AGO: &str =
+    "extract(epoch from ((now() AT TIME ZONE 'UTC')::date - $1::integer))::bigint";
+
+// Opens the connections of the pool
+struct ConnectionManager {
+    config: Config,
+}
+
+impl r2d2::ManageConnection for ConnectionManager {
+    type Connection = Client;
+    type Error = ::postgres::Error;
+
+    fn connect(&self) -> Result<Client, ::postgres::Error> {
+        self.config.connect(NoTls)
+    }
+
+    fn is_valid(&self, client: &mut Client) -> Result<(), ::postgres::Error> {
+        client.simple_query("").map(|_rows| ())
+    }
+
+    fn has_broken(&self, client: &mut Client) -> bool {
+        client.is_closed()
+    }
+}
+
+// The synchronous client blocks on a runtime of its own, so the storage must neither be used nor
+// dropped on an async worker thread
+pub struct PostgresStorage {
+    // Reads and writes use their own connections
+    pool: r2d2::Pool<ConnectionManager>,
+}
+
+impl PostgresStorage {
+    pub fn new(url: &str) -> Result<Self> {
+        let manager = ConnectionManager {
+            config: url.parse()?,
+        };
+        let pool = r2d2::Pool::new(manager)?;
+        Self::migrate(&mut *pool.get()?)?;
+
+        Ok(Self { pool })
+    }
+
+    fn migrate(client: &mut Client) -> Result<()> {
+        client.batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
+        )?;
+
+        // Several instances may share the database, only one of them migrates it
+        let mut tx = client.transaction()?;
+        tx.batch_execute("LOCK TABLE schema_version IN EXCLUSIVE MODE")?;
+        let current: i32 = match tx.query_opt("SELECT version FROM schema_version", &[])? {
+            Some(row) => row.get(0),
+            None => {
+                tx.execute("INSERT INTO schema_version (version) VALUES (0)", &[])?;
+                0
+            }
+        };
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+            let new_version = index as i32 + 1;
+            info!("Migrating database to schema version {}", new_version);
+            tx.batch_execute(migration)?;
+            tx.execute("UPDATE schema_version SET version = $1", &[&new_version])?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn with_client<T>(&self, f: impl FnOnce(&mut Client) -> Result<T>) -> Result<T> {
+        let mut client = self.pool.get()?;
+        f(&mut client)
+    }
+
+    fn user_from_row(row: &Row) -> User {
+        User {
+            id: row.get(0),
+            name: row.get(1),
+            role: row.get(2),
+            password_hash: row.get(3),
+            created_at: row.get(4),
+        }
+    }
+
+    fn alert_from_row(row: &Row) -> Alert {
+        Alert {
+            id: row.get(0),
+            rule: row.get(1),
+            domain: row.get(2),
+            message: row.get(3),
+            created_at: row.get(4),
+            acknowledged_by: row.get(5),
+            acknowledged_at: row.get(6),
+        }
+    }
+
+    fn get_report_ids(
+        &self,
+        filter: &ReportFilter,
+        offset: u32,
+        limit: Option<u32>,
+    ) -> Result<Vec<i64>> {
+        // A limit of NULL returns all rows
+        let (org_name, source_ip) = filter.like_patterns();
+        self.with_client(|client| {
+            let rows = client.query(
+                format!(
+                    "SELECT report.id {} ORDER BY {} LIMIT $8 OFFSET $9",
+                    REPORT_FILTER,
+                    filter.order_by()
+                )
+                .as_str(),
+                &[
+                    &filter.domain,
+                    &filter.account,
+                    &org_name,
+                    &source_ip,
+                    &filter.date_from,
+                    &filter.date_to,
+                    &filter.dmarc_result,
+                    &limit.map(i64::from),
+                    &i64::from(offset),
+                ],
+            )?;
+            Ok(rows.iter().map(|row| row.get(0)).collect())
+        })
+    }
+
+    fn load_report(&self, id: i64, with_blob: bool) -> Result<Option<report::Report>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT
+                id,
+                source_ip,
+                count,
+                policy_ev_disposition,
+                policy_ev_dkim,
+                policy_ev_spf,
+                identifier_header_from,
+                identifier_envelope_to,
+                identifier_envelope_from,
+                dkim_aligned,
+                spf_aligned,
+                country
+                FROM record
+                WHERE report = $1
+                ORDER BY id",
+                &[&id],
+            )?;
+            let reason_stmt = client.prepare(
+                "SELECT type, comment
+                FROM policy_override_reason
+                WHERE record = $1
+                ORDER BY id",
+            )?;
+            let dkim_stmt = client.prepare(
+                "SELECT domain, selector, result, human_result
+                FROM dkim_result
+                WHERE record = $1
+                ORDER BY id",
+            )?;
+            let spf_stmt = client.prepare(
+                "SELECT domain, scope, result
+                FROM spf_result
+                WHERE record = $1
+                ORDER BY id",
+            )?;
+
+            let mut records: Vec<report::Record> = Vec::new();
+            for row in rows {
+                let record_id: i64 = row.get(0);
+                let auth_results_dkim = client
+                    .query(&dkim_stmt, &[&record_id])?
+                    .iter()
+                    .map(|dkim| report::DkimResult {
+                        domain: dkim.get(0),
+                        selector: dkim.get(1),
+                        result: dkim.get(2),
+                        human_result: dkim.get(3),
+                    })
+                    .collect();
+                let auth_results_spf = client
+                    .query(&spf_stmt, &[&record_id])?
+                    .iter()
+                    .map(|spf| report::SpfResult {
+                        domain: spf.get(0),
+                        scope: spf.get(1),
+                        result: spf.get(2),
+                    })
+                    .collect();
+                let policy_evaluated_reasons = client
+                    .query(&reason_stmt, &[&record_id])?
+                    .iter()
+                    .map(|reason| report::PolicyOverrideReason {
+                        reason_type: reason.get(0),
+                        comment: reason.get(1),
+                    })
+                    .collect();
+                let mut record = report::Record {
+                    source_ip: row.get(1),
+                    count: row.get(2),
+                    policy_evaluated_disposition: row.get(3),
+                    policy_evaluated_dkim: row.get(4),
+                    policy_evaluated_spf: row.get(5),
+                    policy_evaluated_reasons,
+                    identifiers_header_from: row.get(6),
+                    identifiers_envelope_to: row.get(7),
+                    identifiers_envelope_from: row.get(8),
+                    auth_results_dkim,
+                    auth_results_spf,
+                    alignment: None,
+                    country: row.get(11),
+                };
+                if let (Some(dkim), Some(spf)) = (row.get(9), row.get(10)) {
+                    record.alignment = Some(analysis::Alignment::new(&record, dkim, spf));
+                }
+                records.push(record);
+            }
+
+            let errors = client
+                .query(
+                    "SELECT error FROM report_error WHERE report = $1 ORDER BY id",
+                    &[&id],
+                )?
+                .iter()
+                .map(|row| row.get(0))
+                .collect();
+
+            let row = match client.query_opt(
+                "SELECT
+                    report_id,
+                    account,
+                    CASE WHEN $2 THEN blob END,
+                    org_name,
+                    email,
+                    extra_contact_info,
+                    date_begin,
+                    date_end,
+                    policy_domain,
+                    policy_adkim,
+                    policy_aspf,
+                    policy_p,
+                    policy_sp,
+                    policy_pct,
+                    policy_fo,
+                    policy_np
+                FROM report
+                WHERE id = $1",
+                &[&id, &with_blob],
+            )? {
+                Some(row) => row,
+                None => return Ok(None),
+            };
+            Ok(Some(report::Report {
+                id,
+                report_id: row.get(0),
+                account: row.get(1),
+                blob: row.get(2),
+                org_name: row.get(3),
+                email: row.get(4),
+                extra_contact_info: row.get(5),
+                date_begin: row.get(6),
+                date_end: row.get(7),
+                errors,
+                policy_domain: row.get(8),
+                policy_adkim: row.get(9),
+                policy_aspf: row.get(10),
+                policy_p: row.get(11),
+                policy_sp: row.get(12),
+                policy_pct: row.get::<_, Option<i16>>(13).map(|pct| pct as i8),
+                policy_fo: row.get(14),
+                policy_np: row.get(15),
+                records,
+            }))
+        })
+    }
+}
+
+impl Storage for PostgresStorage {
+    fn insert_report(&self, report: &report::Report) -> Result<bool> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            tx.execute(
+                "INSERT INTO domains (domain) VALUES ($1) ON CONFLICT DO NOTHING",
+                &[&report.policy_domain],
+            )?;
+            let domain_id: i64 = tx
+                .query_one(
+                    "SELECT id FROM domains WHERE domain = $1",
+                    &[&report.policy_domain],
+                )?
+                .get(0);
+
+            // An existing report is detected by the unique constraint, which also covers
+            // reports inserted concurrently by another instance
+            let row = tx.query_opt(
+                "INSERT INTO report (
+                    report_id,
+                    account,
+                    blob,
+                    org_name,
+                    email,
+                    extra_contact_info,
+                    date_begin,
+                    date_end,
+                    policy_domain,
+                    policy_adkim,
+                    policy_aspf,
+                    policy_p,
+                    policy_sp,
+                    policy_pct,
+                    policy_fo,
+                    policy_np,
+                    domain_id,
+                    imported_at
+                )
+                VALUES
+                    ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)
+                ON CONFLICT (org_name, email, report_id) DO NOTHING
+                RETURNING id",
+                &[
+                    &report.report_id,
+                    &report.account,
+                    &report.blob.clone().unwrap_or_default(),
+                    &report.org_name,
+                    &report.email,
+                    &report.extra_contact_info,
+                    &report.date_begin,
+                    &report.date_end,
+                    &report.policy_domain,
+                    &report.policy_adkim,
+                    &report.policy_aspf,
+                    &report.policy_p,
+                    &report.policy_sp,
+                    &report.policy_pct.map(i16::from),
+                    &report.policy_fo,
+                    &report.policy_np,
+                    &domain_id,
+                    &Utc::now().timestamp(),
+                ],
+            )?;
+            let report_id: i64 = match row {
+                Some(row) => row.get(0),
+                None => {
+                    info!("Report {} already exists", report.report_id);
+                    return Ok(false);
+                }
+            };
+
+            for error in &report.errors {
+                tx.execute(
+                    "INSERT INTO report_error (report, error) VALUES ($1, $2)",
+                    &[&report_id, error],
+                )?;
+            }
+
+            for record in &report.records {
+                let record_id: i64 = tx
+                    .query_one(
+                        "INSERT INTO record (
+                        report,
+                        source_ip,
+                        count,
+                        policy_ev_disposition,
+                        policy_ev_dkim,
+                        policy_ev_spf,
+                        identifier_header_from,
+                        identifier_envelope_to,
+                        identifier_envelope_from,
+                        dkim_aligned,
+                        spf_aligned,
+                        country
+                        )
+                        VALUES
+                        ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+                        RETURNING id",
+                        &[
+                            &report_id,
+                            &record.source_ip,
+                            &record.count,
+                            &record.policy_evaluated_disposition,
+                            &record.policy_evaluated_dkim,
+                            &record.policy_evaluated_spf,
+                            &record.identifiers_header_from,
+                            &record.identifiers_envelope_to,
+                            &record.identifiers_envelope_from,
+                            &record.alignment.as_ref().map(|a| a.dkim),
+                            &record.alignment.as_ref().map(|a| a.spf),
+                            &record.country,
+                        ],
+                    )?
+                    .get(0);
+
+                for dkim in &record.auth_results_dkim {
+                    tx.execute(
+                        "INSERT INTO dkim_result (record, domain, selector, result, human_result)
+                        VALUES ($1, $2, $3, $4, $5)",
+                        &[
+                            &record_id,
+                            &dkim.domain,
+                            &dkim.selector,
+                            &dkim.result,
+                            &dkim.human_result,
+                        ],
+                    )?;
+                }
+
+                for spf in &record.auth_results_spf {
+                    tx.execute(
+                        "INSERT INTO spf_result (record, domain, scope, result)
+                        VALUES ($1, $2, $3, $4)",
+                        &[&record_id, &spf.domain, &spf.scope, &spf.result],
+                    )?;
+                }
+
+                for reason in &record.policy_evaluated_reasons {
+                    tx.execute(
+                        "INSERT INTO policy_override_reason (record, type, comment)
+                        VALUES ($1, $2, $3)",
+                        &[&record_id, &reason.reason_type, &reason.comment],
+                    )?;
+                }
+            }
+
+            tx.commit()?;
+
+            Ok(true)
+        })
+    }
+
+    fn insert_forensic_report(&self, report: &forensic::ForensicReport) -> Result<bool> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            if let Some(domain) = &report.reported_domain {
+                tx.execute(
+                    "INSERT INTO domains (domain) VALUES ($1) ON CONFLICT DO NOTHING",
+                    &[domain],
+                )?;
+            }
+
+            let row = tx.query_opt(
+                "INSERT INTO forensic_report (
+                    message_id,
+                    account,
+                    blob,
+                    feedback_type,
+                    user_agent,
+                    arrival_date,
+                    received_at,
+                    source_ip,
+                    reported_domain,
+                    original_mail_from,
+                    original_rcpt_to,
+                    auth_failure,
+                    authentication_results,
+                    delivery_result,
+                    dkim_domain,
+                    dkim_selector,
+                    identity_alignment,
+                    original_from,
+                    original_subject
+                )
+                VALUES
+                    ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17,
+                    $18, $19)
+                ON CONFLICT (message_id) DO NOTHING
+                RETURNING id",
+                &[
+                    &report.message_id,
+                    &report.account,
+                    &report.blob,
+                    &report.feedback_type,
+                    &report.user_agent,
+                    &report.arrival_date,
+                    &report.received_at,
+                    &report.source_ip,
+                    &report.reported_domain,
+                    &report.original_mail_from,
+                    &report.original_rcpt_to,
+                    &report.auth_failure,
+                    &report.authentication_results,
+                    &report.delivery_result,
+                    &report.dkim_domain,
+                    &report.dkim_selector,
+                    &report.identity_alignment,
+                    &report.original_from,
+                    &report.original_subject,
+                ],
+            )?;
+            let id: i64 = match row {
+                Some(row) => row.get(0),
+                None => {
+                    info!(
+                        "Forensic report {} already exists",
+                        report.message_id.clone().unwrap_or_default()
+                    );
+                    return Ok(false);
+                }
+            };
+
+            for (position, header) in report.original_headers.iter().enumerate() {
+                tx.execute(
+                    "INSERT INTO forensic_header (forensic_report, position, name, value)
+                    VALUES ($1, $2, $3, $4)",
+                    &[&id, &(position as i32), &header.name, &header.value],
+                )?;
+            }
+
+            tx.commit()?;
+
+            Ok(true)
+        })
+    }
+
+    fn get_forensic_reports(
+        &self,
+        domain: Option<String>,
+    ) -> Result<Vec<forensic::ForensicReport>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT
+                    id,
+                    message_id,
+                    account,
+                    feedback_type,
+                    user_agent,
+                    arrival_date,
+                    received_at,
+                    source_ip,
+                    reported_domain,
+                    original_mail_from,
+                    original_rcpt_to,
+                    auth_failure,
+                    authentication_results,
+                    delivery_result,
+                    dkim_domain,
+                    dkim_selector,
+                    identity_alignment,
+                    original_from,
+                    original_subject
+                FROM forensic_report
+                WHERE $1::text IS NULL OR reported_domain = $1
+                ORDER BY received_at DESC",
+                &[&domain],
+            )?;
+            let header_stmt = client.prepare(
+                "SELECT name, value
+                FROM forensic_header
+                WHERE forensic_report = $1
+                ORDER BY position",
+            )?;
+
+            let mut reports = Vec::new();
+            for row in rows {
+                let id: i64 = row.get(0);
+                let original_headers = client
+                    .query(&header_stmt, &[&id])?
+                    .iter()
+                    .map(|header| forensic::Header {
+                        name: header.get(0),
+                        value: header.get(1),
+                    })
+                    .collect();
+                reports.push(forensic::ForensicReport {
+                    message_id: row.get(1),
+                    account: row.get(2),
+                    blob: None,
+                    feedback_type: row.get(3),
+                    user_agent: row.get(4),
+                    arrival_date: row.get(5),
+                    received_at: row.get(6),
+                    source_ip: row.get(7),
+                    reported_domain: row.get(8),
+                    original_mail_from: row.get(9),
+                    original_rcpt_to: row.get(10),
+                    auth_failure: row.get(11),
+                    authentication_results: row.get(12),
+                    delivery_result: row.get(13),
+                    dkim_domain: row.get(14),
+                    dkim_selector: row.get(15),
+                    identity_alignment: row.get(16),
+                    original_from: row.get(17),
+                    original_subject: row.get(18),
+                    original_headers,
+                });
+            }
+
+            Ok(reports)
+        })
+    }
+
+    fn get_imap_state(&self, account: &str, folder: &str) -> Result<Option<(u32, u32)>> {
+        // Returns the UIDVALIDITY and the last processed UID of the folder
+        self.with_client(|client| {
+            let row = client.query_opt(
+                "SELECT uid_validity, last_uid
+                FROM imap_state
+                WHERE account = $1 AND folder = $2",
+                &[&account, &folder],
+            )?;
+            Ok(row.map(|row| (row.get::<_, i64>(0) as u32, row.get::<_, i64>(1) as u32)))
+        })
+    }
+
+    fn set_imap_state(
+        &self,
+        account: &str,
+        folder: &str,
+        uid_validity: u32,
+        last_uid: u32,
+    ) -> Result<()> {
+        self.with_client(|client| {
+            client.execute(
+                "INSERT INTO imap_state (account, folder, uid_validity, last_uid)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (account, folder)
+                DO UPDATE SET uid_validity = excluded.uid_validity, last_uid = excluded.last_uid",
+                &[
+                    &account,
+                    &folder,
+                    &i64::from(uid_validity),
+                    &i64::from(last_uid),
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn start_fetch_run(&self, trigger: &str, started_at: i64) -> Result<i64> {
+        self.with_client(|client| {
+            let row = client.query_one(
+                "INSERT INTO fetch_run (trigger, started_at) VALUES ($1, $2) RETURNING id",
+                &[&trigger, &started_at],
+            )?;
+            Ok(row.get(0))
+        })
+    }
+
+    fn finish_fetch_run(&self, run: &FetchRun) -> Result<()> {
+        self.with_client(|client| {
+            client.execute(
+                "UPDATE fetch_run
+                SET finished_at = $1, imported = $2, error = $3, log = $4
+                WHERE id = $5",
+                &[
+                    &run.finished_at,
+                    &i64::from(run.imported),
+                    &run.error,
+                    &run.log,
+                    &run.id,
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn get_fetch_runs(&self, limit: u32) -> Result<Vec<FetchRun>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT id, trigger, started_at, finished_at, imported, error, log
+                FROM fetch_run
+                ORDER BY started_at DESC, id DESC
+                LIMIT $1",
+                &[&i64::from(limit)],
+            )?;
+            Ok(rows
+                .iter()
+                .map(|row| FetchRun {
+                    id: row.get(0),
+                    trigger: row.get(1),
+                    started_at: row.get(2),
+                    finished_at: row.get(3),
+                    imported: row.get::<_, i64>(4) as u32,
+                    error: row.get(5),
+                    log: row.get(6),
+                })
+                .collect())
+        })
+    }
+
+    fn insert_failed_import(&self, failed: &FailedImport) -> Result<i64> {
+        self.with_client(|client| {
+            let row = client.query_one(
+                "INSERT INTO failed_import (account, message_id, reason, created_at, raw)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING id",
+                &[
+                    &failed.account,
+                    &failed.message_id,
+                    &failed.reason,
+                    &failed.created_at,
+                    &failed.raw.clone().unwrap_or_default(),
+                ],
+            )?;
+            Ok(row.get(0))
+        })
+    }
+
+    fn get_failed_imports(&self) -> Result<Vec<FailedImport>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT id, account, message_id, reason, created_at
+                FROM failed_import
+                ORDER BY created_at DESC, id DESC",
+                &[],
+            )?;
+            Ok(rows
+                .iter()
+                .map(|row| FailedImport {
+                    id: row.get(0),
+                    account: row.get(1),
+                    message_id: row.get(2),
+                    reason: row.get(3),
+                    created_at: row.get(4),
+                    raw: None,
+                })
+                .collect())
+        })
+    }
+
+    fn get_failed_import(&self, id: i64) -> Result<Option<FailedImport>> {
+        self.with_client(|client| {
+            let row = client.query_opt(
+                "SELECT id, account, message_id, reason, created_at, raw
+                FROM failed_import
+                WHERE id = $1",
+                &[&id],
+            )?;
+            Ok(row.map(|row| FailedImport {
+                id: row.get(0),
+                account: row.get(1),
+                message_id: row.get(2),
+                reason: row.get(3),
+                created_at: row.get(4),
+                raw: row.get(5),
+            }))
+        })
+    }
+
+    fn delete_failed_import(&self, id: i64) -> Result<()> {
+        self.with_client(|client| {
+            client.execute("DELETE FROM failed_import WHERE id = $1", &[&id])?;
+            Ok(())
+        })
+    }
+
+    fn insert_user(
+        &self,
+        name: &str,
+        password_hash: &str,
+        role: &str,
+        created_at: i64,
+    ) -> Result<i64> {
+        self.with_client(|client| {
+            let row = client.query_one(
+                "INSERT INTO users (name, password_hash, role, created_at) VALUES ($1, $2, $3, $4)
+                RETURNING id",
+                &[&name, &password_hash, &role, &created_at],
+            )?;
+            Ok(row.get(0))
+        })
+    }
+
+    fn get_user(&self, name: &str) -> Result<Option<User>> {
+        self.with_client(|client| {
+            let row = client.query_opt(
+                "SELECT id, name, role, password_hash, created_at FROM users WHERE name = $1",
+                &[&name],
+            )?;
+            Ok(row.as_ref().map(Self::user_from_row))
+        })
+    }
+
+    fn get_users(&self) -> Result<Vec<User>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT id, name, role, password_hash, created_at FROM users ORDER BY name",
+                &[],
+            )?;
+            Ok(rows.iter().map(Self::user_from_row).collect())
+        })
+    }
+
+    fn set_user_password(&self, name: &str, password_hash: &str) -> Result<bool> {
+        self.with_client(|client| {
+            let changed = client.execute(
+                "UPDATE users SET password_hash = $1 WHERE name = $2",
+                &[&password_hash, &name],
+            )?;
+            Ok(changed > 0)
+        })
+    }
+
+    fn set_user_role(&self, name: &str, role: &str) -> Result<bool> {
+        self.with_client(|client| {
+            let changed = client.execute(
+                "UPDATE users SET role = $1 WHERE name = $2",
+                &[&role, &name],
+            )?;
+            Ok(changed > 0)
+        })
+    }
+
+    fn delete_user(&self, name: &str) -> Result<bool> {
+        self.with_client(|client| {
+            let changed = client.execute("DELETE FROM users WHERE name = $1", &[&name])?;
+            Ok(changed > 0)
+        })
+    }
+
+    fn get_user_domains(&self, user_id: i64) -> Result<Vec<String>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT domains.domain
+                FROM user_domain
+                JOIN domains ON domains.id = user_domain.domain_id
+                WHERE user_domain.user_id = $1
+                ORDER BY domains.domain",
+                &[&user_id],
+            )?;
+            Ok(rows.iter().map(|row| row.get(0)).collect())
+        })
+    }
+
+    fn add_user_domain(&self, user_id: i64, domain: &str) -> Result<()> {
+        // Access can be granted before the first report of the domain arrived
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            tx.execute(
+                "INSERT INTO domains (domain) VALUES ($1) ON CONFLICT DO NOTHING",
+                &[&domain],
+            )?;
+            tx.execute(
+                "INSERT INTO user_domain (user_id, domain_id)
+                SELECT $1, id FROM domains WHERE domain = $2
+                ON CONFLICT DO NOTHING",
+                &[&user_id, &domain],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn remove_user_domain(&self, user_id: i64, domain: &str) -> Result<bool> {
+        self.with_client(|client| {
+            let changed = client.execute(
+                "DELETE FROM user_domain
+                WHERE user_id = $1 AND domain_id = (SELECT id FROM domains WHERE domain = $2)",
+                &[&user_id, &domain],
+            )?;
+            Ok(changed > 0)
+        })
+    }
+
+    fn insert_session(&self, token_hash: &str, user_id: i64, expires_at: i64) -> Result<()> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            tx.execute(
+                "DELETE FROM session WHERE expires_at < extract(epoch from now())::bigint",
+                &[],
+            )?;
+            tx.execute(
+                "INSERT INTO session (token_hash, user_id, expires_at) VALUES ($1, $2, $3)",
+                &[&token_hash, &user_id, &expires_at],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn get_session_user(&self, token_hash: &str, now: i64) -> Result<Option<User>> {
+        self.with_client(|client| {
+            let row = client.query_opt(
+                "SELECT users.id, users.name, users.role, users.password_hash, users.created_at
+                FROM session
+                JOIN users ON users.id = session.user_id
+                WHERE session.token_hash = $1 AND session.expires_at > $2",
+                &[&token_hash, &now],
+            )?;
+            Ok(row.as_ref().map(Self::user_from_row))
+        })
+    }
+
+    fn delete_session(&self, token_hash: &str) -> Result<()> {
+        self.with_client(|client| {
+            client.execute("DELETE FROM session WHERE token_hash = $1", &[&token_hash])?;
+            Ok(())
+        })
+    }
+
+    fn get_domains(&self) -> Result<Vec<String>> {
+        self.with_client(|client| {
+            let rows = client.query("SELECT domain FROM domains ORDER BY domain", &[])?;
+            Ok(rows.iter().map(|row| row.get(0)).collect())
+        })
+    }
+
+    fn get_accounts(&self, domain: &str) -> Result<Vec<String>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT DISTINCT report.account
+                FROM report
+                JOIN domains ON domains.id = report.domain_id
+                WHERE domains.domain = $1 AND report.account IS NOT NULL
+                ORDER BY report.account",
+                &[&domain],
+            )?;
+            Ok(rows.iter().map(|row| row.get(0)).collect())
+        })
+    }
+
+    fn get_basic_stats(&self, last_days: u16) -> Result<HashMap<String, BasicStats>> {
+        let domains = self.get_domains()?;
+
+        let mut stats = HashMap::new();
+        for domain in domains {
+            stats.entry(domain).or_insert(BasicStats {
+                dkim_passed: 0,
+                spf_passed: 0,
+                dkim_failed: 0,
+                spf_failed: 0,
+            });
+        }
+
+        let rows = self.with_client(|client| {
+            Ok(client.query(
+                format!(
+                    "SELECT
+                        domains.domain,
+                        coalesce(sum(record.count) FILTER (WHERE record.policy_ev_dkim = 'pass'), 0),
+                        coalesce(sum(record.count) FILTER (WHERE record.policy_ev_spf = 'pass'), 0),
+                        coalesce(sum(record.count) FILTER (WHERE record.policy_ev_dkim != 'pass'), 0),
+                        coalesce(sum(record.count) FILTER (WHERE record.policy_ev_spf != 'pass'), 0)
+                    FROM report
+                    JOIN domains ON domains.id = report.domain_id
+                    JOIN record ON record.report = report.id
+                    WHERE report.date_begin >= {}
+                    GROUP BY domains.domain",
+                    DAYS_AGO
+                )
+                .as_str(),
+                &[&i32::from(last_days)],
+            )?)
+        })?;
+
+        for row in rows {
+            if let Some(cur) = stats.get_mut(row.get::<_, &str>(0)) {
+                cur.dkim_passed = get_count(&row, 1)?;
+                cur.spf_passed = get_count(&row, 2)?;
+                cur.dkim_failed = get_count(&row, 3)?;
+                cur.spf_failed = get_count(&row, 4)?;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    fn count_reports(&self, filter: &ReportFilter) -> Result<u32> {
+        let (org_name, source_ip) = filter.like_patterns();
+        self.with_client(|client| {
+            let row = client.query_one(
+                format!("SELECT count(*) {}", REPORT_FILTER).as_str(),
+                &[
+                    &filter.domain,
+                    &filter.account,
+                    &org_name,
+                    &source_ip,
+                    &filter.date_from,
+                    &filter.date_to,
+                    &filter.dmarc_result,
+                ],
+            )?;
+            Ok(row.get::<_, i64>(0) as u32)
+        })
+    }
+
+    fn get_reports(
+        &self,
+        filter: &ReportFilter,
+        offset: u32,
+        limit: Option<u32>,
+    ) -> Result<Vec<report::Report>> {
+        let mut reports: Vec<report::Report> = Vec::new();
+
+        let report_ids = self.get_report_ids(filter, offset, limit)?;
+
+        for id in report_ids {
+            // a report deleted in the meantime is left out
+            reports.extend(self.load_report(id, false)?);
+        }
+
+        Ok(reports)
+    }
+
+    fn get_report(&self, id: i64) -> Result<Option<report::Report>> {
+        self.load_report(id, true)
+    }
+
+    fn get_report_without_blob(&self, id: i64) -> Result<Option<report::Report>> {
+        self.load_report(id, false)
+    }
+
+    fn get_unanalyzed_report_ids(&self) -> Result<Vec<i64>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT DISTINCT report FROM record WHERE dkim_aligned IS NULL",
+                &[],
+            )?;
+            Ok(rows.iter().map(|row| row.get(0)).collect())
+        })
+    }
+
+    fn set_alignment(&self, report: &report::Report) -> Result<()> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            // The records of a report are stored and loaded in the same order
+            let record_ids: Vec<i64> = tx
+                .query(
+                    "SELECT id FROM record WHERE report = $1 ORDER BY id",
+                    &[&report.id],
+                )?
+                .iter()
+                .map(|row| row.get(0))
+                .collect();
+            for (id, record) in record_ids.iter().zip(&report.records) {
+                if let Some(alignment) = &record.alignment {
+                    tx.execute(
+                        "UPDATE record SET dkim_aligned = $1, spf_aligned = $2 WHERE id = $3",
+                        &[&alignment.dkim, &alignment.spf, id],
+                    )?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn get_policy_evaluated_stats(
+        &self,
+        last_days: u16,
+    ) -> Result<HashMap<String, HashMap<String, PolicyEvaluatedStats>>> {
+        let domains = self.get_domains()?;
+
+        let mut result = HashMap::new();
+        for domain in &domains {
+            let rows = self.with_client(|client| {
+                Ok(client.query(
+                    format!(
+                        "SELECT
+                            to_char(to_timestamp(report.date_begin) AT TIME ZONE 'UTC', 'YYYY-MM-DD'),
+                            record.policy_ev_dkim,
+                            record.policy_ev_spf,
+                            record.count,
+                            COALESCE(
+                                NOT record.dkim_aligned AND NOT record.spf_aligned AND (
+                                    EXISTS (
+                                        SELECT 1 FROM spf_result
+                                        WHERE spf_result.record = record.id
+                                        AND spf_result.result = 'pass'
+                                        AND (spf_result.scope IS NULL OR spf_result.scope = 'mfrom')
+                                    ) OR EXISTS (
+                                        SELECT 1 FROM dkim_result
+                                        WHERE dkim_result.record = record.id
+                                        AND dkim_result.result = 'pass'
+                                    )
+                                ),
+                                false
+                            )
+                        FROM report
+                        JOIN domains ON domains.id = report.domain_id
+                        JOIN record ON record.report = report.id
+                        WHERE
+                            report.date_begin >= {}
+                        AND
+                            domains.domain = $2",
+                        DAYS_AGO
+                    )
+                    .as_str(),
+                    &[&i32::from(last_days), domain],
+                )?)
+            })?;
+
+            let mut data = HashMap::new();
+
+            for row in rows {
+                let date: String = row.get(0);
+                let dkim: &str = row.get(1);
+                let spf: &str = row.get(2);
+                let count = u64::try_from(row.get::<_, i32>(3))?;
+                let cur = data.entry(date.clone()).or_insert(PolicyEvaluatedStats {
+                    date,
+                    pass: 0,
+                    dkim_fail: 0,
+                    spf_fail: 0,
+                    not_aligned: 0,
+                });
+                // Like passed records, they count for both DKIM and SPF
+                if row.get(4) {
+                    cur.not_aligned += 2 * count;
+                    continue;
+                }
+                if dkim == "pass" && spf == "pass" {
+                    cur.pass += 2 * count
+                }
+                if dkim == "fail" {
+                    cur.dkim_fail += count
+                }
+                if spf == "fail" {
+                    cur.spf_fail += count
+                }
+            }
+            result.insert(domain.clone(), data);
+        }
+        Ok(result)
+    }
+
+    fn get_country_stats(&self, last_days: u16) -> Result<HashMap<String, Vec<CountryStats>>> {
+        let rows = self.with_client(|client| {
+            Ok(client.query(
+                format!(
+                    "SELECT
+                        domains.domain,
+                        record.country,
+                        sum(record.count),
+                        coalesce(sum(record.count) FILTER (
+                            WHERE record.policy_ev_dkim != 'pass' AND record.policy_ev_spf != 'pass'
+                        ), 0)
+                    FROM report
+                    JOIN domains ON domains.id = report.domain_id
+                    JOIN record ON record.report = report.id
+                    WHERE report.date_begin >= {}
+                    GROUP BY domains.domain, record.country
+                    ORDER BY sum(record.count) DESC, record.country",
+                    DAYS_AGO
+                )
+                .as_str(),
+                &[&i32::from(last_days)],
+            )?)
+        })?;
+
+        let mut stats: HashMap<String, Vec<CountryStats>> = HashMap::new();
+        for row in rows {
+            stats.entry(row.get(0)).or_default().push(CountryStats {
+                country: row.get(1),
+                count: get_count(&row, 2)?,
+                dmarc_fail: get_count(&row, 3)?,
+            });
+        }
+        Ok(stats)
+    }
+
+    fn get_senders(
+        &self,
+        domain: &str,
+        date_from: Option<i64>,
+        date_to: Option<i64>,
+    ) -> Result<Vec<SenderRow>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT
+                    record.source_ip,
+                    sum(record.count),
+                    sum(CASE WHEN record.policy_ev_dkim = 'pass' OR record.policy_ev_spf = 'pass'
+                        THEN record.count ELSE 0 END),
+                    sum(CASE WHEN record.policy_ev_dkim = 'pass' THEN record.count ELSE 0 END),
+                    sum(CASE WHEN record.policy_ev_spf = 'pass' THEN record.count ELSE 0 END),
+                    min(report.date_begin),
+                    max(report.date_end)
+                FROM report
+                JOIN domains ON domains.id = report.domain_id
+                JOIN record ON record.report = report.id
+                WHERE domains.domain = $1
+                AND ($2::bigint IS NULL OR report.date_begin >= $2)
+                AND ($3::bigint IS NULL OR report.date_begin < $3)
+                GROUP BY record.source_ip",
+                &[&domain, &date_from, &date_to],
+            )?;
+            rows.iter()
+                .map(|row| {
+                    Ok(SenderRow {
+                        source_ip: row.get(0),
+                        count: get_count(row, 1)?,
+                        dmarc_pass: get_count(row, 2)?,
+                        dkim_pass: get_count(row, 3)?,
+                        spf_pass: get_count(row, 4)?,
+                        first_seen: row.get(5),
+                        last_seen: row.get(6),
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn get_stale_ips(&self, looked_up_before: i64, limit: u32) -> Result<Vec<String>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT record.source_ip
+                FROM record
+                LEFT JOIN ip_info ON ip_info.ip = record.source_ip
+                WHERE ip_info.ip IS NULL OR ip_info.looked_up_at < $1
+                GROUP BY record.source_ip
+                ORDER BY min(coalesce(ip_info.looked_up_at, 0)), record.source_ip
+                LIMIT $2",
+                &[&looked_up_before, &i64::from(limit)],
+            )?;
+            Ok(rows.iter().map(|row| row.get(0)).collect())
+        })
+    }
+
+    fn set_ip_info(&self, info: &IpInfo) -> Result<()> {
+        self.with_client(|client| {
+            client.execute(
+                "INSERT INTO ip_info (ip, hostname, asn, as_org, looked_up_at)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (ip)
+                DO UPDATE SET hostname = excluded.hostname, asn = excluded.asn,
+                    as_org = excluded.as_org, looked_up_at = excluded.looked_up_at",
+                &[
+                    &info.ip,
+                    &info.hostname,
+                    &info.asn.map(i64::from),
+                    &info.as_org,
+                    &info.looked_up_at,
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn get_ip_info(&self, ips: &[String]) -> Result<HashMap<String, IpInfo>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT ip, hostname, asn, as_org, looked_up_at FROM ip_info WHERE ip = ANY($1)",
+                &[&ips],
+            )?;
+            Ok(rows
+                .iter()
+                .map(|row| {
+                    let info = IpInfo {
+                        ip: row.get(0),
+                        hostname: row.get(1),
+                        asn: row.get::<_, Option<i64>>(2).map(|asn| asn as u32),
+                        as_org: row.get(3),
+                        looked_up_at: row.get(4),
+                    };
+                    (info.ip.clone(), info)
+                })
+                .collect())
+        })
+    }
+
+    fn get_imported_sources(&self, since: i64) -> Result<Vec<ImportedSource>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT
+                    domains.domain,
+                    record.source_ip,
+                    sum(record.count),
+                    sum(CASE WHEN record.policy_ev_dkim = 'pass' OR record.policy_ev_spf = 'pass'
+                        THEN record.count ELSE 0 END),
+                    EXISTS (
+                        SELECT 1 FROM report AS old
+                        JOIN record AS old_record ON old_record.report = old.id
+                        WHERE old.domain_id = domains.id
+                        AND (old.imported_at IS NULL OR old.imported_at < $1)
+                        AND old_record.source_ip = record.source_ip
+                    )
+                FROM report
+                JOIN domains ON domains.id = report.domain_id
+                JOIN record ON record.report = report.id
+                WHERE report.imported_at >= $1
+                GROUP BY domains.id, domains.domain, record.source_ip
+                ORDER BY domains.domain, record.source_ip",
+                &[&since],
+            )?;
+            rows.iter()
+                .map(|row| {
+                    Ok(ImportedSource {
+                        domain: row.get(0),
+                        source_ip: row.get(1),
+                        count: get_count(row, 2)?,
+                        dmarc_pass: get_count(row, 3)?,
+                        known: row.get(4),
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn get_new_reporters(&self, since: i64) -> Result<Vec<(String, String)>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT DISTINCT domains.domain, report.org_name
+                FROM report
+                JOIN domains ON domains.id = report.domain_id
+                WHERE report.imported_at >= $1
+                AND NOT EXISTS (
+                    SELECT 1 FROM report AS old
+                    WHERE old.domain_id = report.domain_id
+                    AND old.org_name = report.org_name
+                    AND (old.imported_at IS NULL OR old.imported_at < $1)
+                )
+                ORDER BY domains.domain, report.org_name",
+                &[&since],
+            )?;
+            Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+        })
+    }
+
+    fn insert_alert(&self, alert: &Alert) -> Result<i64> {
+        self.with_client(|client| {
+            let row = client.query_one(
+                "INSERT INTO alert (rule, domain, message, created_at)
+                VALUES ($1, $2, $3, $4)
+                RETURNING id",
+                &[
+                    &alert.rule,
+                    &alert.domain,
+                    &alert.message,
+                    &alert.created_at,
+                ],
+            )?;
+            Ok(row.get(0))
+        })
+    }
+
+    fn get_alerts(&self, limit: u32) -> Result<Vec<Alert>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT id, rule, domain, message, created_at, acknowledged_by, acknowledged_at
+                FROM alert
+                ORDER BY id DESC
+                LIMIT $1",
+                &[&i64::from(limit)],
+            )?;
+            Ok(rows.iter().map(Self::alert_from_row).collect())
+        })
+    }
+
+    fn get_alert(&self, id: i64) -> Result<Option<Alert>> {
+        self.with_client(|client| {
+            let row = client.query_opt(
+                "SELECT id, rule, domain, message, created_at, acknowledged_by, acknowledged_at
+                FROM alert
+                WHERE id = $1",
+                &[&id],
+            )?;
+            Ok(row.as_ref().map(Self::alert_from_row))
+        })
+    }
+
+    fn acknowledge_alert(&self, id: i64, user: &str, at: i64) -> Result<bool> {
+        self.with_client(|client| {
+            let changed = client.execute(
+                "UPDATE alert SET acknowledged_by = $1, acknowledged_at = $2
+                WHERE id = $3 AND acknowledged_at IS NULL",
+                &[&user, &at, &id],
+            )?;
+            Ok(changed > 0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::fixture::ReportFixture;
+
+    #[test]
+    fn test_insert_and_get_report() {
+        // Needs a PostgreSQL server, e.g. DMARC_TEST_POSTGRES_URL="host=localhost user=postgres"
+        let url = match std::env::var("DMARC_TEST_POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_e) => return,
+        };
+        let storage = PostgresStorage::new(&url).unwrap();
+
+        let report_id = format!("test-{}", chrono::Utc::now().timestamp_nanos());
+        let mut report = ReportFixture {
+            org_name: "postgres.test",
+            report_id: &report_id,
+            domain: "postgres.test",
+            spf: "fail",
+            ..Default::default()
+        }
+        .report();
+        // A second signature of another domain
+        report.records[0]
+            .auth_results_dkim
+            .push(report::DkimResult {
+                domain: Some(String::from("other.test")),
+                selector: Some(String::from("s2")),
+                result: Some(String::from("fail")),
+                human_result: Some(String::from("signature expired")),
+            });
+        // And a second SPF result of the HELO identity
+        report.records[0].auth_results_spf.push(report::SpfResult {
+            domain: Some(String::from("mx.postgres.test")),
+            scope: Some(String::from("helo")),
+            result: Some(String::from("pass")),
+        });
+        report.records[0].country = Some(String::from("DE"));
+
+        assert!(storage.insert_report(&report).unwrap());
+        assert!(!storage.insert_report(&report).unwrap());
+
+        let reports = storage
+            .get_all_reports_for_domain(String::from("postgres.test"), None)
+            .unwrap();
+        let stored = reports
+            .into_iter()
+            .find(|r| r.report_id == report_id)
+            .unwrap();
+        assert_eq!(None, stored.blob);
+        report.id = stored.id;
+        assert_eq!(report, storage.get_report(stored.id).unwrap().unwrap());
+        let countries = &storage.get_country_stats(12000).unwrap()["postgres.test"];
+        assert!(countries
+            .iter()
+            .any(|c| c.country.as_deref() == Some("DE") && c.count >= 2));
+
+        let info = IpInfo {
+            ip: String::from("192.0.2.1"),
+            hostname: None,
+            asn: Some(64500),
+            as_org: Some(String::from("EXAMPLE-AS")),
+            looked_up_at: i64::MAX,
+        };
+        storage.set_ip_info(&info).unwrap();
+        let ips = vec![info.ip.clone()];
+        assert_eq!(info, storage.get_ip_info(&ips).unwrap()[&info.ip]);
+        assert!(!storage
+            .get_stale_ips(i64::MAX, u32::MAX)
+            .unwrap()
+            .contains(&info.ip));
+
+        let mut alert = Alert {
+            id: 0,
+            rule: String::from("failures"),
+            domain: String::from("postgres.test"),
+            message: String::from("2 reported messages failed DMARC"),
+            created_at: 1612137600,
+            acknowledged_by: None,
+            acknowledged_at: None,
+        };
+        alert.id = storage.insert_alert(&alert).unwrap();
+        assert_eq!(Some(alert.clone()), storage.get_alert(alert.id).unwrap());
+        assert!(storage
+            .acknowledge_alert(alert.id, "alice", 1612137601)
+            .unwrap());
+        assert!(!storage
+            .acknowledge_alert(alert.id, "alice", 1612137602)
+            .unwrap());
+        // the report was imported just now, so it is new to the alert rules
+        assert!(storage
+            .get_imported_sources(Utc::now().timestamp() - 60)
+            .unwrap()
+            .iter()
+            .any(|s| s.domain == "postgres.test"));
+    }
+}
@@ -0,0 +1,1838 @@
+use super::migrations;
+use super::{
+    Alert, BasicStats, CountryStats, FailedImport, FetchRun, ImportedSource, IpInfo,
+    PolicyEvaluatedStats, ReportFilter, SenderRow, Storage, User,
+};
+use crate::analysis;
+use crate::report;
+use crate::report::forensic;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use log::info;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction, TransactionBehavior};
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::Path;
+
+use std::sync::Mutex;
+
+// Milliseconds a connection waits for a lock held by another one
+const BUSY_TIMEOUT: &str = "PRAGMA busy_timeout = 5000";
+
+// Message counts and their sums, which may exceed 32 bits
+fn get_count(row: &Row, index: usize) -> rusqlite::Result<u64> {
+    let count: i64 = row.get(index)?;
+    u64::try_from(count)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Integer, Box::new(e)))
+}
+
+// Reports matching a ReportFilter, its fields are bound to ?1 to ?7 in their order
+const REPORT_FILTER: &str = "FROM report
+    JOIN domains ON domains.id = report.domain_id
+    WHERE domains.domain = ?1
+    AND (?2 IS NULL OR report.account = ?2)
+    AND (?3 IS NULL OR report.org_name LIKE ?3 ESCAPE '\\')
+    AND (?4 IS NULL OR EXISTS (
+        SELECT 1 FROM record WHERE record.report = report.id AND record.source_ip LIKE ?4 ESCAPE '\\'
+    ))
+    AND (?5 IS NULL OR report.date_begin >= ?5)
+    AND (?6 IS NULL OR report.date_begin < ?6)
+    AND (?7 IS NULL OR (?7 = 'fail') = EXISTS (
+        SELECT 1 FROM record WHERE record.report = report.id
+        AND record.policy_ev_dkim IS NOT 'pass' AND record.policy_ev_spf IS NOT 'pass'
+    ))";
+
+#[derive(Debug)]
+pub struct SqliteStorage {
+    // All writes go through this connection, so writers never wait for each other
+    pub(super) writer: Mutex<Connection>,
+    // Readers are not blocked by a running write in WAL mode. An in-memory database only exists
+    // for a single connection, so it has no readers.
+    readers: Option<r2d2::Pool<SqliteConnectionManager>>,
+}
+
+impl SqliteStorage {
+    pub fn new(db_path: &Path) -> Result<Self> {
+        let conn = Connection::open(db_path).expect("Error opening database");
+
+        // Keep a copy of the database as it was before migrating it
+        let current = migrations::version(&conn)?;
+        if current < migrations::latest_version() && !migrations::is_empty(&conn)? {
+            // Databases in WAL mode may keep committed changes in the -wal file
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", params![], |_row| Ok(()))?;
+            let backup = format!(
+                "{}.backup-{}",
+                db_path.display(),
+                chrono::Utc::now().format("%Y%m%d%H%M%S")
+            );
+            std::fs::copy(db_path, &backup)
+                .with_context(|| format!("Failed to back up database to {}", backup))?;
+            info!(
+                "Backed up database with schema version {} to {}",
+                current, backup
+            );
+        }
+        migrations::migrate(&conn)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
+        conn.execute_batch(BUSY_TIMEOUT)?;
+
+        let in_memory = db_path == Path::new(":memory:");
+        let readers = if in_memory {
+            None
+        } else {
+            conn.query_row("PRAGMA journal_mode = WAL", params![], |_row| Ok(()))?;
+            let manager = SqliteConnectionManager::file(db_path).with_init(|conn| {
+                conn.execute_batch(&format!("PRAGMA query_only = ON; {}", BUSY_TIMEOUT))
+            });
+            Some(r2d2::Pool::new(manager)?)
+        };
+
+        Ok(Self {
+            writer: Mutex::new(conn),
+            readers,
+        })
+    }
+
+    fn reader(&self) -> Result<Box<dyn Deref<Target = Connection> + '_>> {
+        match &self.readers {
+            Some(pool) => Ok(Box::new(pool.get()?)),
+            None => Ok(Box::new(self.writer.lock().expect("Could not get DB lock"))),
+        }
+    }
+
+    fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+        Ok(User {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            role: row.get(2)?,
+            password_hash: row.get(3)?,
+            created_at: row.get(4)?,
+        })
+    }
+
+    fn alert_from_row(row: &Row) -> rusqlite::Result<Alert> {
+        Ok(Alert {
+            id: row.get(0)?,
+            rule: row.get(1)?,
+            domain: row.get(2)?,
+            message: row.get(3)?,
+            created_at: row.get(4)?,
+            acknowledged_by: row.get(5)?,
+            acknowledged_at: row.get(6)?,
+        })
+    }
+
+    fn get_report_ids(
+        conn: &Connection,
+        filter: &ReportFilter,
+        offset: u32,
+        limit: Option<u32>,
+    ) -> Result<Vec<i64>> {
+        let (org_name, source_ip) = filter.like_patterns();
+        // A negative limit returns all rows
+        let mut stmt = conn.prepare(&format!(
+            "SELECT report.id {} ORDER BY {} LIMIT ?8 OFFSET ?9",
+            REPORT_FILTER,
+            filter.order_by()
+        ))?;
+        let limit = limit.map(i64::from).unwrap_or(-1);
+        let reports_iter = stmt.query_map(
+            params![
+                filter.domain,
+                filter.account,
+                org_name,
+                source_ip,
+                filter.date_from,
+                filter.date_to,
+                filter.dmarc_result,
+                limit,
+                offset
+            ],
+            |row| Ok(row.get(0)),
+        )?;
+
+        let mut report_ids: Vec<i64> = Vec::new();
+        for report in reports_iter {
+            let id = report??;
+            report_ids.push(id);
+        }
+
+        Ok(report_ids)
+    }
+
+    fn load_report(conn: &Connection, id: i64, with_blob: bool) -> Result<Option<report::Report>> {
+        let mut records: Vec<report::Record> = Vec::new();
+
+        let mut stmt = conn.prepare(
+            "SELECT
+            id,
+            source_ip,
+            count,
+            policy_ev_disposition,
+            policy_ev_dkim,
+            policy_ev_spf,
+            identifier_header_from,
+            identifier_envelope_to,
+            identifier_envelope_from,
+            dkim_aligned,
+            spf_aligned,
+            country
+            FROM record
+            WHERE report = ?
+            ORDER BY id",
+        )?;
+
+        let record_iter = stmt.query_map(params![id], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                (row.get(9)?, row.get(10)?),
+                report::Record {
+                    source_ip: row.get(1)?,
+                    count: row.get(2)?,
+                    policy_evaluated_disposition: row.get(3)?,
+                    policy_evaluated_dkim: row.get(4)?,
+                    policy_evaluated_spf: row.get(5)?,
+                    policy_evaluated_reasons: Vec::new(),
+                    identifiers_header_from: row.get(6)?,
+                    identifiers_envelope_to: row.get(7)?,
+                    identifiers_envelope_from: row.get(8)?,
+                    auth_results_dkim: Vec::new(),
+                    auth_results_spf: Vec::new(),
+                    alignment: None,
+                    country: row.get(11)?,
+                },
+            ))
+        })?;
+
+        let mut reason_stmt = conn.prepare(
+            "SELECT type, comment
+            FROM policy_override_reason
+            WHERE record = ?
+            ORDER BY id",
+        )?;
+
+        let mut dkim_stmt = conn.prepare(
+            "SELECT domain, selector, result, human_result
+            FROM dkim_result
+            WHERE record = ?
+            ORDER BY id",
+        )?;
+
+        let mut spf_stmt = conn.prepare(
+            "SELECT domain, scope, result
+            FROM spf_result
+            WHERE record = ?
+            ORDER BY id",
+        )?;
+
+        for record in record_iter {
+            let (id, aligned, mut record) = record?;
+            let dkim_results = dkim_stmt.query_map(params![id], |row| {
+                Ok(report::DkimResult {
+                    domain: row.get(0)?,
+                    selector: row.get(1)?,
+                    result: row.get(2)?,
+                    human_result: row.get(3)?,
+                })
+            })?;
+            for dkim in dkim_results {
+                record.auth_results_dkim.push(dkim?);
+            }
+            let spf_results = spf_stmt.query_map(params![id], |row| {
+                Ok(report::SpfResult {
+                    domain: row.get(0)?,
+                    scope: row.get(1)?,
+                    result: row.get(2)?,
+                })
+            })?;
+            for spf in spf_results {
+                record.auth_results_spf.push(spf?);
+            }
+            let reasons = reason_stmt.query_map(params![id], |row| {
+                Ok(report::PolicyOverrideReason {
+                    reason_type: row.get(0)?,
+                    comment: row.get(1)?,
+                })
+            })?;
+            for reason in reasons {
+                record.policy_evaluated_reasons.push(reason?);
+            }
+            if let (Some(dkim), Some(spf)) = aligned {
+                record.alignment = Some(analysis::Alignment::new(&record, dkim, spf));
+            }
+            records.push(record);
+        }
+
+        let mut error_stmt =
+            conn.prepare("SELECT error FROM report_error WHERE report = ? ORDER BY id")?;
+        let error_iter = error_stmt.query_map(params![id], |row| row.get(0))?;
+        let mut errors = Vec::new();
+        for error in error_iter {
+            errors.push(error?);
+        }
+
+        let report = conn.query_row(
+            "SELECT
+                report_id,
+                account,
+                CASE WHEN ?2 THEN blob END,
+                org_name,
+                email,
+                extra_contact_info,
+                date_begin,
+                date_end,
+                policy_domain,
+                policy_adkim,
+                policy_aspf,
+                policy_p,
+                policy_sp,
+                policy_pct,
+                policy_fo,
+                policy_np
+            FROM report
+            WHERE id = ?1",
+            params![id, with_blob],
+            |row| {
+                Ok(report::Report {
+                    id,
+                    report_id: row.get(0)?,
+                    account: row.get(1)?,
+                    blob: row.get(2)?,
+                    org_name: row.get(3)?,
+                    email: row.get(4)?,
+                    extra_contact_info: row.get(5)?,
+                    date_begin: row.get(6)?,
+                    date_end: row.get(7)?,
+                    errors,
+                    policy_domain: row.get(8)?,
+                    policy_adkim: row.get(9)?,
+                    policy_aspf: row.get(10)?,
+                    policy_p: row.get(11)?,
+                    policy_sp: row.get(12)?,
+                    policy_pct: row.get(13)?,
+                    policy_fo: row.get(14)?,
+                    policy_np: row.get(15)?,
+                    records,
+                })
+            },
+        );
+        Ok(report.optional()?)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn insert_report(&self, report: &report::Report) -> Result<bool> {
+        // Returns false if the report already exists, report ids are only unique per reporting
+        // organisation
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        conn.execute(
+            "INSERT OR IGNORE INTO domains (domain) VALUES (?1)",
+            params![report.policy_domain.clone()],
+        )?;
+        let domain_id: i64 = conn.query_row(
+            "SELECT id FROM domains WHERE domain = ?",
+            params![report.policy_domain],
+            |row| row.get(0),
+        )?;
+
+        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        let exists: bool = tx.query_row(
+            "SELECT count(*) > 0 FROM report
+            WHERE org_name = ?1 AND email = ?2 AND report_id = ?3",
+            params![report.org_name, report.email, report.report_id],
+            |row| row.get(0),
+        )?;
+        if exists {
+            info!("Report {} already exists", report.report_id);
+            return Ok(false);
+        }
+
+        tx.execute(
+            "INSERT INTO report (
+                report_id,
+                account,
+                blob,
+                org_name,
+                email,
+                extra_contact_info,
+                date_begin,
+                date_end,
+                policy_domain,
+                policy_adkim,
+                policy_aspf,
+                policy_p,
+                policy_sp,
+                policy_pct,
+                policy_fo,
+                policy_np,
+                domain_id,
+                imported_at
+            )
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
+            params![
+                report.report_id,
+                report.account,
+                report.blob,
+                report.org_name,
+                report.email,
+                report.extra_contact_info,
+                report.date_begin,
+                report.date_end,
+                report.policy_domain,
+                report.policy_adkim,
+                report.policy_aspf,
+                report.policy_p,
+                report.policy_sp,
+                report.policy_pct,
+                report.policy_fo,
+                report.policy_np,
+                domain_id,
+                Utc::now().timestamp(),
+            ],
+        )?;
+        let report_id = tx.last_insert_rowid();
+
+        for error in &report.errors {
+            tx.execute(
+                "INSERT INTO report_error (report, error) VALUES (?1, ?2)",
+                params![report_id, error],
+            )?;
+        }
+
+        for record in &report.records {
+            tx.execute(
+                "INSERT INTO record (
+                report,
+                source_ip,
+                count,
+                policy_ev_disposition,
+                policy_ev_dkim,
+                policy_ev_spf,
+                identifier_header_from,
+                identifier_envelope_to,
+                identifier_envelope_from,
+                dkim_aligned,
+                spf_aligned,
+                country
+                )
+                VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    report_id,
+                    record.source_ip,
+                    record.count,
+                    record.policy_evaluated_disposition,
+                    record.policy_evaluated_dkim,
+                    record.policy_evaluated_spf,
+                    record.identifiers_header_from,
+                    record.identifiers_envelope_to,
+                    record.identifiers_envelope_from,
+                    record.alignment.as_ref().map(|a| a.dkim),
+                    record.alignment.as_ref().map(|a| a.spf),
+                    record.country,
+                ],
+            )?;
+            let record_id = tx.last_insert_rowid();
+
+            for dkim in &record.auth_results_dkim {
+                tx.execute(
+                    "INSERT INTO dkim_result (record, domain, selector, result, human_result)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        record_id,
+                        dkim.domain,
+                        dkim.selector,
+                        dkim.result,
+                        dkim.human_result
+                    ],
+                )?;
+            }
+
+            for spf in &record.auth_results_spf {
+                tx.execute(
+                    "INSERT INTO spf_result (record, domain, scope, result)
+                    VALUES (?1, ?2, ?3, ?4)",
+                    params![record_id, spf.domain, spf.scope, spf.result],
+                )?;
+            }
+
+            for reason in &record.policy_evaluated_reasons {
+                tx.execute(
+                    "INSERT INTO policy_override_reason (record, type, comment)
+                    VALUES (?1, ?2, ?3)",
+                    params![record_id, reason.reason_type, reason.comment],
+                )?;
+            }
+        }
+
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    fn insert_forensic_report(&self, report: &forensic::ForensicReport) -> Result<bool> {
+        // Returns false if a report for the same message already exists
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        if let Some(domain) = &report.reported_domain {
+            conn.execute(
+                "INSERT OR IGNORE INTO domains (domain) VALUES (?1)",
+                params![domain],
+            )?;
+        }
+
+        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        let exists: bool = tx.query_row(
+            "SELECT count(*) > 0 FROM forensic_report WHERE message_id = ?",
+            params![report.message_id],
+            |row| row.get(0),
+        )?;
+        if exists {
+            info!(
+                "Forensic report {} already exists",
+                report.message_id.clone().unwrap_or_default()
+            );
+            return Ok(false);
+        }
+
+        tx.execute(
+            "INSERT INTO forensic_report (
+                message_id,
+                account,
+                blob,
+                feedback_type,
+                user_agent,
+                arrival_date,
+                received_at,
+                source_ip,
+                reported_domain,
+                original_mail_from,
+                original_rcpt_to,
+                auth_failure,
+                authentication_results,
+                delivery_result,
+                dkim_domain,
+                dkim_selector,
+                identity_alignment,
+                original_from,
+                original_subject
+            )
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18,
+                ?19)",
+            params![
+                report.message_id,
+                report.account,
+                report.blob,
+                report.feedback_type,
+                report.user_agent,
+                report.arrival_date,
+                report.received_at,
+                report.source_ip,
+                report.reported_domain,
+                report.original_mail_from,
+                report.original_rcpt_to,
+                report.auth_failure,
+                report.authentication_results,
+                report.delivery_result,
+                report.dkim_domain,
+                report.dkim_selector,
+                report.identity_alignment,
+                report.original_from,
+                report.original_subject,
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+
+        for (position, header) in report.original_headers.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO forensic_header (forensic_report, position, name, value)
+                VALUES (?1, ?2, ?3, ?4)",
+                params![id, position as i64, header.name, header.value],
+            )?;
+        }
+
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    fn get_forensic_reports(
+        &self,
+        domain: Option<String>,
+    ) -> Result<Vec<forensic::ForensicReport>> {
+        let conn = self.reader()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT
+                id,
+                message_id,
+                account,
+                feedback_type,
+                user_agent,
+                arrival_date,
+                received_at,
+                source_ip,
+                reported_domain,
+                original_mail_from,
+                original_rcpt_to,
+                auth_failure,
+                authentication_results,
+                delivery_result,
+                dkim_domain,
+                dkim_selector,
+                identity_alignment,
+                original_from,
+                original_subject
+            FROM forensic_report
+            WHERE ?1 IS NULL OR reported_domain = ?1
+            ORDER BY received_at DESC",
+        )?;
+        let rows = stmt.query_map(params![domain], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                forensic::ForensicReport {
+                    message_id: row.get(1)?,
+                    account: row.get(2)?,
+                    blob: None,
+                    feedback_type: row.get(3)?,
+                    user_agent: row.get(4)?,
+                    arrival_date: row.get(5)?,
+                    received_at: row.get(6)?,
+                    source_ip: row.get(7)?,
+                    reported_domain: row.get(8)?,
+                    original_mail_from: row.get(9)?,
+                    original_rcpt_to: row.get(10)?,
+                    auth_failure: row.get(11)?,
+                    authentication_results: row.get(12)?,
+                    delivery_result: row.get(13)?,
+                    dkim_domain: row.get(14)?,
+                    dkim_selector: row.get(15)?,
+                    identity_alignment: row.get(16)?,
+                    original_from: row.get(17)?,
+                    original_subject: row.get(18)?,
+                    original_headers: Vec::new(),
+                },
+            ))
+        })?;
+
+        let mut header_stmt = conn.prepare(
+            "SELECT name, value
+            FROM forensic_header
+            WHERE forensic_report = ?
+            ORDER BY position",
+        )?;
+
+        let mut reports = Vec::new();
+        for row in rows {
+            let (id, mut report) = row?;
+            let headers = header_stmt.query_map(params![id], |row| {
+                Ok(forensic::Header {
+                    name: row.get(0)?,
+                    value: row.get(1)?,
+                })
+            })?;
+            for header in headers {
+                report.original_headers.push(header?);
+            }
+            reports.push(report);
+        }
+
+        Ok(reports)
+    }
+
+    fn get_imap_state(&self, account: &str, folder: &str) -> Result<Option<(u32, u32)>> {
+        // Returns the UIDVALIDITY and the last processed UID of the folder
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(
+            "SELECT uid_validity, last_uid
+            FROM imap_state
+            WHERE account = ?1 AND folder = ?2",
+        )?;
+        let mut rows = stmt.query_map(params![account, folder], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+
+        Ok(rows.next().transpose()?)
+    }
+
+    fn set_imap_state(
+        &self,
+        account: &str,
+        folder: &str,
+        uid_validity: u32,
+        last_uid: u32,
+    ) -> Result<()> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        conn.execute(
+            "INSERT OR REPLACE INTO imap_state (account, folder, uid_validity, last_uid)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![account, folder, uid_validity, last_uid],
+        )?;
+        Ok(())
+    }
+
+    fn start_fetch_run(&self, trigger: &str, started_at: i64) -> Result<i64> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        conn.execute(
+            "INSERT INTO fetch_run (trigger, started_at) VALUES (?1, ?2)",
+            params![trigger, started_at],
+        )?;
+
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn finish_fetch_run(&self, run: &FetchRun) -> Result<()> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        conn.execute(
+            "UPDATE fetch_run
+            SET finished_at = ?1, imported = ?2, error = ?3, log = ?4
+            WHERE id = ?5",
+            params![run.finished_at, run.imported, run.error, run.log, run.id],
+        )?;
+        Ok(())
+    }
+
+    fn get_fetch_runs(&self, limit: u32) -> Result<Vec<FetchRun>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, trigger, started_at, finished_at, imported, error, log
+            FROM fetch_run
+            ORDER BY started_at DESC, id DESC
+            LIMIT ?",
+        )?;
+
+        let rows = stmt.query_map(params![limit], |row| {
+            Ok(FetchRun {
+                id: row.get(0)?,
+                trigger: row.get(1)?,
+                started_at: row.get(2)?,
+                finished_at: row.get(3)?,
+                imported: row.get(4)?,
+                error: row.get(5)?,
+                log: row.get(6)?,
+            })
+        })?;
+
+        let mut runs = Vec::new();
+        for row in rows {
+            runs.push(row?);
+        }
+        Ok(runs)
+    }
+
+    fn insert_failed_import(&self, failed: &FailedImport) -> Result<i64> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        conn.execute(
+            "INSERT INTO failed_import (account, message_id, reason, created_at, raw)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                failed.account,
+                failed.message_id,
+                failed.reason,
+                failed.created_at,
+                failed.raw.clone().unwrap_or_default(),
+            ],
+        )?;
+
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn get_failed_imports(&self) -> Result<Vec<FailedImport>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, account, message_id, reason, created_at
+            FROM failed_import
+            ORDER BY created_at DESC, id DESC",
+        )?;
+
+        let rows = stmt.query_map(params![], |row| {
+            Ok(FailedImport {
+                id: row.get(0)?,
+                account: row.get(1)?,
+                message_id: row.get(2)?,
+                reason: row.get(3)?,
+                created_at: row.get(4)?,
+                raw: None,
+            })
+        })?;
+
+        let mut failed = Vec::new();
+        for row in rows {
+            failed.push(row?);
+        }
+        Ok(failed)
+    }
+
+    fn get_failed_import(&self, id: i64) -> Result<Option<FailedImport>> {
+        let conn = self.reader()?;
+        Ok(conn
+            .query_row(
+                "SELECT id, account, message_id, reason, created_at, raw
+            FROM failed_import
+            WHERE id = ?",
+                params![id],
+                |row| {
+                    Ok(FailedImport {
+                        id: row.get(0)?,
+                        account: row.get(1)?,
+                        message_id: row.get(2)?,
+                        reason: row.get(3)?,
+                        created_at: row.get(4)?,
+                        raw: row.get(5)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    fn delete_failed_import(&self, id: i64) -> Result<()> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        conn.execute("DELETE FROM failed_import WHERE id = ?", params![id])?;
+        Ok(())
+    }
+
+    fn insert_user(
+        &self,
+        name: &str,
+        password_hash: &str,
+        role: &str,
+        created_at: i64,
+    ) -> Result<i64> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        conn.execute(
+            "INSERT INTO users (name, password_hash, role, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![name, password_hash, role, created_at],
+        )?;
+
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn get_user(&self, name: &str) -> Result<Option<User>> {
+        let conn = self.reader()?;
+        Ok(conn
+            .query_row(
+                "SELECT id, name, role, password_hash, created_at FROM users WHERE name = ?",
+                params![name],
+                Self::user_from_row,
+            )
+            .optional()?)
+    }
+
+    fn get_users(&self) -> Result<Vec<User>> {
+        let conn = self.reader()?;
+        let mut stmt = conn
+            .prepare("SELECT id, name, role, password_hash, created_at FROM users ORDER BY name")?;
+
+        let rows = stmt.query_map(params![], Self::user_from_row)?;
+
+        let mut users = Vec::new();
+        for row in rows {
+            users.push(row?);
+        }
+        Ok(users)
+    }
+
+    fn set_user_password(&self, name: &str, password_hash: &str) -> Result<bool> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        let changed = conn.execute(
+            "UPDATE users SET password_hash = ?1 WHERE name = ?2",
+            params![password_hash, name],
+        )?;
+        Ok(changed > 0)
+    }
+
+    fn set_user_role(&self, name: &str, role: &str) -> Result<bool> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        let changed = conn.execute(
+            "UPDATE users SET role = ?1 WHERE name = ?2",
+            params![role, name],
+        )?;
+        Ok(changed > 0)
+    }
+
+    fn delete_user(&self, name: &str) -> Result<bool> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        let changed = conn.execute("DELETE FROM users WHERE name = ?", params![name])?;
+        Ok(changed > 0)
+    }
+
+    fn get_user_domains(&self, user_id: i64) -> Result<Vec<String>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(
+            "SELECT domains.domain
+            FROM user_domain
+            JOIN domains ON domains.id = user_domain.domain_id
+            WHERE user_domain.user_id = ?
+            ORDER BY domains.domain",
+        )?;
+
+        let rows = stmt.query_map(params![user_id], |row| row.get(0))?;
+
+        let mut domains = Vec::new();
+        for row in rows {
+            domains.push(row?);
+        }
+        Ok(domains)
+    }
+
+    fn add_user_domain(&self, user_id: i64, domain: &str) -> Result<()> {
+        // Access can be granted before the first report of the domain arrived
+        let mut conn = self.writer.lock().expect("Could not get DB lock");
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute(
+            "INSERT OR IGNORE INTO domains (domain) VALUES (?)",
+            params![domain],
+        )?;
+        tx.execute(
+            "INSERT OR IGNORE INTO user_domain (user_id, domain_id)
+            SELECT ?1, id FROM domains WHERE domain = ?2",
+            params![user_id, domain],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn remove_user_domain(&self, user_id: i64, domain: &str) -> Result<bool> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        let changed = conn.execute(
+            "DELETE FROM user_domain
+            WHERE user_id = ?1 AND domain_id = (SELECT id FROM domains WHERE domain = ?2)",
+            params![user_id, domain],
+        )?;
+        Ok(changed > 0)
+    }
+
+    fn insert_session(&self, token_hash: &str, user_id: i64, expires_at: i64) -> Result<()> {
+        let mut conn = self.writer.lock().expect("Could not get DB lock");
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute(
+            "DELETE FROM session WHERE expires_at < CAST(strftime('%s', 'now') AS INTEGER)",
+            params![],
+        )?;
+        tx.execute(
+            "INSERT INTO session (token_hash, user_id, expires_at) VALUES (?1, ?2, ?3)",
+            params![token_hash, user_id, expires_at],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_session_user(&self, token_hash: &str, now: i64) -> Result<Option<User>> {
+        let conn = self.reader()?;
+        Ok(conn
+            .query_row(
+                "SELECT users.id, users.name, users.role, users.password_hash, users.created_at
+                FROM session
+                JOIN users ON users.id = session.user_id
+                WHERE session.token_hash = ?1 AND session.expires_at > ?2",
+                params![token_hash, now],
+                Self::user_from_row,
+            )
+            .optional()?)
+    }
+
+    fn delete_session(&self, token_hash: &str) -> Result<()> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        conn.execute(
+            "DELETE FROM session WHERE token_hash = ?",
+            params![token_hash],
+        )?;
+        Ok(())
+    }
+
+    fn get_domains(&self) -> Result<Vec<String>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare("SELECT domain FROM domains ORDER BY domain")?;
+
+        let rows = stmt.query_map(params![], |row| row.get(0))?;
+
+        let mut domains = Vec::new();
+        for domain_result in rows {
+            domains.push(domain_result?);
+        }
+        Ok(domains)
+    }
+
+    fn get_accounts(&self, domain: &str) -> Result<Vec<String>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT report.account
+            FROM report
+            JOIN domains ON domains.id = report.domain_id
+            WHERE domains.domain = ? AND report.account IS NOT NULL
+            ORDER BY report.account",
+        )?;
+
+        let rows = stmt.query_map(params![domain], |row| row.get(0))?;
+
+        let mut accounts = Vec::new();
+        for account_result in rows {
+            accounts.push(account_result?);
+        }
+        Ok(accounts)
+    }
+
+    fn get_basic_stats(&self, last_days: u16) -> Result<HashMap<String, BasicStats>> {
+        let domains = self.get_domains()?;
+
+        #[derive(Debug)]
+        struct ResRow {
+            count: u64,
+            domain: String,
+        }
+
+        let mut stats = HashMap::new();
+        for domain in domains {
+            stats.entry(domain).or_insert(BasicStats {
+                dkim_passed: 0,
+                spf_passed: 0,
+                dkim_failed: 0,
+                spf_failed: 0,
+            });
+        }
+
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(
+            "SELECT
+                domains.domain,
+                sum(record.count)
+            FROM report
+            JOIN domains ON domains.id = report.domain_id
+            JOIN record ON record.report = report.id
+            WHERE record.policy_ev_dkim = 'pass'
+            AND report.date_begin >= CAST(strftime('%s', date('now', ?)) AS INTEGER)
+            GROUP BY domains.domain",
+        )?;
+        let rows = stmt.query_map(params![format!("-{} days", last_days)], |row| {
+            Ok(ResRow {
+                count: get_count(row, 1)?,
+                domain: row.get(0)?,
+            })
+        })?;
+
+        for row in rows {
+            let d = row?;
+            if let Some(cur) = stats.get_mut(&d.domain) {
+                cur.dkim_passed = d.count;
+            }
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT
+                domains.domain,
+                sum(record.count)
+            FROM report
+            JOIN domains ON domains.id = report.domain_id
+            JOIN record ON record.report = report.id
+            WHERE record.policy_ev_spf = 'pass'
+            AND report.date_begin >= CAST(strftime('%s', date('now', ?)) AS INTEGER)
+            GROUP BY domains.domain",
+        )?;
+        let rows = stmt.query_map(params![format!("-{} days", last_days)], |row| {
+            Ok(ResRow {
+                count: get_count(row, 1)?,
+                domain: row.get(0)?,
+            })
+        })?;
+
+        for row in rows {
+            let d = row?;
+            if let Some(cur) = stats.get_mut(&d.domain) {
+                cur.spf_passed = d.count;
+            }
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT
+                domains.domain,
+                sum(record.count)
+            FROM report
+            JOIN domains ON domains.id = report.domain_id
+            JOIN record ON record.report = report.id
+            WHERE record.policy_ev_dkim != 'pass'
+            AND report.date_begin >= CAST(strftime('%s', date('now', ?)) AS INTEGER)
+            GROUP BY domains.domain",
+        )?;
+        let rows = stmt.query_map(params![format!("-{} days", last_days)], |row| {
+            Ok(ResRow {
+                count: get_count(row, 1)?,
+                domain: row.get(0)?,
+            })
+        })?;
+
+        for row in rows {
+            let d = row?;
+            if let Some(cur) = stats.get_mut(&d.domain) {
+                cur.dkim_failed = d.count;
+            }
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT
+                domains.domain,
+                sum(record.count)
+            FROM report
+            JOIN domains ON domains.id = report.domain_id
+            JOIN record ON record.report = report.id
+            WHERE record.policy_ev_spf != 'pass'
+            AND report.date_begin >= CAST(strftime('%s', date('now', ?)) AS INTEGER)
+            GROUP BY domains.domain",
+        )?;
+        let rows = stmt.query_map(params![format!("-{} days", last_days)], |row| {
+            Ok(ResRow {
+                count: get_count(row, 1)?,
+                domain: row.get(0)?,
+            })
+        })?;
+
+        for row in rows {
+            let d = row?;
+            if let Some(cur) = stats.get_mut(&d.domain) {
+                cur.spf_failed = d.count;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    fn count_reports(&self, filter: &ReportFilter) -> Result<u32> {
+        let conn = self.reader()?;
+        let (org_name, source_ip) = filter.like_patterns();
+        Ok(conn.query_row(
+            &format!("SELECT count(*) {}", REPORT_FILTER),
+            params![
+                filter.domain,
+                filter.account,
+                org_name,
+                source_ip,
+                filter.date_from,
+                filter.date_to,
+                filter.dmarc_result
+            ],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn get_reports(
+        &self,
+        filter: &ReportFilter,
+        offset: u32,
+        limit: Option<u32>,
+    ) -> Result<Vec<report::Report>> {
+        let mut reports: Vec<report::Report> = Vec::new();
+
+        // All reports are loaded with the same connection
+        let conn = self.reader()?;
+        let report_ids = Self::get_report_ids(&conn, filter, offset, limit)?;
+
+        for id in report_ids {
+            // a report deleted in the meantime is left out
+            reports.extend(Self::load_report(&conn, id, false)?);
+        }
+
+        Ok(reports)
+    }
+
+    fn get_report(&self, id: i64) -> Result<Option<report::Report>> {
+        let conn = self.reader()?;
+        Self::load_report(&conn, id, true)
+    }
+
+    fn get_report_without_blob(&self, id: i64) -> Result<Option<report::Report>> {
+        let conn = self.reader()?;
+        Self::load_report(&conn, id, false)
+    }
+
+    fn get_unanalyzed_report_ids(&self) -> Result<Vec<i64>> {
+        let conn = self.reader()?;
+        let mut stmt =
+            conn.prepare("SELECT DISTINCT report FROM record WHERE dkim_aligned IS NULL")?;
+        let rows = stmt.query_map(params![], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<i64>>>()?)
+    }
+
+    fn set_alignment(&self, report: &report::Report) -> Result<()> {
+        let mut conn = self.writer.lock().expect("Could not get DB lock");
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // The records of a report are stored and loaded in the same order
+        let record_ids = {
+            let mut stmt = tx.prepare("SELECT id FROM record WHERE report = ? ORDER BY id")?;
+            let rows = stmt.query_map(params![report.id], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<i64>>>()?
+        };
+        for (id, record) in record_ids.iter().zip(&report.records) {
+            if let Some(alignment) = &record.alignment {
+                tx.execute(
+                    "UPDATE record SET dkim_aligned = ?1, spf_aligned = ?2 WHERE id = ?3",
+                    params![alignment.dkim, alignment.spf, id],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_policy_evaluated_stats(
+        &self,
+        last_days: u16,
+    ) -> Result<HashMap<String, HashMap<String, PolicyEvaluatedStats>>> {
+        let domains = self.get_domains()?;
+        let conn = self.reader()?;
+
+        let mut result = HashMap::new();
+
+        struct RowResult {
+            date: String,
+            dkim: String,
+            spf: String,
+            count: u64,
+            not_aligned: bool,
+        }
+
+        for domain in &domains {
+            let mut stmt = conn.prepare(
+                "SELECT
+                    date(report.date_begin, 'unixepoch'),
+                    record.policy_ev_dkim,
+                    record.policy_ev_spf,
+                    record.count,
+                    COALESCE(
+                        record.dkim_aligned = 0 AND record.spf_aligned = 0 AND (
+                            EXISTS (
+                                SELECT 1 FROM spf_result
+                                WHERE spf_result.record = record.id AND spf_result.result = 'pass'
+                                AND (spf_result.scope IS NULL OR spf_result.scope = 'mfrom')
+                            ) OR EXISTS (
+                                SELECT 1 FROM dkim_result
+                                WHERE dkim_result.record = record.id AND dkim_result.result = 'pass'
+                            )
+                        ),
+                        0
+                    )
+                FROM report
+                JOIN domains ON domains.id = report.domain_id
+                JOIN record ON record.report = report.id
+                WHERE
+                    report.date_begin >= CAST(strftime('%s', date('now', ?)) AS INTEGER)
+                AND
+                    domains.domain = ?",
+            )?;
+            let rows = stmt.query_map(params![format!("-{} days", last_days), domain], |row| {
+                Ok(RowResult {
+                    date: row.get(0)?,
+                    dkim: row.get(1)?,
+                    spf: row.get(2)?,
+                    count: get_count(row, 3)?,
+                    not_aligned: row.get(4)?,
+                })
+            })?;
+
+            let mut data = HashMap::new();
+
+            for row in rows {
+                let d = row?;
+                data.entry(d.date.clone()).or_insert(PolicyEvaluatedStats {
+                    date: d.date.clone(),
+                    pass: 0,
+                    dkim_fail: 0,
+                    spf_fail: 0,
+                    not_aligned: 0,
+                });
+                if let Some(cur) = data.get_mut(&d.date) {
+                    // Like passed records, they count for both DKIM and SPF
+                    if d.not_aligned {
+                        cur.not_aligned += 2 * d.count;
+                        continue;
+                    }
+                    if d.dkim == "pass" && d.spf == "pass" {
+                        cur.pass += 2 * d.count
+                    }
+                    if d.dkim == "fail" {
+                        cur.dkim_fail += d.count
+                    }
+                    if d.spf == "fail" {
+                        cur.spf_fail += d.count
+                    }
+                }
+            }
+            result.insert(domain.clone(), data);
+        }
+        Ok(result)
+    }
+
+    fn get_country_stats(&self, last_days: u16) -> Result<HashMap<String, Vec<CountryStats>>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(
+            "SELECT
+                domains.domain,
+                record.country,
+                sum(record.count),
+                sum(CASE WHEN record.policy_ev_dkim != 'pass' AND record.policy_ev_spf != 'pass'
+                    THEN record.count ELSE 0 END)
+            FROM report
+            JOIN domains ON domains.id = report.domain_id
+            JOIN record ON record.report = report.id
+            WHERE report.date_begin >= CAST(strftime('%s', date('now', ?)) AS INTEGER)
+            GROUP BY domains.domain, record.country
+            ORDER BY sum(record.count) DESC, record.country",
+        )?;
+        let rows = stmt.query_map(params![format!("-{} days", last_days)], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                CountryStats {
+                    country: row.get(1)?,
+                    count: get_count(row, 2)?,
+                    dmarc_fail: get_count(row, 3)?,
+                },
+            ))
+        })?;
+
+        let mut stats: HashMap<String, Vec<CountryStats>> = HashMap::new();
+        for row in rows {
+            let (domain, country) = row?;
+            stats.entry(domain).or_default().push(country);
+        }
+        Ok(stats)
+    }
+
+    fn get_senders(
+        &self,
+        domain: &str,
+        date_from: Option<i64>,
+        date_to: Option<i64>,
+    ) -> Result<Vec<SenderRow>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(
+            "SELECT
+                record.source_ip,
+                sum(record.count),
+                sum(CASE WHEN record.policy_ev_dkim = 'pass' OR record.policy_ev_spf = 'pass'
+                    THEN record.count ELSE 0 END),
+                sum(CASE WHEN record.policy_ev_dkim = 'pass' THEN record.count ELSE 0 END),
+                sum(CASE WHEN record.policy_ev_spf = 'pass' THEN record.count ELSE 0 END),
+                min(report.date_begin),
+                max(report.date_end)
+            FROM report
+            JOIN domains ON domains.id = report.domain_id
+            JOIN record ON record.report = report.id
+            WHERE domains.domain = ?1
+            AND (?2 IS NULL OR report.date_begin >= ?2)
+            AND (?3 IS NULL OR report.date_begin < ?3)
+            GROUP BY record.source_ip",
+        )?;
+        let rows = stmt.query_map(params![domain, date_from, date_to], |row| {
+            Ok(SenderRow {
+                source_ip: row.get(0)?,
+                count: get_count(row, 1)?,
+                dmarc_pass: get_count(row, 2)?,
+                dkim_pass: get_count(row, 3)?,
+                spf_pass: get_count(row, 4)?,
+                first_seen: row.get(5)?,
+                last_seen: row.get(6)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<SenderRow>>>()?)
+    }
+
+    fn get_stale_ips(&self, looked_up_before: i64, limit: u32) -> Result<Vec<String>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(
+            "SELECT record.source_ip
+            FROM record
+            LEFT JOIN ip_info ON ip_info.ip = record.source_ip
+            WHERE ip_info.ip IS NULL OR ip_info.looked_up_at < ?1
+            GROUP BY record.source_ip
+            ORDER BY min(coalesce(ip_info.looked_up_at, 0)), record.source_ip
+            LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![looked_up_before, limit], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<String>>>()?)
+    }
+
+    fn set_ip_info(&self, info: &IpInfo) -> Result<()> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        conn.execute(
+            "INSERT OR REPLACE INTO ip_info (ip, hostname, asn, as_org, looked_up_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                info.ip,
+                info.hostname,
+                info.asn,
+                info.as_org,
+                info.looked_up_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_ip_info(&self, ips: &[String]) -> Result<HashMap<String, IpInfo>> {
+        let conn = self.reader()?;
+        let mut stmt = conn
+            .prepare("SELECT ip, hostname, asn, as_org, looked_up_at FROM ip_info WHERE ip = ?1")?;
+        let mut infos = HashMap::new();
+        for ip in ips {
+            let info = stmt
+                .query_row(params![ip], |row| {
+                    Ok(IpInfo {
+                        ip: row.get(0)?,
+                        hostname: row.get(1)?,
+                        asn: row.get(2)?,
+                        as_org: row.get(3)?,
+                        looked_up_at: row.get(4)?,
+                    })
+                })
+                .optional()?;
+            if let Some(info) = info {
+                infos.insert(ip.clone(), info);
+            }
+        }
+        Ok(infos)
+    }
+
+    fn get_imported_sources(&self, since: i64) -> Result<Vec<ImportedSource>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(
+            "SELECT
+                domains.domain,
+                record.source_ip,
+                sum(record.count),
+                sum(CASE WHEN record.policy_ev_dkim = 'pass' OR record.policy_ev_spf = 'pass'
+                    THEN record.count ELSE 0 END),
+                EXISTS (
+                    SELECT 1 FROM report AS old
+                    JOIN record AS old_record ON old_record.report = old.id
+                    WHERE old.domain_id = domains.id
+                    AND (old.imported_at IS NULL OR old.imported_at < ?1)
+                    AND old_record.source_ip = record.source_ip
+                )
+            FROM report
+            JOIN domains ON domains.id = report.domain_id
+            JOIN record ON record.report = report.id
+            WHERE report.imported_at >= ?1
+            GROUP BY domains.id, domains.domain, record.source_ip
+            ORDER BY domains.domain, record.source_ip",
+        )?;
+        let rows = stmt.query_map(params![since], |row| {
+            Ok(ImportedSource {
+                domain: row.get(0)?,
+                source_ip: row.get(1)?,
+                count: get_count(row, 2)?,
+                dmarc_pass: get_count(row, 3)?,
+                known: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<ImportedSource>>>()?)
+    }
+
+    fn get_new_reporters(&self, since: i64) -> Result<Vec<(String, String)>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT domains.domain, report.org_name
+            FROM report
+            JOIN domains ON domains.id = report.domain_id
+            WHERE report.imported_at >= ?1
+            AND NOT EXISTS (
+                SELECT 1 FROM report AS old
+                WHERE old.domain_id = report.domain_id
+                AND old.org_name = report.org_name
+                AND (old.imported_at IS NULL OR old.imported_at < ?1)
+            )
+            ORDER BY domains.domain, report.org_name",
+        )?;
+        let rows = stmt.query_map(params![since], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<(String, String)>>>()?)
+    }
+
+    fn insert_alert(&self, alert: &Alert) -> Result<i64> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        conn.execute(
+            "INSERT INTO alert (rule, domain, message, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![alert.rule, alert.domain, alert.message, alert.created_at],
+        )?;
+
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn get_alerts(&self, limit: u32) -> Result<Vec<Alert>> {
+        let conn = self.reader()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, rule, domain, message, created_at, acknowledged_by, acknowledged_at
+            FROM alert
+            ORDER BY id DESC
+            LIMIT ?",
+        )?;
+        let rows = stmt.query_map(params![limit], Self::alert_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<Alert>>>()?)
+    }
+
+    fn get_alert(&self, id: i64) -> Result<Option<Alert>> {
+        let conn = self.reader()?;
+        Ok(conn
+            .query_row(
+                "SELECT id, rule, domain, message, created_at, acknowledged_by, acknowledged_at
+                FROM alert
+                WHERE id = ?",
+                params![id],
+                Self::alert_from_row,
+            )
+            .optional()?)
+    }
+
+    fn acknowledge_alert(&self, id: i64, user: &str, at: i64) -> Result<bool> {
+        let conn = &self.writer.lock().expect("Could not get DB lock");
+        let changed = conn.execute(
+            "UPDATE alert SET acknowledged_by = ?1, acknowledged_at = ?2
+            WHERE id = ?3 AND acknowledged_at IS NULL",
+            params![user, at, id],
+        )?;
+        Ok(changed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::fixture::ReportFixture;
+
+    fn report(
+        org_name: &str,
+        begin: i64,
+        source_ip: &str,
+        count: u32,
+        dkim: &str,
+    ) -> report::Report {
+        ReportFixture {
+            org_name,
+            report_id: &begin.to_string(),
+            begin,
+            source_ip,
+            count,
+            dkim,
+            spf: "fail",
+            ..Default::default()
+        }
+        .report()
+    }
+
+    #[test]
+    fn test_auth_results() {
+        let storage = SqliteStorage::new(Path::new(":memory:")).unwrap();
+        let mut report = report("google.com", 1612137600, "192.0.2.1", 1, "fail");
+        report.records[0].auth_results_dkim[0].human_result = Some(String::from("bad signature"));
+        // Reporters may list the results of both SPF identities
+        report.records[0].auth_results_spf.push(report::SpfResult {
+            domain: Some(String::from("mx.example.com")),
+            scope: Some(String::from("helo")),
+            result: Some(String::from("pass")),
+        });
+        storage.insert_report(&report).unwrap();
+
+        let stored = storage.get_report(1).unwrap().unwrap();
+        assert_eq!(
+            report.records[0].auth_results_dkim,
+            stored.records[0].auth_results_dkim
+        );
+        assert_eq!(
+            report.records[0].auth_results_spf,
+            stored.records[0].auth_results_spf
+        );
+    }
+
+    #[test]
+    fn test_report_filter() {
+        let storage = SqliteStorage::new(Path::new(":memory:")).unwrap();
+        // 2021-02-01, 2021-02-02 and 2021-02-03
+        for (org, begin, ip, count, dkim) in [
+            ("google.com", 1612137600, "192.0.2.1", 5, "pass"),
+            ("yahoo.com", 1612224000, "198.51.100.7", 1, "fail"),
+            ("google.com", 1612310400, "192.0.2.200", 3, "fail"),
+        ] {
+            storage
+                .insert_report(&report(org, begin, ip, count, dkim))
+                .unwrap();
+        }
+
+        let orgs = |filter: ReportFilter| -> Vec<(String, i64)> {
+            storage
+                .get_reports(&filter, 0, None)
+                .unwrap()
+                .into_iter()
+                .map(|report| (report.org_name, report.date_begin))
+                .collect()
+        };
+        let domain = String::from("example.com");
+
+        let filter = ReportFilter {
+            domain: domain.clone(),
+            org_name: Some(String::from("GOOGLE")),
+            ..Default::default()
+        };
+        assert_eq!(2, storage.count_reports(&filter).unwrap());
+        let filter = ReportFilter {
+            domain: domain.clone(),
+            source_ip: Some(String::from("192.0.2.")),
+            date_from: Some(1612224000),
+            ..Default::default()
+        };
+        assert_eq!(vec![(String::from("google.com"), 1612310400)], orgs(filter));
+        let filter = ReportFilter {
+            domain: domain.clone(),
+            dmarc_result: Some(String::from(crate::db::DMARC_FAIL)),
+            date_to: Some(1612310400),
+            ..Default::default()
+        };
+        assert_eq!(vec![(String::from("yahoo.com"), 1612224000)], orgs(filter));
+
+        let filter = ReportFilter {
+            domain,
+            sort: crate::db::ReportSort::Count,
+            ascending: true,
+            ..Default::default()
+        };
+        let counts: Vec<i64> = orgs(filter)
+            .into_iter()
+            .map(|(_org, begin)| begin)
+            .collect();
+        assert_eq!(vec![1612224000, 1612310400, 1612137600], counts);
+
+        // Wildcards in the filter text are matched literally
+        storage
+            .insert_report(&report(
+                "mail_ru.example",
+                1612396800,
+                "192.0.2.1",
+                1,
+                "pass",
+            ))
+            .unwrap();
+        let org_count = |org_name: &str| {
+            storage
+                .count_reports(&ReportFilter {
+                    domain: String::from("example.com"),
+                    org_name: Some(String::from(org_name)),
+                    ..Default::default()
+                })
+                .unwrap()
+        };
+        assert_eq!(1, org_count("l_r"));
+        assert_eq!(0, org_count("e_c"));
+        assert_eq!(0, org_count("%"));
+        let filter = ReportFilter {
+            domain: String::from("example.com"),
+            source_ip: Some(String::from("192_0")),
+            ..Default::default()
+        };
+        assert_eq!(0, storage.count_reports(&filter).unwrap());
+    }
+
+    #[test]
+    fn test_accounts() {
+        let storage = SqliteStorage::new(Path::new(":memory:")).unwrap();
+        for (account, domain) in [
+            ("alice", "example.com"),
+            ("bob", "example.org"),
+            ("carol", "example.com"),
+        ] {
+            let mut report = ReportFixture {
+                report_id: account,
+                domain,
+                ..Default::default()
+            }
+            .report();
+            report.account = Some(String::from(account));
+            storage.insert_report(&report).unwrap();
+        }
+        // Only the accounts of the domain are offered in its report filter
+        assert_eq!(
+            vec![String::from("alice"), String::from("carol")],
+            storage.get_accounts("example.com").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_ip_info() {
+        let storage = SqliteStorage::new(Path::new(":memory:")).unwrap();
+        for (begin, ip) in [(1612137600, "192.0.2.1"), (1612224000, "192.0.2.2")] {
+            storage
+                .insert_report(&report("google.com", begin, ip, 1, "pass"))
+                .unwrap();
+        }
+        let info = |ip: &str, looked_up_at: i64| IpInfo {
+            ip: String::from(ip),
+            hostname: Some(String::from("mail.example.com")),
+            asn: Some(64500),
+            as_org: None,
+            looked_up_at,
+        };
+
+        assert_eq!(
+            vec![String::from("192.0.2.1"), String::from("192.0.2.2")],
+            storage.get_stale_ips(1000, 10).unwrap()
+        );
+        storage.set_ip_info(&info("192.0.2.1", 500)).unwrap();
+        storage.set_ip_info(&info("192.0.2.2", 2000)).unwrap();
+        // entries older than the TTL are looked up again
+        assert_eq!(
+            vec![String::from("192.0.2.1")],
+            storage.get_stale_ips(1000, 10).unwrap()
+        );
+        storage.set_ip_info(&info("192.0.2.1", 1500)).unwrap();
+        assert!(storage.get_stale_ips(1000, 10).unwrap().is_empty());
+
+        let infos = storage
+            .get_ip_info(&[String::from("192.0.2.1"), String::from("198.51.100.1")])
+            .unwrap();
+        assert_eq!(1, infos.len());
+        assert_eq!(info("192.0.2.1", 1500), infos["192.0.2.1"]);
+    }
+
+    #[test]
+    fn test_country_stats() {
+        let storage = SqliteStorage::new(Path::new(":memory:")).unwrap();
+        let begin = chrono::Utc::now().timestamp();
+        for (org, count, dkim, country) in [
+            ("google.com", 5, "pass", Some("DE")),
+            ("yahoo.com", 2, "fail", Some("DE")),
+            ("outlook.com", 9, "fail", None),
+        ] {
+            let mut report = report(org, begin, "192.0.2.1", count, dkim);
+            report.records[0].country = country.map(String::from);
+            storage.insert_report(&report).unwrap();
+        }
+
+        assert_eq!(
+            vec![
+                CountryStats {
+                    country: None,
+                    count: 9,
+                    dmarc_fail: 9,
+                },
+                CountryStats {
+                    country: Some(String::from("DE")),
+                    count: 7,
+                    dmarc_fail: 2,
+                },
+            ],
+            storage.get_country_stats(30).unwrap()["example.com"]
+        );
+        let report = storage.get_report(1).unwrap().unwrap();
+        assert_eq!(Some(String::from("DE")), report.records[0].country);
+    }
+
+    #[test]
+    fn test_large_counts() {
+        let storage = SqliteStorage::new(Path::new(":memory:")).unwrap();
+        let begin = chrono::Utc::now().timestamp();
+        // The largest count a record can have, three of them don't fit into 32 bits
+        let count = i32::MAX as u32;
+        for org in ["google.com", "yahoo.com", "outlook.com"] {
+            storage
+                .insert_report(&report(org, begin, "192.0.2.1", count, "pass"))
+                .unwrap();
+        }
+        let total = 3 * u64::from(count);
+        assert!(total > u64::from(u32::MAX));
+
+        let stats = &storage.get_basic_stats(30).unwrap()["example.com"];
+        assert_eq!((total, total), (stats.dkim_passed, stats.spf_failed));
+        let countries = &storage.get_country_stats(30).unwrap()["example.com"];
+        assert_eq!((total, 0), (countries[0].count, countries[0].dmarc_fail));
+        let senders = storage.get_senders("example.com", None, None).unwrap();
+        assert_eq!(total, senders[0].count);
+    }
+
+    #[test]
+    fn test_alerts() {
+        let storage = SqliteStorage::new(Path::new(":memory:")).unwrap();
+        let insert = |org: &str, begin: i64, ip: &str, dkim: &str| {
+            storage
+                .insert_report(&report(org, begin, ip, 3, dkim))
+                .unwrap();
+        };
+        insert("google.com", 1612137600, "192.0.2.1", "pass");
+        // reports stored before the import time was recorded count as old as well
+        storage
+            .writer
+            .lock()
+            .unwrap()
+            .execute("UPDATE report SET imported_at = NULL", params![])
+            .unwrap();
+        insert("yahoo.com", 1612224000, "192.0.2.1", "pass");
+        insert("google.com", 1612310400, "198.51.100.7", "fail");
+
+        let since = Utc::now().timestamp() - 60;
+        let sources: Vec<(String, u64, bool)> = storage
+            .get_imported_sources(since)
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.source_ip, s.dmarc_pass, s.known))
+            .collect();
+        assert_eq!(
+            vec![
+                (String::from("192.0.2.1"), 3, true),
+                (String::from("198.51.100.7"), 0, false)
+            ],
+            sources
+        );
+        assert_eq!(
+            vec![(String::from("example.com"), String::from("yahoo.com"))],
+            storage.get_new_reporters(since).unwrap()
+        );
+        assert!(storage
+            .get_imported_sources(Utc::now().timestamp() + 60)
+            .unwrap()
+            .is_empty());
+
+        let mut alert = Alert {
+            id: 0,
+            rule: String::from("senders"),
+            domain: String::from("example.com"),
+            message: String::from("1 new unknown sending IPs: 198.51.100.7"),
+            created_at: since,
+            acknowledged_by: None,
+            acknowledged_at: None,
+        };
+        alert.id = storage.insert_alert(&alert).unwrap();
+        assert_eq!(vec![alert.clone()], storage.get_alerts(10).unwrap());
+        assert!(storage.acknowledge_alert(alert.id, "alice", since).unwrap());
+        assert!(!storage.acknowledge_alert(alert.id, "bob", since).unwrap());
+        let acknowledged = storage.get_alert(alert.id).unwrap().unwrap();
+        assert_eq!(Some(String::from("alice")), acknowledged.acknowledged_by);
+    }
+
+    #[test]
+    fn test_read_during_write() {
+        // The -wal and -shm files are removed along with the directory
+        let dir = tempfile::tempdir().unwrap();
+        let storage = SqliteStorage::new(&dir.path().join("dmarc.db")).unwrap();
+        storage
+            .writer
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO domains (domain) VALUES ('example.com')",
+                params![],
+            )
+            .unwrap();
+
+        // A write transaction that is still open does not block readers
+        let writer = storage.writer.lock().unwrap();
+        writer.execute_batch("BEGIN IMMEDIATE").unwrap();
+        writer
+            .execute(
+                "INSERT INTO domains (domain) VALUES ('example.org')",
+                params![],
+            )
+            .unwrap();
+        assert_eq!(
+            vec![String::from("example.com")],
+            storage.get_domains().unwrap()
+        );
+        writer.execute_batch("COMMIT").unwrap();
+        drop(writer);
+        assert_eq!(2, storage.get_domains().unwrap().len());
+    }
+}
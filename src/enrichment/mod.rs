@@ -0,0 +1,295 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use maxminddb::{geoip2, MaxMindDBError};
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::db;
+use crate::report::Report;
+
+// Upper bound of lookups per run, the remaining IPs are looked up by the next runs
+const MAX_LOOKUPS: u32 = 1000;
+// Reverse DNS lookups run this many at a time
+const DNS_CONCURRENCY: usize = 16;
+// No further reverse DNS lookups are started after this time
+const DNS_TIME_LIMIT: Duration = Duration::from_secs(120);
+
+// Range of an iptoasn.com TSV file
+#[derive(Debug, PartialEq)]
+pub struct AsnRange {
+    start: IpAddr,
+    end: IpAddr,
+    asn: u32,
+    org: String,
+}
+
+// Offline database mapping IPs to autonomous systems
+pub enum AsnDatabase {
+    MaxMind(maxminddb::Reader<Vec<u8>>),
+    // Sorted by the start of the ranges
+    Ranges(Vec<AsnRange>),
+}
+
+impl AsnDatabase {
+    // .mmdb files are MaxMind databases, anything else is read as iptoasn.com TSV file
+    pub fn open(path: &Path) -> Result<Self> {
+        if path.extension().and_then(|e| e.to_str()) == Some("mmdb") {
+            let reader = maxminddb::Reader::open_readfile(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            return Ok(AsnDatabase::MaxMind(reader));
+        }
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse_tsv(&text).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    // Lines of the form: range_start range_end AS_number country_code AS_description
+    fn parse_tsv(text: &str) -> Result<Self> {
+        let mut ranges = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 5 {
+                return Err(anyhow!("Line {}: expected 5 fields", number + 1));
+            }
+            let parse_ip = |field: &str| -> Result<IpAddr> {
+                field
+                    .parse()
+                    .with_context(|| format!("Line {}: invalid IP '{}'", number + 1, field))
+            };
+            let asn: u32 = fields[2]
+                .parse()
+                .with_context(|| format!("Line {}: invalid AS number", number + 1))?;
+            // AS 0 marks ranges that are not routed
+            if asn == 0 {
+                continue;
+            }
+            ranges.push(AsnRange {
+                start: parse_ip(fields[0])?,
+                end: parse_ip(fields[1])?,
+                asn,
+                org: String::from(fields[4]),
+            });
+        }
+        ranges.sort_by_key(|range| range.start);
+
+        Ok(AsnDatabase::Ranges(ranges))
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Result<Option<(u32, Option<String>)>> {
+        match self {
+            AsnDatabase::MaxMind(reader) => match reader.lookup::<geoip2::Asn>(ip) {
+                Ok(asn) => Ok(asn
+                    .autonomous_system_number
+                    .map(|number| (number, asn.autonomous_system_organization.map(String::from)))),
+                Err(MaxMindDBError::AddressNotFoundError(_e)) => Ok(None),
+                Err(e) => Err(anyhow!("ASN lookup of {} failed: {}", ip, e)),
+            },
+            AsnDatabase::Ranges(ranges) => {
+                // IPv4 addresses sort before IPv6 addresses, so the range has the same family
+                let index = ranges.partition_point(|range| range.start <= ip);
+                Ok(index
+                    .checked_sub(1)
+                    .map(|i| &ranges[i])
+                    .filter(|range| ip <= range.end)
+                    .map(|range| (range.asn, Some(range.org.clone()))))
+            }
+        }
+    }
+}
+
+// Offline databases, opened once at startup
+#[derive(Default)]
+pub struct IpDatabases {
+    asn: Option<AsnDatabase>,
+    country: Option<maxminddb::Reader<Vec<u8>>>,
+}
+
+impl IpDatabases {
+    pub fn open(config: &Config) -> Result<Self> {
+        let asn = match &config.enrichment.asn_db {
+            Some(path) => Some(AsnDatabase::open(path)?),
+            None => None,
+        };
+        let country = match &config.enrichment.country_db {
+            Some(path) => Some(
+                maxminddb::Reader::open_readfile(path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?,
+            ),
+            None => None,
+        };
+        Ok(Self { asn, country })
+    }
+
+    // Sets the country of the records if a country database is configured
+    pub fn locate(&self, report: &mut Report) {
+        if let Some(reader) = &self.country {
+            for record in report.records.iter_mut() {
+                record.country = country(reader, &record.source_ip);
+            }
+        }
+    }
+}
+
+// ISO code of the country the IP is located in, or else of the country it is registered in
+fn country(reader: &maxminddb::Reader<Vec<u8>>, ip: &str) -> Option<String> {
+    let ip: IpAddr = ip.parse().ok()?;
+    let result: geoip2::Country = reader.lookup(ip).ok()?;
+    result
+        .country
+        .and_then(|c| c.iso_code)
+        .or_else(|| result.registered_country.and_then(|c| c.iso_code))
+        .map(String::from)
+}
+
+fn reverse_dns(ip: &IpAddr) -> Option<String> {
+    let hostname = dns_lookup::lookup_addr(ip).ok()?;
+    // getnameinfo falls back to the numeric address if there is no PTR record
+    if hostname.parse::<IpAddr>().is_ok() {
+        None
+    } else {
+        Some(hostname)
+    }
+}
+
+// Resolves the IPs on a thread each and waits for all of them, so no lookup outlives the run. How
+// long a lookup may take is up to the timeouts of the system resolver.
+fn reverse_dns_all(ips: &[IpAddr]) -> HashMap<IpAddr, String> {
+    thread::scope(|scope| {
+        let lookups: Vec<_> = ips
+            .iter()
+            .map(|ip| scope.spawn(move || (*ip, reverse_dns(ip))))
+            .collect();
+        lookups
+            .into_iter()
+            .filter_map(|lookup| match lookup.join() {
+                Ok((ip, Some(hostname))) => Some((ip, hostname)),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+// Looks up the source IPs that are not cached or whose information is older than the TTL
+pub fn enrich(
+    config: &Config,
+    database: &dyn db::Storage,
+    ip_databases: &IpDatabases,
+    log: &mut impl Write,
+) -> Result<()> {
+    let enrichment = &config.enrichment;
+    if !enrichment.reverse_dns && ip_databases.asn.is_none() {
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    let now = Utc::now().timestamp();
+    let ttl = i64::try_from(enrichment.ttl_hours.saturating_mul(3600)).unwrap_or(i64::MAX);
+    let ips = database.get_stale_ips(now.saturating_sub(ttl), MAX_LOOKUPS)?;
+    let mut looked_up = 0;
+    for chunk in ips.chunks(DNS_CONCURRENCY) {
+        if enrichment.reverse_dns && started.elapsed() > DNS_TIME_LIMIT {
+            writeln!(
+                log,
+                "Reverse DNS took too long, {} source IPs are looked up by the next run",
+                ips.len() - looked_up
+            )?;
+            break;
+        }
+        // invalid IPs are cached without information, so they are not tried on every run
+        let parsed: Vec<(&String, Option<IpAddr>)> = chunk
+            .iter()
+            .map(|source_ip| (source_ip, source_ip.parse().ok()))
+            .collect();
+        let hostnames = if enrichment.reverse_dns {
+            let valid: Vec<IpAddr> = parsed.iter().filter_map(|(_s, ip)| *ip).collect();
+            reverse_dns_all(&valid)
+        } else {
+            HashMap::new()
+        };
+
+        for (source_ip, ip) in parsed {
+            let mut info = db::IpInfo {
+                ip: source_ip.clone(),
+                hostname: None,
+                asn: None,
+                as_org: None,
+                looked_up_at: now,
+            };
+            if let Some(ip) = ip {
+                info.hostname = hostnames.get(&ip).cloned();
+                if let Some(asn_db) = &ip_databases.asn {
+                    if let Some((asn, org)) = asn_db.lookup(ip)? {
+                        info.asn = Some(asn);
+                        info.as_org = org;
+                    }
+                }
+            }
+            database.set_ip_info(&info)?;
+        }
+        looked_up += chunk.len();
+    }
+    if looked_up > 0 {
+        writeln!(log, "Looked up {} source IPs", looked_up)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract;
+
+    #[test]
+    fn test_asn_ranges() {
+        let tsv = "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n\
+                   1.0.1.0\t1.0.3.255\t0\tNone\tNot routed\n\
+                   192.0.2.0\t192.0.2.127\t64500\tZZ\tEXAMPLE-AS\n\
+                   2001:db8::\t2001:db8::ffff\t64501\tZZ\tEXAMPLE-V6\n";
+        let db = AsnDatabase::parse_tsv(tsv).unwrap();
+        let lookup = |ip: &str| db.lookup(ip.parse().unwrap()).unwrap();
+
+        assert_eq!(
+            Some((13335, Some(String::from("CLOUDFLARENET")))),
+            lookup("1.0.0.1")
+        );
+        assert_eq!(None, lookup("1.0.2.1"));
+        assert_eq!(Some(64500), lookup("192.0.2.127").map(|(asn, _org)| asn));
+        assert_eq!(None, lookup("192.0.2.128"));
+        assert_eq!(Some(64501), lookup("2001:db8::1").map(|(asn, _org)| asn));
+        assert_eq!(None, lookup("2001:db8::1:0"));
+        // IPv6 addresses never match an IPv4 range
+        assert_eq!(None, lookup("::1"));
+
+        assert!(AsnDatabase::parse_tsv("1.0.0.0\t1.0.0.255\t13335").is_err());
+        assert!(AsnDatabase::parse_tsv("1.0.0.0\tx\t13335\tUS\tTEST").is_err());
+    }
+
+    #[test]
+    fn test_locate() {
+        // maps 192.0.2.0/24 to DE
+        let country = include_bytes!("../../tests/data/country.mmdb").to_vec();
+        let ip_databases = IpDatabases {
+            asn: None,
+            country: Some(maxminddb::Reader::from_source(country).unwrap()),
+        };
+        let body = include_bytes!("../../tests/data/yahoo.eml");
+        let mut report = match extract::parse_message(body).unwrap() {
+            extract::Message::Aggregate(report) => report,
+            extract::Message::Forensic(_r) => panic!("parsed as forensic report"),
+        };
+        assert_eq!(None, report.records[0].country);
+
+        IpDatabases::default().locate(&mut report);
+        assert_eq!(None, report.records[0].country);
+        ip_databases.locate(&mut report);
+        assert_eq!(Some(String::from("DE")), report.records[0].country);
+    }
+}
@@ -0,0 +1,506 @@
+pub mod sniff;
+
+use anyhow::{anyhow, Context, Result};
+use mailparse::*;
+use serde_xml_rs::from_reader;
+
+use crate::db;
+use crate::enrichment::IpDatabases;
+use crate::report;
+use crate::report::forensic;
+use crate::report::serde_defs;
+
+pub enum Message {
+    Aggregate(report::Report),
+    Forensic(forensic::ForensicReport),
+}
+
+pub enum Imported {
+    Aggregate(String),
+    Forensic(String),
+    // domain and description of a report that already exists
+    Duplicate(String, String),
+}
+
+pub struct Attachment {
+    pub content: Vec<u8>,
+    pub decompressed: Option<Vec<u8>>,
+    pub mimetype: String,
+    pub name: String,
+}
+
+pub fn decompress_attachment(mut attachment: Attachment) -> Result<Attachment> {
+    // Decompresses the attachment, saves it in te Attachment struct and returns it
+    let (decompressed, name) =
+        sniff::unpack(&attachment.content, &attachment.name, &attachment.mimetype)?;
+    attachment.name = name;
+    attachment.decompressed = Some(decompressed);
+
+    Ok(attachment)
+}
+
+pub fn get_attachment(mail: &ParsedMail) -> Result<Attachment> {
+    // Extracts the attachment from the mail
+    let part = find_report_part(mail).ok_or_else(|| anyhow!("No attachment found."))?;
+
+    let body = part.get_body_raw()?;
+    if body.is_empty() {
+        return Err(anyhow!("No attachment found."));
+    }
+
+    Ok(Attachment {
+        content: body,
+        decompressed: None,
+        name: part_name(part),
+        mimetype: part.ctype.mimetype.clone(),
+    })
+}
+
+fn find_report_part<'a>(mail: &'a ParsedMail<'a>) -> Option<&'a ParsedMail<'a>> {
+    // Returns the first part that might contain a report, nested multiparts are searched as well
+    if mail.subparts.is_empty() {
+        if sniff::is_usable(&mail.ctype.mimetype, &part_name(mail)) {
+            return Some(mail);
+        }
+        return None;
+    }
+    mail.subparts.iter().find_map(find_report_part)
+}
+
+fn part_name(part: &ParsedMail) -> String {
+    // Some senders only set the name in the Content-Type header
+    part.get_content_disposition()
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .cloned()
+        .unwrap_or_default()
+}
+
+pub fn parse_report(xml: Vec<u8>) -> Result<report::Report> {
+    // Parses the decompressed XML and keeps it as blob in the report
+    let parsed_report: serde_defs::Feedback =
+        from_reader(std::io::Cursor::new(&xml)).context("Failed to parse report XML")?;
+
+    Ok(report::Report::from_with_blob(parsed_report, Some(xml)))
+}
+
+pub fn parse_message(body: &[u8]) -> Result<Message> {
+    // Runs the whole pipeline on a raw mail, forensic reports are recognized by their MIME type,
+    // everything else is expected to carry an aggregate report as attachment
+
+    // report files that are not wrapped in a mail, e.g. from a local import
+    if sniff::detect(body, "", "") != sniff::ContentKind::Unknown {
+        let (xml, _name) = sniff::unpack(body, "", "")?;
+        return Ok(Message::Aggregate(parse_report(xml)?));
+    }
+
+    let mail = parse_mail(body)?;
+
+    if let Some(report_part) = find_feedback_report(&mail) {
+        let mut report = get_forensic_report(report_part)?;
+        report.message_id = mail.headers.get_first_value("Message-ID");
+        if report.received_at == 0 {
+            report.received_at = mail
+                .headers
+                .get_first_value("Date")
+                .and_then(|date| dateparse(&date).ok())
+                .unwrap_or_else(|| chrono::Utc::now().timestamp());
+        }
+        report.blob = Some(body.to_vec());
+        return Ok(Message::Forensic(report));
+    }
+
+    let attachment = decompress_attachment(get_attachment(&mail)?)?;
+
+    Ok(Message::Aggregate(parse_report(
+        attachment.decompressed.unwrap_or_default(),
+    )?))
+}
+
+pub fn import_message(
+    database: &dyn db::Storage,
+    ip_databases: &IpDatabases,
+    body: &[u8],
+    account: &str,
+) -> Result<Imported> {
+    // Parses the raw message and stores the contained report, a report that already exists is
+    // not considered an error
+    match parse_message(body)? {
+        Message::Aggregate(mut report) => {
+            report.account = Some(String::from(account));
+            ip_databases.locate(&mut report);
+            let description = format!(
+                "Report: '{}' - Organisation: '{}'",
+                report.report_id, report.org_name
+            );
+
+            match database.insert_report(&report) {
+                Ok(true) => Ok(Imported::Aggregate(
+                    report.policy_domain.unwrap_or_default(),
+                )),
+                Ok(false) => Ok(Imported::Duplicate(
+                    report.policy_domain.unwrap_or_default(),
+                    format!("Report already exists -- {}", description),
+                )),
+                Err(e) => Err(anyhow!(e).context(description)),
+            }
+        }
+        Message::Forensic(mut report) => {
+            report.account = Some(String::from(account));
+            let description = format!(
+                "Forensic report: {}",
+                report.message_id.clone().unwrap_or_default()
+            );
+
+            match database.insert_forensic_report(&report) {
+                Ok(true) => Ok(Imported::Forensic(
+                    report.reported_domain.unwrap_or_default(),
+                )),
+                Ok(false) => Ok(Imported::Duplicate(
+                    report.reported_domain.unwrap_or_default(),
+                    format!("Report already exists -- {}", description),
+                )),
+                Err(e) => Err(anyhow!(e).context(description)),
+            }
+        }
+    }
+}
+
+pub fn message_id(body: &[u8]) -> Option<String> {
+    parse_mail(body)
+        .ok()
+        .and_then(|mail| mail.headers.get_first_value("Message-ID"))
+}
+
+fn find_feedback_report<'a>(mail: &'a ParsedMail<'a>) -> Option<&'a ParsedMail<'a>> {
+    // Returns the multipart/report part containing a message/feedback-report, it might be
+    // nested in other multipart parts
+    if mail.ctype.mimetype == "multipart/report"
+        && mail
+            .subparts
+            .iter()
+            .any(|p| p.ctype.mimetype == "message/feedback-report")
+    {
+        return Some(mail);
+    }
+    mail.subparts.iter().find_map(find_feedback_report)
+}
+
+fn get_forensic_report(mail: &ParsedMail) -> Result<forensic::ForensicReport> {
+    // Parses a RFC 6591 failure report from the parts of a multipart/report
+    let mut feedback_headers = Vec::new();
+    let mut original_headers = Vec::new();
+
+    for subpart in &mail.subparts {
+        match subpart.ctype.mimetype.as_str() {
+            "message/feedback-report" => {
+                let body = subpart.get_body_raw()?;
+                feedback_headers = parse_headers(&body)?
+                    .0
+                    .iter()
+                    .map(|h| forensic::Header {
+                        name: h.get_key(),
+                        value: h.get_value(),
+                    })
+                    .collect();
+            }
+            "message/rfc822" | "text/rfc822-headers" => {
+                let body = subpart.get_body_raw()?;
+                original_headers = parse_headers(&body)?
+                    .0
+                    .iter()
+                    .map(|h| forensic::Header {
+                        name: h.get_key(),
+                        value: h.get_value(),
+                    })
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+
+    let field = |name: &str| {
+        feedback_headers
+            .iter()
+            .find(|h: &&forensic::Header| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value.trim().to_string())
+    };
+
+    let feedback_type = field("Feedback-Type").ok_or_else(|| anyhow!("No feedback type found."))?;
+    let arrival_date = field("Arrival-Date").or_else(|| field("Received-Date"));
+    let received_at = arrival_date
+        .as_ref()
+        .and_then(|date| dateparse(date).ok())
+        .unwrap_or(0);
+
+    let mut report = forensic::ForensicReport {
+        account: None,
+        message_id: None,
+        blob: None,
+        feedback_type,
+        user_agent: field("User-Agent"),
+        arrival_date,
+        received_at,
+        source_ip: field("Source-IP"),
+        reported_domain: field("Reported-Domain"),
+        original_mail_from: field("Original-Mail-From"),
+        original_rcpt_to: field("Original-Rcpt-To"),
+        auth_failure: field("Auth-Failure"),
+        authentication_results: field("Authentication-Results"),
+        delivery_result: field("Delivery-Result"),
+        dkim_domain: field("DKIM-Domain"),
+        dkim_selector: field("DKIM-Selector"),
+        identity_alignment: field("Identity-Alignment"),
+        original_from: None,
+        original_subject: None,
+        original_headers,
+    };
+    report.original_from = report.original_header("From");
+    report.original_subject = report.original_header("Subject");
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORENSIC_MAIL: &str = "From: dmarc-noreply@receiver.example\r
+To: dmarc-ruf@example.com\r
+Subject: Report Domain: example.com Submitter: receiver.example\r
+Message-ID: <ruf-1@receiver.example>\r
+Date: Mon, 1 Feb 2021 12:00:00 +0000\r
+MIME-Version: 1.0\r
+Content-Type: multipart/report; report-type=feedback-report; boundary=\"b1\"\r
+\r
+--b1\r
+Content-Type: text/plain\r
+\r
+This is an authentication failure report.\r
+--b1\r
+Content-Type: message/feedback-report\r
+\r
+Feedback-Type: auth-failure\r
+User-Agent: Receiver/1.0\r
+Version: 1\r
+Original-Mail-From: <bounce@spoofer.example>\r
+Arrival-Date: Mon, 1 Feb 2021 11:59:00 +0000\r
+Source-IP: 198.51.100.7\r
+Reported-Domain: example.com\r
+Auth-Failure: dmarc\r
+Delivery-Result: reject\r
+Identity-Alignment: none\r
+--b1\r
+Content-Type: text/rfc822-headers\r
+\r
+From: CEO <ceo@example.com>\r
+To: victim@receiver.example\r
+Subject: Urgent wire transfer\r
+Message-ID: <spoofed@spoofer.example>\r
+--b1--\r
+";
+
+    #[test]
+    fn test_parse_forensic_report() {
+        let report = match parse_message(FORENSIC_MAIL.as_bytes()).unwrap() {
+            Message::Forensic(report) => report,
+            Message::Aggregate(_r) => panic!("parsed as aggregate report"),
+        };
+
+        assert_eq!("auth-failure", report.feedback_type);
+        assert_eq!(
+            Some(String::from("<ruf-1@receiver.example>")),
+            report.message_id
+        );
+        assert_eq!(Some(String::from("198.51.100.7")), report.source_ip);
+        assert_eq!(Some(String::from("example.com")), report.reported_domain);
+        assert_eq!(Some(String::from("dmarc")), report.auth_failure);
+        assert_eq!(Some(String::from("reject")), report.delivery_result);
+        assert_eq!(1612180740, report.received_at);
+        assert_eq!(
+            Some(String::from("CEO <ceo@example.com>")),
+            report.original_from
+        );
+        assert_eq!(
+            Some(String::from("Urgent wire transfer")),
+            report.original_subject
+        );
+        assert_eq!(4, report.original_headers.len());
+    }
+
+    const AGGREGATE_MAIL: &str = "From: noreply-dmarc@receiver.example\r
+Subject: Report domain: example.com\r
+Message-ID: <rua-1@receiver.example>\r
+MIME-Version: 1.0\r
+Content-Type: multipart/mixed; boundary=\"outer\"\r
+\r
+--outer\r
+Content-Type: multipart/alternative; boundary=\"inner\"\r
+\r
+--inner\r
+Content-Type: text/plain\r
+\r
+This is a DMARC aggregate report.\r
+--inner\r
+Content-Type: text/xml; name=\"receiver.example!example.com!1612137600!1612223999.xml\"\r
+\r
+<?xml version=\"1.0\"?>\r
+<feedback></feedback>\r
+--inner--\r
+--outer--\r
+";
+
+    #[test]
+    fn test_get_attachment_nested() {
+        let mail = parse_mail(AGGREGATE_MAIL.as_bytes()).unwrap();
+        let attachment = decompress_attachment(get_attachment(&mail).unwrap()).unwrap();
+
+        assert_eq!("text/xml", attachment.mimetype);
+        assert_eq!(
+            "receiver.example!example.com!1612137600!1612223999.xml",
+            attachment.name
+        );
+        assert!(attachment
+            .decompressed
+            .unwrap()
+            .starts_with(b"<?xml version=\"1.0\"?>"));
+    }
+
+    // Anonymized aggregate report mails as sent by the big receivers
+    const GOOGLE_MAIL: &[u8] = include_bytes!("../../tests/data/google.eml");
+    const MICROSOFT_MAIL: &[u8] = include_bytes!("../../tests/data/microsoft.eml");
+    const YAHOO_MAIL: &[u8] = include_bytes!("../../tests/data/yahoo.eml");
+    const MAILRU_MAIL: &[u8] = include_bytes!("../../tests/data/mailru.eml");
+
+    fn attachment_kind(body: &[u8]) -> (String, sniff::ContentKind) {
+        let mail = parse_mail(body).unwrap();
+        let attachment = get_attachment(&mail).unwrap();
+        let kind = sniff::detect(&attachment.content, &attachment.name, &attachment.mimetype);
+        (attachment.mimetype, kind)
+    }
+
+    fn aggregate(body: &[u8]) -> report::Report {
+        match parse_message(body).unwrap() {
+            Message::Aggregate(report) => report,
+            Message::Forensic(_r) => panic!("parsed as forensic report"),
+        }
+    }
+
+    #[test]
+    fn test_google_sample() {
+        assert_eq!(
+            (String::from("application/zip"), sniff::ContentKind::Zip),
+            attachment_kind(GOOGLE_MAIL)
+        );
+        let report = aggregate(GOOGLE_MAIL);
+        assert_eq!("google.com", report.org_name);
+        assert_eq!("4412098355173095322", report.report_id);
+        assert_eq!(
+            (1612137600, 1612223999),
+            (report.date_begin, report.date_end)
+        );
+        assert_eq!(Some(String::from("example.com")), report.policy_domain);
+        assert_eq!(Some(String::from("none")), report.policy_p);
+        assert_eq!(2, report.records.len());
+        assert_eq!("209.85.220.41", report.records[0].source_ip);
+        assert_eq!(14, report.records[0].count);
+        assert_eq!(
+            Some(String::from("google")),
+            report.records[0].auth_results_dkim[0].selector
+        );
+        assert!(report.records[1].auth_results_dkim.is_empty());
+        assert_eq!(
+            Some(String::from("softfail")),
+            report.records[1].auth_results_spf[0].result
+        );
+        assert!(report.blob.unwrap().starts_with(b"<?xml"));
+    }
+
+    #[test]
+    fn test_microsoft_sample() {
+        assert_eq!(
+            (String::from("application/gzip"), sniff::ContentKind::Gzip),
+            attachment_kind(MICROSOFT_MAIL)
+        );
+        // the namespaced feedback element and the version do not get in the way
+        let report = aggregate(MICROSOFT_MAIL);
+        assert_eq!("Enterprise Outlook", report.org_name);
+        assert_eq!("dmarcreport@microsoft.com", report.email);
+        assert_eq!(Some(String::from("quarantine")), report.policy_p);
+        assert_eq!(Some(String::from("0")), report.policy_fo);
+        assert_eq!(2, report.records.len());
+        let record = &report.records[1];
+        assert_eq!("203.0.113.80", record.source_ip);
+        assert_eq!("quarantine", record.policy_evaluated_disposition);
+        assert_eq!(
+            Some(String::from("example.org")),
+            record.identifiers_envelope_to
+        );
+        assert_eq!(
+            Some(String::from("bulk.example.net")),
+            record.identifiers_envelope_from
+        );
+        assert_eq!(
+            Some(String::from("mfrom")),
+            record.auth_results_spf[0].scope
+        );
+    }
+
+    #[test]
+    fn test_yahoo_sample() {
+        assert_eq!(
+            (
+                String::from("application/x-zip-compressed"),
+                sniff::ContentKind::Zip
+            ),
+            attachment_kind(YAHOO_MAIL)
+        );
+        let report = aggregate(YAHOO_MAIL);
+        assert_eq!("Yahoo", report.org_name);
+        assert_eq!("1612221632.276331", report.report_id);
+        assert_eq!(None, report.extra_contact_info);
+        assert_eq!(Some(String::from("reject")), report.policy_p);
+        assert_eq!(1, report.records.len());
+        let record = &report.records[0];
+        assert_eq!(("192.0.2.25", 5), (record.source_ip.as_str(), record.count));
+        assert_eq!("fail", record.policy_evaluated_spf);
+        assert_eq!(None, record.identifiers_envelope_from);
+        assert_eq!(
+            Some(String::from("bounce.example.com")),
+            record.auth_results_spf[0].domain
+        );
+    }
+
+    #[test]
+    fn test_mailru_sample() {
+        // sent as application/octet-stream, which is a zip archive and not a gzip
+        assert_eq!(
+            (
+                String::from("application/octet-stream"),
+                sniff::ContentKind::Zip
+            ),
+            attachment_kind(MAILRU_MAIL)
+        );
+        let report = aggregate(MAILRU_MAIL);
+        assert_eq!("Mail.Ru", report.org_name);
+        assert_eq!("33867471592716288", report.report_id);
+        assert_eq!(
+            Some(String::from("http://help.mail.ru/mail-help")),
+            report.extra_contact_info
+        );
+        assert_eq!(2, report.records.len());
+        let record = &report.records[1];
+        assert_eq!(
+            ("198.51.100.99", 7),
+            (record.source_ip.as_str(), record.count)
+        );
+        assert_eq!(
+            vec![report::PolicyOverrideReason {
+                reason_type: String::from("forwarded"),
+                comment: None,
+            }],
+            record.policy_evaluated_reasons
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use anyhow::{anyhow, Result};
+use libflate::gzip::Decoder;
+use std::io::prelude::*;
+use zip::ZipArchive;
+
+// Archives within archives are unpacked up to this depth
+const MAX_DEPTH: u8 = 3;
+// Upper bound of every unpacked file, so that a small attachment cannot fill the memory
+const MAX_UNPACKED_SIZE: u64 = 64 * 1024 * 1024;
+
+const ZIP_MAGIC: [u8; 4] = [0x50, 0x4b, 0x03, 0x04];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const UTF8_BOM: [u8; 3] = [0xef, 0xbb, 0xbf];
+
+const USABLE_MIMETYPES: [&str; 10] = [
+    "application/zip",
+    "application/x-zip",
+    "application/x-zip-compressed",
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-gunzip",
+    "application/octet-stream",
+    "application/xml",
+    "text/xml",
+    "multipart/x-zip",
+];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ContentKind {
+    Zip,
+    Gzip,
+    Xml,
+    Unknown,
+}
+
+pub fn is_usable(mimetype: &str, name: &str) -> bool {
+    // Decides if a mail part might contain a report, either by its MIME type or its file name
+    USABLE_MIMETYPES.contains(&mimetype.to_lowercase().as_str())
+        || kind_from_name(name) != ContentKind::Unknown
+}
+
+pub fn detect(content: &[u8], name: &str, mimetype: &str) -> ContentKind {
+    // The content itself is the most reliable source, file name and MIME type are only used as
+    // fallback as both are often wrong
+    if content.starts_with(&ZIP_MAGIC) {
+        return ContentKind::Zip;
+    }
+    if content.starts_with(&GZIP_MAGIC) {
+        return ContentKind::Gzip;
+    }
+    if looks_like_xml(content) {
+        return ContentKind::Xml;
+    }
+
+    match kind_from_name(name) {
+        ContentKind::Unknown => kind_from_mimetype(mimetype),
+        kind => kind,
+    }
+}
+
+pub fn unpack(content: &[u8], name: &str, mimetype: &str) -> Result<(Vec<u8>, String)> {
+    // Unpacks the content until the XML report is found, returns it together with its file name
+    unpack_nested(content, name, mimetype, 0)
+}
+
+fn unpack_nested(
+    content: &[u8],
+    name: &str,
+    mimetype: &str,
+    depth: u8,
+) -> Result<(Vec<u8>, String)> {
+    if depth > MAX_DEPTH {
+        return Err(anyhow!("Archive nested too deeply."));
+    }
+
+    match detect(content, name, mimetype) {
+        ContentKind::Xml => Ok((content.to_vec(), String::from(name))),
+        ContentKind::Gzip => {
+            let decoder = Decoder::new(std::io::Cursor::new(content))?;
+            let decompressed = read_limited(decoder, MAX_UNPACKED_SIZE)?;
+            unpack_nested(&decompressed, strip_extension(name), "", depth + 1)
+        }
+        ContentKind::Zip => {
+            let mut zip = ZipArchive::new(std::io::Cursor::new(content))?;
+            for i in 0..zip.len() {
+                let file = zip.by_index(i)?;
+                if file.is_dir() {
+                    continue;
+                }
+                let file_name = String::from(file.name());
+                let decompressed = read_limited(file, MAX_UNPACKED_SIZE)?;
+
+                if detect(&decompressed, &file_name, "") != ContentKind::Unknown {
+                    return unpack_nested(&decompressed, &file_name, "", depth + 1);
+                }
+            }
+            Err(anyhow!("No report found in zip archive."))
+        }
+        ContentKind::Unknown => Err(anyhow!("Unknown attachment type '{}'.", mimetype)),
+    }
+}
+
+fn read_limited(reader: impl Read, limit: u64) -> Result<Vec<u8>> {
+    // One byte more than the limit is read to tell a file of exactly the limit from a bigger one
+    let mut content = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut content)?;
+    if content.len() as u64 > limit {
+        return Err(anyhow!("Unpacked file is larger than {} bytes.", limit));
+    }
+    Ok(content)
+}
+
+fn looks_like_xml(content: &[u8]) -> bool {
+    let content = content.strip_prefix(&UTF8_BOM).unwrap_or(content);
+    let start = content
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .unwrap_or(content.len());
+
+    content[start..].starts_with(b"<?xml") || content[start..].starts_with(b"<feedback")
+}
+
+fn kind_from_name(name: &str) -> ContentKind {
+    let name = name.to_lowercase();
+    if name.ends_with(".zip") {
+        ContentKind::Zip
+    } else if name.ends_with(".gz") || name.ends_with(".gzip") {
+        ContentKind::Gzip
+    } else if name.ends_with(".xml") {
+        ContentKind::Xml
+    } else {
+        ContentKind::Unknown
+    }
+}
+
+fn kind_from_mimetype(mimetype: &str) -> ContentKind {
+    match mimetype.to_lowercase().as_str() {
+        "application/zip"
+        | "application/x-zip"
+        | "application/x-zip-compressed"
+        | "multipart/x-zip" => ContentKind::Zip,
+        "application/gzip" | "application/x-gzip" | "application/x-gunzip" => ContentKind::Gzip,
+        "application/xml" | "text/xml" => ContentKind::Xml,
+        _ => ContentKind::Unknown,
+    }
+}
+
+fn strip_extension(name: &str) -> &str {
+    match name.rfind('.') {
+        Some(pos) => &name[..pos],
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libflate::gzip::Encoder;
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+
+    const XML: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\" ?>\n<feedback></feedback>\n";
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new()).unwrap();
+        encoder.write_all(content).unwrap();
+        encoder.finish().into_result().unwrap()
+    }
+
+    fn zip(name: &str, content: &[u8]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        writer.start_file(name, FileOptions::default()).unwrap();
+        writer.write_all(content).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_plain_and_nested() {
+        // uncompressed XML with a byte order mark
+        let mut content = UTF8_BOM.to_vec();
+        content.extend_from_slice(XML.as_bytes());
+        assert_eq!(ContentKind::Xml, detect(&content, "report", "text/plain"));
+
+        // a gzipped report within a zip archive
+        let content = zip("report.xml.gz", &gzip(XML.as_bytes()));
+        assert_eq!(
+            (XML.as_bytes().to_vec(), String::from("report.xml")),
+            unpack(&content, "report.zip", "application/zip").unwrap()
+        );
+
+        assert_eq!(
+            ContentKind::Unknown,
+            detect(b"just some text", "notes.txt", "text/plain")
+        );
+        assert!(unpack(b"just some text", "notes.txt", "text/plain").is_err());
+    }
+
+    #[test]
+    fn test_read_limited() {
+        let content = gzip(&[b' '; 4096]);
+        let decoder = || Decoder::new(std::io::Cursor::new(&content)).unwrap();
+        assert_eq!(4096, read_limited(decoder(), 4096).unwrap().len());
+        assert!(read_limited(decoder(), 4095).is_err());
+    }
+}
@@ -1,150 +1,226 @@
 use anyhow::{anyhow, Context, Result};
-use libflate::gzip::Decoder;
-use mailparse::*;
+use imap::types::Uid;
 use native_tls::TlsConnector;
-use serde_xml_rs::from_reader;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::prelude::*;
-use std::path::PathBuf;
-use zip::ZipArchive;
 
-use crate::config::Config;
+use crate::config::{Account, Config};
 use crate::db;
-use crate::report;
-use crate::report::serde_defs;
+use crate::enrichment::IpDatabases;
+use crate::extract;
 
-extern crate libflate;
+// Number of messages that are fetched from the server at once
+const BATCH_SIZE: usize = 50;
 
-struct Attachment {
-    content: Vec<u8>,
-    decompressed: Option<Vec<u8>>,
-    mimetype: String,
-    name: String,
-}
-
-const USABLE_MIMETYPES: [&str; 3] = [
-    "application/zip",
-    "application/gzip",
-    "application/octet-stream",
-];
+const INBOX: &str = "INBOX";
 
 #[derive(Debug)]
 pub struct ImapExtract {
-    server: String,
-    port: u16,
-    user: String,
-    password: String,
-    store_folder: String,
+    accounts: Vec<Account>,
+    imported: u32,
 }
 
 impl ImapExtract {
     pub fn new(config: &Config) -> Self {
         Self {
-            server: config.server.clone(),
-            port: config.port,
-            user: config.user.clone(),
-            password: config.password.clone(),
-            store_folder: config.store_folder.clone(),
+            accounts: config.accounts.clone(),
+            imported: 0,
         }
     }
 
-    pub fn fetch_reports(self, database: &db::DB, logbuf: &mut Vec<u8>) -> Result<()> {
+    pub fn imported(&self) -> u32 {
+        // Number of reports imported by the last fetch
+        self.imported
+    }
+
+    pub fn fetch_reports(
+        &mut self,
+        database: &dyn db::Storage,
+        ip_databases: &IpDatabases,
+        logbuf: &mut Vec<u8>,
+    ) -> Result<()> {
+        if self.accounts.is_empty() {
+            return Err(anyhow!("No IMAP account configured"));
+        }
+
+        let mut failed_accounts = Vec::new();
+        self.imported = 0;
+
+        for account in &self.accounts {
+            writeln!(logbuf, "==========")?;
+            writeln!(logbuf, "Account: {}", account.name)?;
+            if let Err(e) = Self::fetch_account_reports(
+                account,
+                database,
+                ip_databases,
+                logbuf,
+                &mut self.imported,
+            ) {
+                writeln!(logbuf, "Error: {:#}", e)?;
+                failed_accounts.push(account.name.clone());
+            }
+        }
+
+        if !failed_accounts.is_empty() {
+            return Err(anyhow!(
+                "Fetching failed for account(s): {}",
+                failed_accounts.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn fetch_account_reports(
+        account: &Account,
+        database: &dyn db::Storage,
+        ip_databases: &IpDatabases,
+        logbuf: &mut Vec<u8>,
+        imported: &mut u32,
+    ) -> Result<()> {
         writeln!(logbuf, "Starting to fetch reports!")?;
         let tls = TlsConnector::builder().build()?;
         let client = imap::connect(
-            (self.server.clone(), self.port as u16),
-            self.server.clone(),
+            (account.server.clone(), account.port),
+            account.server.clone(),
             &tls,
         )
         .context("Error connecting to server")?;
-        let mut imap_session = client.login(self.user, self.password).map_err(|e| e.0)?;
+        let mut imap_session = client
+            .login(&account.user, &account.password)
+            .map_err(|e| e.0)?;
 
-        match imap_session.select(format!("INBOX/{}", self.store_folder)) {
-            Ok(_o) => {}
-            Err(_e) => {
-                writeln!(logbuf, "Creating store folder: {}", self.store_folder)?;
-                imap_session
-                    .create(format!("INBOX/{}", self.store_folder))
-                    .context("Failed to create store folder")?
-            }
-        };
+        Self::ensure_folder(&mut imap_session, &account.store_folder, logbuf)
+            .context("Failed to create store folder")?;
+        Self::ensure_folder(&mut imap_session, &account.quarantine_folder, logbuf)
+            .context("Failed to create quarantine folder")?;
+
+        let capabilities = imap_session.capabilities()?;
+        let has_move = capabilities.has_str("MOVE");
+        let has_uidplus = capabilities.has_str("UIDPLUS");
+        drop(capabilities);
 
         let inbox = imap_session
-            .select("INBOX")
+            .select(INBOX)
             .context("Failed to select INBOX")?;
-        let message_count = inbox.exists;
 
-        if message_count == 0 {
+        if inbox.exists == 0 {
             writeln!(logbuf, "No messages found. Finished")?;
             return Ok(());
         }
 
-        let messages = imap_session.fetch("1:*", "RFC822")?;
-        let mut log_each_msg = message_count / 20;
-        if log_each_msg == 0 {
-            log_each_msg = 20;
+        // Only messages newer than the last run are fetched
+        let uid_validity = inbox.uid_validity.unwrap_or(0);
+        let last_uid = Self::last_uid(database, &account.name, uid_validity)?;
+
+        // "n:*" always matches the newest message, even if its UID is lower than n
+        let mut uids: Vec<Uid> = imap_session
+            .uid_search(format!("UID {}:*", last_uid + 1))?
+            .into_iter()
+            .filter(|uid| *uid > last_uid)
+            .collect();
+        uids.sort_unstable();
+
+        if uids.is_empty() {
+            writeln!(logbuf, "No new messages found. Finished")?;
+            return Ok(());
         }
+        writeln!(logbuf, "{} new message(s) found", uids.len())?;
 
-        let mut count = 0;
+        let mut processed = 0;
         let mut fetch_stats = HashMap::new();
-        for message in messages.iter() {
-            if count % log_each_msg == 0 {
+        let mut forensic_stats = HashMap::new();
+        let mut duplicate_stats = HashMap::new();
+        let mut quarantined = 0;
+        let mut moved_uids: Vec<Uid> = Vec::new();
+        let mut handled: HashSet<Uid> = HashSet::new();
+        for batch in uids.chunks(BATCH_SIZE) {
+            let messages = imap_session.uid_fetch(Self::uid_set(batch), "RFC822")?;
+            let mut targets: HashMap<&String, Vec<Uid>> = HashMap::new();
+
+            for message in messages.iter() {
+                let (uid, body) = match (message.uid, message.body()) {
+                    (Some(uid), Some(body)) => (uid, body),
+                    _ => continue,
+                };
+                let message_id = extract::message_id(body).unwrap_or_default();
+
+                let target_folder =
+                    match extract::import_message(database, ip_databases, body, &account.name) {
+                        Ok(extract::Imported::Aggregate(domain)) => {
+                            *fetch_stats.entry(domain).or_insert(0) += 1;
+                            *imported += 1;
+                            &account.store_folder
+                        }
+                        Ok(extract::Imported::Forensic(domain)) => {
+                            *forensic_stats.entry(domain).or_insert(0) += 1;
+                            *imported += 1;
+                            &account.store_folder
+                        }
+                        Ok(extract::Imported::Duplicate(domain, description)) => {
+                            writeln!(logbuf, "{}", description)?;
+                            *duplicate_stats.entry(domain).or_insert(0) += 1;
+                            &account.store_folder
+                        }
+                        Err(e) => {
+                            writeln!(logbuf, "{:#} Message: {}", e, message_id)?;
+                            let failed = db::FailedImport {
+                                id: 0,
+                                account: Some(account.name.clone()),
+                                message_id: Some(message_id),
+                                reason: format!("{:#}", e),
+                                created_at: chrono::Utc::now().timestamp(),
+                                raw: Some(body.to_vec()),
+                            };
+                            if let Err(e) = database.insert_failed_import(&failed) {
+                                writeln!(logbuf, "Could not record failed import: {}", e)?;
+                            }
+                            quarantined += 1;
+                            &account.quarantine_folder
+                        }
+                    };
+                targets.entry(target_folder).or_default().push(uid);
+                handled.insert(uid);
+            }
+            for uid in batch.iter().filter(|uid| !handled.contains(uid)) {
                 writeln!(
                     logbuf,
-                    "{:.0} % done",
-                    100.00 / message_count as f32 * message.message as f32
+                    "Message {} was not returned by the server, it is fetched again next time",
+                    uid
                 )?;
             }
-            count += 1;
-
-            if let Some(body) = message.body() {
-                let mail = parse_mail(body)?;
-                let message_id = mail.headers.get_first_value("Message-ID").unwrap();
-
-                let attachment = match Self::get_attachment(&mail) {
-                    Ok(attachment) => attachment,
-                    Err(e) => {
-                        writeln!(logbuf, "{} Message: {}", e, message_id)?;
-                        continue;
-                    }
-                };
 
-                let attachment = Self::decompress_attachment(attachment)?;
-
-                let parsed_report: serde_defs::Feedback = from_reader(std::io::Cursor::new(
-                    &attachment.decompressed.clone().unwrap(),
-                ))
-                .unwrap();
-                let report = report::Report::from_with_blob(
-                    parsed_report,
-                    Some(attachment.decompressed.unwrap()),
-                );
-
-                match database.insert_report(&report) {
-                    Ok(_o) => {
-                        let count = fetch_stats
-                            .entry(report.policy_domain.unwrap())
-                            .or_insert(0);
-                        *count += 1;
-                    }
-                    Err(e) => {
-                        writeln!(
-                            logbuf,
-                            "{} -- Report: '{}' - Organisation: '{}' ",
-                            e, report.report_id, report.org_name
-                        )?;
-                        if e.to_string() != "UNIQUE constraint failed: report.report_id" {
-                            continue;
-                        }
-                    }
-                };
-                // not every IMAP server supports MOVE
-                imap_session.copy(&count.to_string(), format!("INBOX/{}", self.store_folder))?;
-                imap_session.store(&count.to_string(), "+FLAGS (\\DELETED)")?;
+            for (folder, folder_uids) in targets {
+                let uid_set = Self::uid_set(&folder_uids);
+                let mailbox = format!("INBOX/{}", folder);
+                if has_move {
+                    imap_session.uid_mv(&uid_set, &mailbox)?;
+                } else {
+                    imap_session.uid_copy(&uid_set, &mailbox)?;
+                    imap_session.uid_store(&uid_set, "+FLAGS (\\Deleted)")?;
+                    moved_uids.extend(folder_uids);
+                }
+            }
+
+            processed += batch.len();
+            if let Some(uid) = Self::complete_up_to(&uids[..processed], &handled) {
+                database.set_imap_state(&account.name, INBOX, uid_validity, uid)?;
+            }
+            writeln!(
+                logbuf,
+                "{:.0} % done",
+                100.00 / uids.len() as f32 * processed as f32
+            )?;
+        }
+
+        if !moved_uids.is_empty() {
+            // without UIDPLUS, messages flagged as deleted by someone else are removed as well
+            if has_uidplus {
+                imap_session.uid_expunge(Self::uid_set(&moved_uids))?;
+            } else {
+                imap_session.expunge()?;
             }
         }
-        imap_session.expunge()?;
-        writeln!(logbuf, "100 % done")?;
         if !fetch_stats.is_empty() {
             writeln!(logbuf, "----------")?;
             writeln!(logbuf, "Imported:")?;
@@ -152,79 +228,133 @@ impl ImapExtract {
                 writeln!(logbuf, "{} -> {}", domain, val)?;
             }
         }
+        if !forensic_stats.is_empty() {
+            writeln!(logbuf, "----------")?;
+            writeln!(logbuf, "Imported forensic reports:")?;
+            for (domain, val) in forensic_stats.iter() {
+                writeln!(logbuf, "{} -> {}", domain, val)?;
+            }
+        }
+        if !duplicate_stats.is_empty() {
+            writeln!(logbuf, "----------")?;
+            writeln!(logbuf, "Skipped duplicates:")?;
+            for (domain, val) in duplicate_stats.iter() {
+                writeln!(logbuf, "{} -> {}", domain, val)?;
+            }
+        }
+        if quarantined > 0 {
+            writeln!(logbuf, "----------")?;
+            writeln!(
+                logbuf,
+                "Moved {} message(s) to {}",
+                quarantined, account.quarantine_folder
+            )?;
+        }
         imap_session.logout()?;
 
         Ok(())
     }
 
-    fn decompress_attachment(mut attachment: Attachment) -> Result<Attachment> {
-        // Decompresses the attachment, saves it in te Attachment struct and returns it
-        let content = std::io::Cursor::new(&attachment.content);
-        let mut decompressed: Vec<u8> = Vec::new();
-        // TODO: add function that determines type better, e.g. check file extension if mimetype is
-        // octect stream
-        if attachment.mimetype == *"application/zip" {
-            let mut zip = ZipArchive::new(content).unwrap();
-            let mut report = zip.by_index(0)?;
-            std::io::copy(&mut report, &mut decompressed)?;
-            attachment.name = String::from(report.name());
-        } else if attachment.mimetype == *"application/gzip"
-            || attachment.mimetype == *"application/octet-stream"
-        {
-            let mut report = Decoder::new(content).unwrap();
-            std::io::copy(&mut report, &mut decompressed)?;
-            let mut path = PathBuf::from(attachment.name.clone());
-            path = path.with_extension("");
-            attachment.name = String::from(path.to_str().unwrap());
-        }
-        attachment.decompressed = Some(decompressed);
+    // The last processed UID, 0 if the UIDs of the mailbox became invalid since the last run
+    fn last_uid(database: &dyn db::Storage, account: &str, uid_validity: u32) -> Result<Uid> {
+        Ok(match database.get_imap_state(account, INBOX)? {
+            Some((validity, last_uid)) if validity == uid_validity => last_uid,
+            _ => 0,
+        })
+    }
 
-        Ok(attachment)
+    // The highest UID up to which all messages were handled, the state must not advance past
+    // a message the server did not return
+    fn complete_up_to(uids: &[Uid], handled: &HashSet<Uid>) -> Option<Uid> {
+        uids.iter()
+            .take_while(|uid| handled.contains(uid))
+            .last()
+            .copied()
     }
 
-    fn get_attachment(mail: &ParsedMail) -> Result<Attachment> {
-        // Extracts the attachment from the mail
-
-        let mut content_type = mail.ctype.mimetype.clone();
-        let mut body: Vec<u8> = vec![];
-        let mut name = String::new();
-
-        if USABLE_MIMETYPES.contains(&content_type.as_str()) {
-            body = mail.get_body_raw().unwrap();
-            name = mail
-                .get_content_disposition()
-                .params
-                .get("filename")
-                .unwrap()
-                .clone();
-        } else if !mail.subparts.is_empty() {
-            for subpart in &mail.subparts {
-                content_type = subpart.ctype.mimetype.clone();
-                if USABLE_MIMETYPES.contains(&content_type.as_str()) {
-                    body = subpart.get_body_raw()?;
-                    name = subpart
-                        .get_content_disposition()
-                        .params
-                        .get("filename")
-                        .unwrap()
-                        .clone();
-                    break;
-                }
+    // Consecutive UIDs are joined to ranges, e.g. 1:3,7
+    fn uid_set(uids: &[Uid]) -> String {
+        let mut sorted = uids.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
+
+        let mut ranges: Vec<(Uid, Uid)> = Vec::new();
+        for uid in sorted {
+            match ranges.last_mut() {
+                Some((_start, end)) if end.checked_add(1) == Some(uid) => *end = uid,
+                _ => ranges.push((uid, uid)),
             }
         }
+        ranges
+            .iter()
+            .map(|(start, end)| {
+                if start == end {
+                    start.to_string()
+                } else {
+                    format!("{}:{}", start, end)
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(",")
+    }
 
-        if body.is_empty() {
-            return Err(anyhow!("No attachment found."));
-        }
-        if name.is_empty() {
-            return Err(anyhow!("No file name found."));
-        }
+    fn ensure_folder<T: Read + Write>(
+        imap_session: &mut imap::Session<T>,
+        folder: &str,
+        logbuf: &mut Vec<u8>,
+    ) -> Result<()> {
+        match imap_session.select(format!("INBOX/{}", folder)) {
+            Ok(_o) => {}
+            Err(_e) => {
+                writeln!(logbuf, "Creating folder: {}", folder)?;
+                imap_session.create(format!("INBOX/{}", folder))?
+            }
+        };
+        Ok(())
+    }
+}
 
-        Ok(Attachment {
-            content: body,
-            decompressed: None,
-            name,
-            mimetype: content_type,
-        })
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Storage;
+    use std::path::Path;
+
+    #[test]
+    fn test_uid_set() {
+        assert_eq!("", ImapExtract::uid_set(&[]));
+        assert_eq!("4", ImapExtract::uid_set(&[4]));
+        assert_eq!("1:3,7,9:10", ImapExtract::uid_set(&[10, 1, 2, 3, 7, 9, 2]));
+        assert_eq!(
+            "4294967294:4294967295",
+            ImapExtract::uid_set(&[u32::MAX, u32::MAX - 1])
+        );
+    }
+
+    #[test]
+    fn test_complete_up_to() {
+        let handled = HashSet::from([3, 4, 6]);
+        assert_eq!(
+            Some(4),
+            ImapExtract::complete_up_to(&[3, 4, 5, 6], &handled)
+        );
+        assert_eq!(Some(6), ImapExtract::complete_up_to(&[3, 4, 6], &handled));
+        assert_eq!(None, ImapExtract::complete_up_to(&[2, 3], &handled));
+    }
+
+    #[test]
+    fn test_last_uid() {
+        let database = db::sqlite::SqliteStorage::new(Path::new(":memory:")).unwrap();
+        assert_eq!(0, ImapExtract::last_uid(&database, "default", 7).unwrap());
+
+        database.set_imap_state("default", INBOX, 7, 42).unwrap();
+        assert_eq!(42, ImapExtract::last_uid(&database, "default", 7).unwrap());
+        assert_eq!(0, ImapExtract::last_uid(&database, "other", 7).unwrap());
+        // the mailbox was recreated, its UIDs start over
+        assert_eq!(0, ImapExtract::last_uid(&database, "default", 8).unwrap());
+
+        database.set_imap_state("default", INBOX, 8, 3).unwrap();
+        assert_eq!(3, ImapExtract::last_uid(&database, "default", 8).unwrap());
+        assert_eq!(0, ImapExtract::last_uid(&database, "default", 7).unwrap());
     }
 }
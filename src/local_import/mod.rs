@@ -0,0 +1,263 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::db;
+use crate::enrichment::IpDatabases;
+use crate::extract;
+
+const LOCAL_ACCOUNT: &str = "local";
+
+#[derive(Debug)]
+pub struct LocalImport {
+    path: PathBuf,
+}
+
+impl LocalImport {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn import_reports(
+        self,
+        database: &dyn db::Storage,
+        ip_databases: &IpDatabases,
+        logbuf: &mut Vec<u8>,
+    ) -> Result<()> {
+        writeln!(
+            logbuf,
+            "Starting to import reports from {}",
+            self.path.display()
+        )?;
+
+        let mut files = Vec::new();
+        Self::collect_files(&self.path, &mut files)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        files.sort();
+
+        if files.is_empty() {
+            writeln!(logbuf, "No files found. Finished")?;
+            return Ok(());
+        }
+
+        let mut import_stats = HashMap::new();
+        let mut forensic_stats = HashMap::new();
+        let mut duplicate_stats = HashMap::new();
+        for file in files {
+            let messages = match Self::messages_from_file(&file) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    writeln!(logbuf, "{:#} File: {}", e, file.display())?;
+                    continue;
+                }
+            };
+
+            for message in messages {
+                match extract::import_message(database, ip_databases, &message, LOCAL_ACCOUNT) {
+                    Ok(extract::Imported::Aggregate(domain)) => {
+                        *import_stats.entry(domain).or_insert(0) += 1;
+                    }
+                    Ok(extract::Imported::Forensic(domain)) => {
+                        *forensic_stats.entry(domain).or_insert(0) += 1;
+                    }
+                    Ok(extract::Imported::Duplicate(domain, description)) => {
+                        writeln!(logbuf, "{}", description)?;
+                        *duplicate_stats.entry(domain).or_insert(0) += 1;
+                    }
+                    Err(e) => {
+                        writeln!(logbuf, "{:#} File: {}", e, file.display())?;
+                        let failed = db::FailedImport {
+                            id: 0,
+                            account: Some(String::from(LOCAL_ACCOUNT)),
+                            message_id: extract::message_id(&message)
+                                .or_else(|| Some(file.display().to_string())),
+                            reason: format!("{:#}", e),
+                            created_at: chrono::Utc::now().timestamp(),
+                            raw: Some(message),
+                        };
+                        if let Err(e) = database.insert_failed_import(&failed) {
+                            writeln!(logbuf, "Could not record failed import: {}", e)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        writeln!(logbuf, "Finished")?;
+        if !import_stats.is_empty() {
+            writeln!(logbuf, "----------")?;
+            writeln!(logbuf, "Imported:")?;
+            for (domain, val) in import_stats.iter() {
+                writeln!(logbuf, "{} -> {}", domain, val)?;
+            }
+        }
+        if !forensic_stats.is_empty() {
+            writeln!(logbuf, "----------")?;
+            writeln!(logbuf, "Imported forensic reports:")?;
+            for (domain, val) in forensic_stats.iter() {
+                writeln!(logbuf, "{} -> {}", domain, val)?;
+            }
+        }
+        if !duplicate_stats.is_empty() {
+            writeln!(logbuf, "----------")?;
+            writeln!(logbuf, "Skipped duplicates:")?;
+            for (domain, val) in duplicate_stats.iter() {
+                writeln!(logbuf, "{} -> {}", domain, val)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+        // Walks the directory recursively, a single file is taken as is
+        if path.is_file() {
+            files.push(path.to_path_buf());
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(path)? {
+            let entry_path = entry?.path();
+            if entry_path.is_dir() {
+                // the tmp folder of a Maildir holds mails that are still being delivered
+                if entry_path.file_name() == Some(OsStr::new("tmp")) {
+                    continue;
+                }
+                Self::collect_files(&entry_path, files)?;
+            } else {
+                files.push(entry_path);
+            }
+        }
+        Ok(())
+    }
+
+    fn messages_from_file(path: &Path) -> Result<Vec<Vec<u8>>> {
+        // Splits the file into messages that can be handled by the import pipeline, raw report
+        // files are passed on as they are
+        let content = fs::read(path)?;
+        let name = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default()
+            .to_lowercase();
+
+        if extract::sniff::detect(&content, &name, "") != extract::sniff::ContentKind::Unknown {
+            return Ok(vec![content]);
+        }
+
+        if name.ends_with(".mbox") || content.starts_with(b"From ") {
+            return Ok(Self::split_mbox(&content));
+        }
+
+        // Maildir messages usually have no file extension
+        if name.ends_with(".eml") || Self::is_maildir_message(path) {
+            return Ok(vec![content]);
+        }
+
+        Err(anyhow!("Unknown file type, skipping."))
+    }
+
+    fn is_maildir_message(path: &Path) -> bool {
+        path.parent()
+            .and_then(Path::file_name)
+            .map(|dir| dir == OsStr::new("cur") || dir == OsStr::new("new"))
+            .unwrap_or(false)
+    }
+
+    fn split_mbox(content: &[u8]) -> Vec<Vec<u8>> {
+        // Every mail in a mbox starts with a "From " line, quoted lines within mails start
+        // with ">From "
+        let mut mails = Vec::new();
+        let mut current: Vec<u8> = Vec::new();
+        let mut in_mail = false;
+
+        for line in content.split_inclusive(|c| *c == b'\n') {
+            if line.starts_with(b"From ") {
+                if in_mail && !current.is_empty() {
+                    mails.push(std::mem::take(&mut current));
+                }
+                in_mail = true;
+                continue;
+            }
+            if in_mail {
+                current.extend_from_slice(line);
+            }
+        }
+        if !current.is_empty() {
+            mails.push(current);
+        }
+
+        mails
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Storage;
+    use crate::report::fixture::ReportFixture;
+    use libflate::gzip::Encoder;
+
+    fn report_xml(report_id: &str) -> String {
+        ReportFixture {
+            report_id,
+            ..Default::default()
+        }
+        .xml()
+    }
+
+    #[test]
+    fn test_split_mbox() {
+        let mbox = b"From a@example.com Mon Feb  1 00:00:00 2021\nSubject: one\n\nbody\n\
+From b@example.com Mon Feb  1 00:00:00 2021\nSubject: two\n\n>From quoted\n";
+        let mails = LocalImport::split_mbox(mbox);
+        assert_eq!(2, mails.len());
+        assert_eq!(b"Subject: one\n\nbody\n".to_vec(), mails[0]);
+        assert_eq!(b"Subject: two\n\n>From quoted\n".to_vec(), mails[1]);
+    }
+
+    #[test]
+    fn test_import_reports() {
+        let dir = std::env::temp_dir().join(format!("dmarc_import_{}", std::process::id()));
+        fs::create_dir_all(dir.join("archive")).unwrap();
+        fs::write(dir.join("plain.xml"), report_xml("1")).unwrap();
+
+        let mut encoder = Encoder::new(Vec::new()).unwrap();
+        encoder.write_all(report_xml("2").as_bytes()).unwrap();
+        fs::write(
+            dir.join("archive").join("report.xml.gz"),
+            encoder.finish().into_result().unwrap(),
+        )
+        .unwrap();
+        fs::write(dir.join("notes.txt"), "not a report").unwrap();
+
+        let database = db::sqlite::SqliteStorage::new(Path::new(":memory:")).unwrap();
+        let mut logbuf = Vec::new();
+        LocalImport::new(&dir)
+            .import_reports(&database, &IpDatabases::default(), &mut logbuf)
+            .unwrap();
+
+        // Importing the same files again only skips them
+        let mut second_logbuf = Vec::new();
+        LocalImport::new(&dir)
+            .import_reports(&database, &IpDatabases::default(), &mut second_logbuf)
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let second_log = String::from_utf8(second_logbuf).unwrap();
+        assert!(second_log.contains("Skipped duplicates:\nexample.com -> 2"));
+
+        let reports = database
+            .get_all_reports_for_domain(String::from("example.com"), None)
+            .unwrap();
+        assert_eq!(2, reports.len());
+        assert_eq!(Some(String::from("local")), reports[0].account);
+        assert!(String::from_utf8(logbuf)
+            .unwrap()
+            .contains("Unknown file type, skipping."));
+    }
+}
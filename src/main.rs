@@ -1,23 +1,48 @@
+// The FromForm derive of this Rocket version allows the since removed private_in_public lint
+#![allow(renamed_and_removed_lints)]
+
 #[macro_use]
 extern crate rocket;
 #[macro_use]
 extern crate serde_derive;
 
-use chrono::{Duration, Utc};
+use chrono::{Duration, NaiveDate, Utc};
+use config::arguments::Command;
+use rocket::form::Form;
 use rocket::fs::FileServer;
+use rocket::http::{ContentType, CookieJar, Header, RawStr, Status};
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
 use rocket::serde::{json::Json, Serialize};
 use rocket::{Request, State};
 use rocket_dyn_templates::Template;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use structopt::StructOpt;
 
+mod alerts;
+mod analysis;
+pub mod api;
+mod auth;
+mod cli;
 mod config;
 mod db;
+mod enrichment;
+mod extract;
 mod imap_extract;
+mod local_import;
 mod report;
+mod scheduler;
+mod senders;
+
+type DbConn = Arc<dyn db::Storage>;
+type IpDatabases = Arc<enrichment::IpDatabases>;
 
-type DbConn = db::DB;
+const REPORTS_PER_PAGE: u32 = 50;
+const ALERTS_SHOWN: u32 = 200;
 type BasicStats = HashMap<String, db::BasicStats>;
 type PolicyEvStats = HashMap<String, HashMap<String, db::PolicyEvaluatedStats>>;
+type CountryStats = HashMap<String, Vec<db::CountryStats>>;
 
 #[derive(Serialize)]
 struct FetchTask {
@@ -28,24 +53,229 @@ struct FetchTask {
 #[derive(Serialize)]
 struct TemplateFetchContext {
     title: String,
+    user: auth::User,
 }
 
 #[derive(Serialize)]
 struct TemplateMainContext {
     title: String,
+    user: auth::User,
     now: String,
     now30_ago: String,
     domains: Vec<String>,
     basic_stats: BasicStats,
     basic_stats_last_30: BasicStats,
     policy_ev_stats_last_30: PolicyEvStats,
+    country_stats_last_30: CountryStats,
 }
 
 #[derive(Serialize)]
 struct TemplateAllReportsContext {
     title: String,
+    user: auth::User,
     domain: String,
+    account: Option<String>,
+    accounts: Vec<String>,
     reports: Vec<report::Report>,
+    query: ReportQuery,
+    // The filters as query string, ending with '&' if there are any
+    filter_query: String,
+    sort: String,
+    order: String,
+    sort_links: HashMap<&'static str, SortLink>,
+    // Cached host names and autonomous systems of the source IPs
+    ip_info: HashMap<String, db::IpInfo>,
+    total: u32,
+    page: u32,
+    pages: u32,
+}
+
+#[derive(Serialize)]
+struct SortLink {
+    href: String,
+    // Shows the current sort order
+    arrow: &'static str,
+}
+
+// Filters, sorting and page of the report list
+#[derive(Debug, Default, FromForm, Serialize)]
+struct ReportQuery {
+    account: Option<String>,
+    org: Option<String>,
+    ip: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    result: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+    page: Option<u32>,
+}
+
+impl ReportQuery {
+    fn filter(&self, domain: String) -> db::ReportFilter {
+        let (date_from, date_to) = day_range(&self.from, &self.to);
+        db::ReportFilter {
+            domain,
+            account: non_empty(&self.account),
+            org_name: non_empty(&self.org),
+            source_ip: non_empty(&self.ip),
+            date_from,
+            date_to,
+            dmarc_result: non_empty(&self.result)
+                .filter(|result| result == db::DMARC_PASS || result == db::DMARC_FAIL),
+            sort: self
+                .sort
+                .as_deref()
+                .and_then(db::ReportSort::from_name)
+                .unwrap_or_default(),
+            ascending: self.order.as_deref() == Some("asc"),
+        }
+    }
+
+    fn filter_query(&self) -> String {
+        let filters = [
+            ("account", &self.account),
+            ("org", &self.org),
+            ("ip", &self.ip),
+            ("from", &self.from),
+            ("to", &self.to),
+            ("result", &self.result),
+        ];
+        filters
+            .iter()
+            .filter_map(|(name, value)| {
+                non_empty(value)
+                    .map(|value| format!("{}={}&", name, RawStr::new(&value).percent_encode()))
+            })
+            .collect()
+    }
+}
+
+fn non_empty(value: &Option<String>) -> Option<String> {
+    value
+        .as_ref()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+// Days are given as YYYY-MM-DD, returns unix timestamps with the end day included
+fn day_range(from: &Option<String>, to: &Option<String>) -> (Option<i64>, Option<i64>) {
+    let day = |value: &Option<String>| {
+        non_empty(value).and_then(|day| NaiveDate::parse_from_str(&day, "%Y-%m-%d").ok())
+    };
+    (
+        day(from).map(|day| day.and_hms(0, 0, 0).timestamp()),
+        // A date without a following day is ignored like an invalid one
+        day(to)
+            .and_then(|day| day.checked_add_signed(Duration::days(1)))
+            .map(|day| day.and_hms(0, 0, 0).timestamp()),
+    )
+}
+
+// Domain, time range and grouping of the senders view
+#[derive(Debug, Default, FromForm, Serialize)]
+struct SenderQuery {
+    domain: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    // "network" groups the IPs by network
+    group: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TemplateSendersContext {
+    title: String,
+    user: auth::User,
+    domains: Vec<String>,
+    query: SenderQuery,
+    by_network: bool,
+    senders: Vec<senders::Sender>,
+    ip_info: HashMap<String, db::IpInfo>,
+}
+
+#[derive(Serialize)]
+struct TemplateReportContext {
+    title: String,
+    user: auth::User,
+    report: report::Report,
+    ip_info: HashMap<String, db::IpInfo>,
+}
+
+#[derive(Serialize)]
+struct TemplateForensicContext {
+    title: String,
+    user: auth::User,
+    domain: Option<String>,
+    domains: Vec<String>,
+    reports: Vec<report::forensic::ForensicReport>,
+}
+
+#[derive(Serialize)]
+struct TemplateFailedContext {
+    title: String,
+    user: auth::User,
+    message: Option<(String, String)>,
+    failed: Vec<db::FailedImport>,
+}
+
+#[derive(Serialize)]
+struct TemplateAlertsContext {
+    title: String,
+    user: auth::User,
+    message: Option<(String, String)>,
+    open: Vec<db::Alert>,
+    acknowledged: Vec<db::Alert>,
+}
+
+#[derive(Serialize)]
+struct TemplateStatusContext {
+    title: String,
+    user: auth::User,
+    fetch_interval: u64,
+    runs: Vec<db::FetchRun>,
+}
+
+#[derive(Serialize)]
+struct TemplateLoginContext {
+    title: String,
+    next: String,
+    error: Option<String>,
+    csrf_token: String,
+}
+
+#[derive(FromForm)]
+struct Login {
+    name: String,
+    password: String,
+    next: String,
+    csrf_token: String,
+}
+
+#[derive(Responder)]
+enum LoginResult {
+    Success(Redirect),
+    #[response(status = 401)]
+    Failure(Template),
+}
+
+#[derive(Responder)]
+struct Download {
+    content: Vec<u8>,
+    content_type: ContentType,
+    disposition: Header<'static>,
+}
+
+impl Download {
+    fn new(content: Vec<u8>, content_type: ContentType, filename: &str) -> Self {
+        Self {
+            content,
+            content_type,
+            disposition: Header::new(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        }
+    }
 }
 
 #[catch(404)]
@@ -55,80 +285,721 @@ fn not_found(_req: &Request) -> Template {
     Template::render("error/404", &map)
 }
 
-#[get("/")]
-fn index(db_conn: &State<DbConn>) -> Template {
-    let domains = db::DB::get_domains(db_conn).expect("get domains");
-    let basic_stats = db::DB::get_basic_stats(db_conn, 12000).expect("get basic stats");
-    let basic_stats_last_30 =
-        db::DB::get_basic_stats(db_conn, 30).expect("get basic last 30 stats");
-    let policy_ev_stats_last_30 =
-        db::DB::get_policy_evaluated_stats(db_conn, 30).expect("get basic last 30 stats");
+#[catch(403)]
+fn forbidden(_req: &Request) -> Template {
+    let mut map = std::collections::HashMap::new();
+    map.insert("title", "403 - forbidden");
+    Template::render("error/403", &map)
+}
 
-    let now = Utc::now();
-    let now30_ago = now - Duration::days(30);
+#[catch(401)]
+fn unauthorized(req: &Request) -> Template {
+    // Pages that need a login show the login form instead
+    let next = match req.method() {
+        rocket::http::Method::Get => req.uri().to_string(),
+        _ => String::from("/"),
+    };
+    login_page(next, None, auth::csrf_token(req))
+}
 
+fn login_page(next: String, error: Option<String>, csrf_token: String) -> Template {
     Template::render(
-        "index",
-        &TemplateMainContext {
-            title: String::from("Start"),
-            now: now.format("%Y-%m-%d").to_string(),
-            now30_ago: now30_ago.format("%Y-%m-%d").to_string(),
-            domains,
-            basic_stats,
-            basic_stats_last_30,
-            policy_ev_stats_last_30,
+        "login",
+        &TemplateLoginContext {
+            title: String::from("Login"),
+            next,
+            error,
+            csrf_token,
         },
     )
 }
 
+// The storage backends block, so routes call them on the blocking threads of the runtime. A panic
+// of f, e.g. a failed expect, is answered with 500.
+pub async fn with_db<T, F>(db_conn: &DbConn, f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce(&DbConn) -> T + Send + 'static,
+{
+    let database = db_conn.clone();
+    rocket::tokio::task::spawn_blocking(move || f(&database))
+        .await
+        .expect("database task")
+}
+
+#[get("/login?<next>")]
+fn login(next: Option<String>, csrf: auth::LoginCsrf) -> Template {
+    login_page(next.unwrap_or_else(|| String::from("/")), None, csrf.0)
+}
+
+#[post("/login", data = "<form>")]
+async fn login_submit(
+    form: Form<Login>,
+    csrf: auth::LoginCsrf,
+    cookies: &CookieJar<'_>,
+    db_conn: &State<DbConn>,
+    config: &State<config::Config>,
+) -> Result<LoginResult, Status> {
+    // Otherwise another site could log the browser into an account of its choice
+    csrf.check_csrf(&form.csrf_token)?;
+    let name = form.name.clone();
+    let password = form.password.clone();
+    let user = with_db(db_conn, move |db_conn| {
+        let user = db_conn.get_user(&name).map_err(|e| {
+            log::error!("Could not get user: {:#}", e);
+            Status::InternalServerError
+        })?;
+        Ok::<_, Status>(auth::check_login(user, &password))
+    })
+    .await?;
+    let user = match user {
+        Some(user) => user,
+        None => {
+            return Ok(LoginResult::Failure(login_page(
+                form.next.clone(),
+                Some(String::from("Wrong user name or password")),
+                csrf.0,
+            )))
+        }
+    };
+
+    // Expired sessions are removed along the way
+    let token = auth::new_token();
+    let token_hash = auth::token_hash(&token);
+    let expires_at = Utc::now() + Duration::hours(config.auth.session_hours as i64);
+    with_db(db_conn, move |db_conn| {
+        db_conn.insert_session(&token_hash, user.id, expires_at.timestamp())
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Could not insert session: {:#}", e);
+        Status::InternalServerError
+    })?;
+    cookies.add(auth::session_cookie(token, &config.auth));
+
+    // Only redirect within this site
+    if form.next.starts_with('/') && !form.next.starts_with("//") && !form.next.starts_with("/\\") {
+        Ok(LoginResult::Success(Redirect::to(form.next.clone())))
+    } else {
+        Ok(LoginResult::Success(Redirect::to(uri!(index))))
+    }
+}
+
+#[post("/logout", data = "<form>")]
+async fn logout(
+    user: auth::User,
+    form: Form<auth::CsrfForm>,
+    cookies: &CookieJar<'_>,
+    db_conn: &State<DbConn>,
+    config: &State<config::Config>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form)?;
+    if let Some(cookie) = cookies.get(auth::SESSION_COOKIE) {
+        let token_hash = auth::token_hash(cookie.value());
+        with_db(db_conn, move |db_conn| db_conn.delete_session(&token_hash))
+            .await
+            .expect("delete session");
+    }
+    cookies.remove(auth::session_cookie(String::new(), &config.auth));
+    Ok(Redirect::to(uri!(login(None::<String>))))
+}
+
+#[get("/")]
+async fn index(user: auth::User, db_conn: &State<DbConn>) -> Template {
+    with_db(db_conn, move |db_conn| {
+        let domains = user.visible_domains(db_conn.get_domains().expect("get domains"));
+        let mut basic_stats = db_conn.get_basic_stats(12000).expect("get basic stats");
+        let mut basic_stats_last_30 = db_conn
+            .get_basic_stats(30)
+            .expect("get basic last 30 stats");
+        let mut policy_ev_stats_last_30 = db_conn
+            .get_policy_evaluated_stats(30)
+            .expect("get basic last 30 stats");
+        user.retain_visible(&mut basic_stats);
+        user.retain_visible(&mut basic_stats_last_30);
+        let mut country_stats_last_30 = db_conn
+            .get_country_stats(30)
+            .expect("get country last 30 stats");
+        user.retain_visible(&mut policy_ev_stats_last_30);
+        user.retain_visible(&mut country_stats_last_30);
+        // Without a country database all countries are unknown
+        country_stats_last_30.retain(|_domain, stats| stats.iter().any(|s| s.country.is_some()));
+
+        let now = Utc::now();
+        let now30_ago = now - Duration::days(30);
+
+        Template::render(
+            "index",
+            &TemplateMainContext {
+                title: String::from("Start"),
+                user,
+                now: now.format("%Y-%m-%d").to_string(),
+                now30_ago: now30_ago.format("%Y-%m-%d").to_string(),
+                domains,
+                basic_stats,
+                basic_stats_last_30,
+                policy_ev_stats_last_30,
+                country_stats_last_30,
+            },
+        )
+    })
+    .await
+}
+
 #[get("/fetch")]
-fn fetch() -> Template {
+fn fetch(admin: auth::Admin) -> Template {
     Template::render(
         "fetched",
         &TemplateFetchContext {
             title: String::from("Fetch"),
+            user: admin.0,
         },
     )
 }
 
-#[get("/fetchdata")]
-fn fetchdata(db_conn: &State<DbConn>, config: &State<config::Config>) -> Json<FetchTask> {
-    let imap_extract = imap_extract::ImapExtract::new(config);
-    let mut error = String::new();
-    let mut logbuf = Vec::new();
+#[post("/fetchdata", data = "<form>")]
+async fn fetchdata(
+    admin: auth::Admin,
+    form: Form<auth::CsrfForm>,
+    db_conn: &State<DbConn>,
+    ip_databases: &State<IpDatabases>,
+    config: &State<config::Config>,
+) -> Result<Json<FetchTask>, Status> {
+    admin.0.check_csrf(&form)?;
+    let database = db_conn.inner().clone();
+    let ip_databases = ip_databases.inner().clone();
+    let config = config.inner().clone();
 
-    match imap_extract.fetch_reports(db_conn, &mut logbuf) {
-        Ok(_o) => {}
-        Err(e) => error = format!("{:#}", e),
-    };
-    Json(FetchTask {
-        log: String::from_utf8(logbuf).expect("get fetch log"),
-        error,
+    let run = rocket::tokio::task::spawn_blocking(move || {
+        scheduler::run_fetch(
+            &config,
+            database.as_ref(),
+            &ip_databases,
+            scheduler::TRIGGER_MANUAL,
+        )
     })
+    .await
+    .map_err(|_e| Status::InternalServerError)?;
+    Ok(Json(FetchTask {
+        log: run.log,
+        error: run.error.unwrap_or_default(),
+    }))
 }
 
-#[get("/all_reports/<domain>")]
-fn all_reports(domain: String, db_conn: &State<DbConn>) -> Template {
-    Template::render(
-        "all_reports",
-        &TemplateAllReportsContext {
-            title: format!("Report list: {}", domain),
-            domain: domain.clone(),
-            reports: db::DB::get_all_reports_for_domain(db_conn, domain)
-                .expect("get all reports for domain"),
+#[get("/status")]
+async fn status(
+    admin: auth::Admin,
+    db_conn: &State<DbConn>,
+    config: &State<config::Config>,
+) -> Template {
+    let fetch_interval = config.fetch_interval;
+    with_db(db_conn, move |db_conn| {
+        Template::render(
+            "status",
+            &TemplateStatusContext {
+                title: String::from("Status"),
+                user: admin.0,
+                fetch_interval,
+                runs: db_conn.get_fetch_runs(50).expect("get fetch runs"),
+            },
+        )
+    })
+    .await
+}
+
+#[get("/all_reports/<domain>?<query..>")]
+async fn all_reports(
+    user: auth::User,
+    domain: String,
+    query: ReportQuery,
+    db_conn: &State<DbConn>,
+) -> Result<Template, Status> {
+    with_db(db_conn, move |db_conn| {
+        user.check_domain(&domain)?;
+        let filter = query.filter(domain.clone());
+        let total = db_conn.count_reports(&filter).expect("count reports");
+        let pages = total.div_ceil(REPORTS_PER_PAGE).max(1);
+        let page = query.page.unwrap_or(1).clamp(1, pages);
+        let reports = db_conn
+            .get_reports(
+                &filter,
+                (page - 1) * REPORTS_PER_PAGE,
+                Some(REPORTS_PER_PAGE),
+            )
+            .expect("get reports");
+        let ip_info = source_ip_info(&reports, db_conn);
+        let accounts = db_conn.get_accounts(&domain).expect("get accounts");
+
+        // Clicking the current sort column again reverses the order
+        let filter_query = query.filter_query();
+        let mut sort_links = HashMap::new();
+        for sort in db::ReportSort::ALL {
+            let current = *sort == filter.sort;
+            let ascending = current && !filter.ascending;
+            let arrow = match (current, filter.ascending) {
+                (false, _) => "",
+                (true, true) => "▲",
+                (true, false) => "▼",
+            };
+            sort_links.insert(
+                sort.name(),
+                SortLink {
+                    href: format!(
+                        "?{}sort={}&order={}",
+                        filter_query,
+                        sort.name(),
+                        if ascending { "asc" } else { "desc" }
+                    ),
+                    arrow,
+                },
+            );
+        }
+
+        Ok(Template::render(
+            "all_reports",
+            &TemplateAllReportsContext {
+                title: format!("Report list: {}", domain),
+                user,
+                domain,
+                account: filter.account.clone(),
+                accounts,
+                reports,
+                sort: String::from(filter.sort.name()),
+                order: String::from(if filter.ascending { "asc" } else { "desc" }),
+                query,
+                filter_query,
+                sort_links,
+                ip_info,
+                total,
+                page,
+                pages,
+            },
+        ))
+    })
+    .await
+}
+
+fn source_ip_info(reports: &[report::Report], db_conn: &DbConn) -> HashMap<String, db::IpInfo> {
+    let ips: Vec<String> = reports
+        .iter()
+        .flat_map(|report| report.records.iter())
+        .map(|record| record.source_ip.clone())
+        .collect::<HashSet<String>>()
+        .into_iter()
+        .collect();
+    db_conn.get_ip_info(&ips).expect("get ip info")
+}
+
+// Loads a report with its raw XML if the user may see its domain
+fn visible_report(user: &auth::User, id: i64, db_conn: &DbConn) -> Result<report::Report, Status> {
+    let report = db_conn
+        .get_report(id)
+        .expect("get report")
+        .ok_or(Status::NotFound)?;
+    user.check_domain(report.policy_domain.as_deref().unwrap_or_default())?;
+    Ok(report)
+}
+
+#[get("/report/<id>")]
+async fn single_report(
+    user: auth::User,
+    id: i64,
+    db_conn: &State<DbConn>,
+) -> Result<Template, Status> {
+    with_db(db_conn, move |db_conn| {
+        let mut report = visible_report(&user, id, db_conn)?;
+        report.blob = None;
+        let ip_info = source_ip_info(std::slice::from_ref(&report), db_conn);
+
+        Ok(Template::render(
+            "report",
+            &TemplateReportContext {
+                title: format!("Report {} from {}", report.report_id, report.org_name),
+                user,
+                report,
+                ip_info,
+            },
+        ))
+    })
+    .await
+}
+
+#[get("/report/<id>/raw")]
+async fn single_report_raw(
+    user: auth::User,
+    id: i64,
+    db_conn: &State<DbConn>,
+) -> Result<Download, Status> {
+    with_db(db_conn, move |db_conn| {
+        let report = visible_report(&user, id, db_conn)?;
+        // The file name follows the naming of report attachments from RFC 7489
+        let filename: String = format!(
+            "{}!{}!{}!{}.xml",
+            report.org_name,
+            report.policy_domain.as_deref().unwrap_or_default(),
+            report.date_begin,
+            report.date_end
+        )
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "!.-_".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+        Ok(Download::new(
+            report.blob.unwrap_or_default(),
+            ContentType::XML,
+            &filename,
+        ))
+    })
+    .await
+}
+
+#[get("/senders?<query..>")]
+async fn all_senders(
+    user: auth::User,
+    query: SenderQuery,
+    db_conn: &State<DbConn>,
+    providers: &State<senders::Providers>,
+) -> Result<Template, Status> {
+    let domains = with_db(db_conn, |db_conn| {
+        db_conn.get_domains().expect("get domains")
+    })
+    .await;
+    let domains = user.visible_domains(domains);
+    let selected = match non_empty(&query.domain) {
+        Some(domain) => {
+            user.check_domain(&domain)?;
+            vec![domain]
+        }
+        None => domains.clone(),
+    };
+    let (date_from, date_to) = day_range(&query.from, &query.to);
+    let rows = with_db(db_conn, move |db_conn| {
+        let mut rows = Vec::new();
+        for domain in &selected {
+            rows.extend(
+                db_conn
+                    .get_senders(domain, date_from, date_to)
+                    .expect("get senders"),
+            );
+        }
+        rows
+    })
+    .await;
+    let by_network = query.group.as_deref() == Some("network");
+    let senders = senders::aggregate(rows, by_network, providers);
+    // Networks have no information of their own
+    let ip_info = if by_network {
+        HashMap::new()
+    } else {
+        let ips: Vec<String> = senders.iter().map(|s| s.source.clone()).collect();
+        with_db(db_conn, move |db_conn| {
+            db_conn.get_ip_info(&ips).expect("get ip info")
+        })
+        .await
+    };
+
+    Ok(Template::render(
+        "senders",
+        &TemplateSendersContext {
+            title: String::from("Senders"),
+            user,
+            domains,
+            senders,
+            ip_info,
+            query,
+            by_network,
         },
-    )
+    ))
+}
+
+#[get("/forensic?<domain>")]
+async fn forensic(
+    user: auth::User,
+    domain: Option<String>,
+    db_conn: &State<DbConn>,
+) -> Result<Template, Status> {
+    with_db(db_conn, move |db_conn| {
+        let title = match &domain {
+            Some(domain) => {
+                user.check_domain(domain)?;
+                format!("Forensic reports: {}", domain)
+            }
+            None => String::from("Forensic reports"),
+        };
+        let mut reports = db_conn
+            .get_forensic_reports(domain.clone())
+            .expect("get forensic reports");
+        reports.retain(|report| {
+            report
+                .reported_domain
+                .as_deref()
+                .map(|d| user.may_see(d))
+                .unwrap_or_else(|| user.is_admin())
+        });
+
+        Ok(Template::render(
+            "forensic",
+            &TemplateForensicContext {
+                title,
+                domain,
+                domains: user.visible_domains(db_conn.get_domains().expect("get domains")),
+                reports,
+                user,
+            },
+        ))
+    })
+    .await
+}
+
+#[get("/failed")]
+async fn failed(
+    admin: auth::Admin,
+    flash: Option<FlashMessage<'_>>,
+    db_conn: &State<DbConn>,
+) -> Template {
+    let message = flash.map(|f| (f.kind().to_string(), f.message().to_string()));
+    with_db(db_conn, move |db_conn| {
+        Template::render(
+            "failed",
+            &TemplateFailedContext {
+                title: String::from("Failed imports"),
+                user: admin.0,
+                message,
+                failed: db_conn.get_failed_imports().expect("get failed imports"),
+            },
+        )
+    })
+    .await
+}
+
+#[get("/failed/<id>/raw")]
+async fn failed_raw(
+    _admin: auth::Admin,
+    id: i64,
+    db_conn: &State<DbConn>,
+) -> Result<Download, Status> {
+    with_db(db_conn, move |db_conn| {
+        let failed = db_conn
+            .get_failed_import(id)
+            .expect("get failed import")
+            .ok_or(Status::NotFound)?;
+        Ok(Download::new(
+            failed.raw.unwrap_or_default(),
+            ContentType::new("message", "rfc822"),
+            &format!("failed_import_{}.eml", id),
+        ))
+    })
+    .await
+}
+
+#[post("/failed/<id>/retry", data = "<form>")]
+async fn failed_retry(
+    admin: auth::Admin,
+    id: i64,
+    form: Form<auth::CsrfForm>,
+    db_conn: &State<DbConn>,
+    ip_databases: &State<IpDatabases>,
+    config: &State<config::Config>,
+) -> Result<Flash<Redirect>, Status> {
+    admin.0.check_csrf(&form)?;
+    let failed = with_db(db_conn, move |db_conn| {
+        db_conn.get_failed_import(id).expect("get failed import")
+    })
+    .await
+    .ok_or(Status::NotFound)?;
+    let account = failed.account.unwrap_or_default();
+    let database = db_conn.inner().clone();
+    let ip_databases = ip_databases.inner().clone();
+    let config = config.inner().clone();
+
+    // Delivering alerts may take a while
+    let result = rocket::tokio::task::spawn_blocking(move || {
+        let started_at = Utc::now().timestamp();
+        extract::import_message(
+            database.as_ref(),
+            &ip_databases,
+            &failed.raw.unwrap_or_default(),
+            &account,
+        )?;
+        database.delete_failed_import(id)?;
+
+        let mut logbuf = Vec::new();
+        if let Err(e) = alerts::evaluate(&config, database.as_ref(), started_at, &mut logbuf) {
+            log::error!("Evaluating alerts failed: {:#}", e);
+        }
+        for line in String::from_utf8_lossy(&logbuf).lines() {
+            log::info!("{}", line);
+        }
+        Ok::<(), anyhow::Error>(())
+    })
+    .await
+    .map_err(|_e| Status::InternalServerError)?;
+
+    let redirect = Redirect::to(uri!(failed));
+    Ok(match result {
+        Ok(_o) => Flash::success(redirect, format!("Imported message {}", id)),
+        Err(e) => Flash::error(
+            redirect,
+            format!("Import of message {} failed again: {:#}", id, e),
+        ),
+    })
+}
+
+#[post("/failed/<id>/delete", data = "<form>")]
+async fn failed_delete(
+    admin: auth::Admin,
+    id: i64,
+    form: Form<auth::CsrfForm>,
+    db_conn: &State<DbConn>,
+) -> Result<Flash<Redirect>, Status> {
+    admin.0.check_csrf(&form)?;
+    with_db(db_conn, move |db_conn| {
+        Ok(match db_conn.delete_failed_import(id) {
+            Ok(_o) => Flash::success(
+                Redirect::to(uri!(failed)),
+                format!("Removed message {}", id),
+            ),
+            Err(e) => Flash::error(Redirect::to(uri!(failed)), e.to_string()),
+        })
+    })
+    .await
+}
+
+#[get("/alerts")]
+async fn all_alerts(
+    user: auth::User,
+    flash: Option<FlashMessage<'_>>,
+    db_conn: &State<DbConn>,
+) -> Template {
+    let message = flash.map(|f| (f.kind().to_string(), f.message().to_string()));
+    with_db(db_conn, move |db_conn| {
+        let (acknowledged, open) = db_conn
+            .get_alerts(ALERTS_SHOWN)
+            .expect("get alerts")
+            .into_iter()
+            .filter(|alert| user.may_see(&alert.domain))
+            .partition(|alert| alert.acknowledged_at.is_some());
+
+        Template::render(
+            "alerts",
+            &TemplateAlertsContext {
+                title: String::from("Alerts"),
+                user,
+                message,
+                open,
+                acknowledged,
+            },
+        )
+    })
+    .await
+}
+
+#[post("/alerts/<id>/acknowledge", data = "<form>")]
+async fn alert_acknowledge(
+    user: auth::User,
+    id: i64,
+    form: Form<auth::CsrfForm>,
+    db_conn: &State<DbConn>,
+) -> Result<Flash<Redirect>, Status> {
+    user.check_csrf(&form)?;
+    with_db(db_conn, move |db_conn| {
+        let alert = db_conn
+            .get_alert(id)
+            .expect("get alert")
+            .ok_or(Status::NotFound)?;
+        user.check_domain(&alert.domain)?;
+
+        let redirect = Redirect::to(uri!(all_alerts));
+        Ok(
+            match db_conn.acknowledge_alert(id, &user.name, Utc::now().timestamp()) {
+                Ok(true) => Flash::success(redirect, format!("Acknowledged alert {}", id)),
+                Ok(false) => {
+                    Flash::error(redirect, format!("Alert {} was acknowledged already", id))
+                }
+                Err(e) => Flash::error(redirect, e.to_string()),
+            },
+        )
+    })
+    .await
 }
 
-#[launch]
-fn rocket() -> _ {
-    let config = config::Config::new();
-    let conn = db::DB::new(&config.db_path).expect("get db conn");
+fn rocket(config: config::Config, conn: DbConn) -> rocket::Rocket<rocket::Build> {
+    let providers = senders::load_providers(&config).expect("load provider networks");
+    let ip_databases: IpDatabases =
+        Arc::new(enrichment::IpDatabases::open(&config).expect("open IP databases"));
     rocket::build()
         .mount("/", FileServer::from("static"))
-        .mount("/", routes![index, fetch, fetchdata, all_reports])
-        .register("/", catchers![not_found])
+        .mount(
+            "/",
+            routes![
+                index,
+                fetch,
+                fetchdata,
+                all_reports,
+                single_report,
+                single_report_raw,
+                all_senders,
+                forensic,
+                failed,
+                failed_raw,
+                failed_retry,
+                failed_delete,
+                all_alerts,
+                alert_acknowledge,
+                status,
+                login,
+                login_submit,
+                logout
+            ],
+        )
+        .mount("/api/v1", api::routes())
+        .register("/", catchers![not_found, unauthorized, forbidden])
+        .register("/api", api::catchers())
+        .attach(scheduler::fairing(
+            config.clone(),
+            conn.clone(),
+            ip_databases.clone(),
+        ))
+        .attach(analysis::fairing(conn.clone()))
         .manage(conn)
         .manage(config)
+        .manage(providers)
+        .manage(ip_databases)
         .attach(Template::fairing())
 }
+
+fn main() {
+    let args = config::arguments::Opt::from_args();
+    let config = config::Config::from_args(&args);
+    let conn: DbConn = Arc::from(db::open(&config).expect("get db conn"));
+
+    let code = match args.cmd.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            if config.auth.enabled && conn.get_users().expect("get users").is_empty() {
+                eprintln!("No users exist yet, add one with the 'user add <name>' command");
+            }
+            // Only the server runs on the async runtime, the storage is kept until it has stopped
+            // as dropping it may block
+            match rocket::execute(rocket(config, conn.clone()).launch()) {
+                Ok(_o) => cli::EXIT_OK,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    cli::EXIT_FAILURE
+                }
+            }
+        }
+        Command::Fetch => cli::fetch(&config, conn.as_ref()),
+        Command::Import { path } => cli::import(&config, &path, conn.as_ref()),
+        Command::Enrich => cli::enrich(&config, conn.as_ref()),
+        Command::Stats { days } => cli::stats(conn.as_ref(), days),
+        Command::Export { domain, output } => cli::export(conn.as_ref(), domain, output),
+        Command::User { cmd } => cli::user(conn.as_ref(), cmd),
+    };
+    std::process::exit(code);
+}
@@ -0,0 +1,53 @@
+// Aggregate report with a single record shared by the tests
+use crate::extract;
+use crate::report::Report;
+
+const TEMPLATE: &str = include_str!("../../tests/data/report.xml");
+
+#[derive(Debug, Clone)]
+pub struct ReportFixture<'a> {
+    pub org_name: &'a str,
+    pub report_id: &'a str,
+    // The report covers one day from here
+    pub begin: i64,
+    pub domain: &'a str,
+    pub source_ip: &'a str,
+    pub count: u32,
+    // Result of DKIM and SPF, both as evaluated by the receiver and as authentication result
+    pub dkim: &'a str,
+    pub spf: &'a str,
+}
+
+impl Default for ReportFixture<'_> {
+    fn default() -> Self {
+        Self {
+            org_name: "google.com",
+            report_id: "1",
+            begin: 1612137600,
+            domain: "example.com",
+            source_ip: "192.0.2.1",
+            count: 2,
+            dkim: "pass",
+            spf: "pass",
+        }
+    }
+}
+
+impl ReportFixture<'_> {
+    pub fn xml(&self) -> String {
+        TEMPLATE
+            .replace("{org_name}", self.org_name)
+            .replace("{report_id}", self.report_id)
+            .replace("{begin}", &self.begin.to_string())
+            .replace("{end}", &(self.begin + 86399).to_string())
+            .replace("{domain}", self.domain)
+            .replace("{source_ip}", self.source_ip)
+            .replace("{count}", &self.count.to_string())
+            .replace("{dkim}", self.dkim)
+            .replace("{spf}", self.spf)
+    }
+
+    pub fn report(&self) -> Report {
+        extract::parse_report(self.xml().into_bytes()).expect("fixture is a valid report")
+    }
+}
@@ -0,0 +1,38 @@
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct ForensicReport {
+    pub account: Option<String>,
+    pub message_id: Option<String>,
+    pub blob: Option<Vec<u8>>,
+    pub feedback_type: String,
+    pub user_agent: Option<String>,
+    pub arrival_date: Option<String>,
+    pub received_at: i64,
+    pub source_ip: Option<String>,
+    pub reported_domain: Option<String>,
+    pub original_mail_from: Option<String>,
+    pub original_rcpt_to: Option<String>,
+    pub auth_failure: Option<String>,
+    pub authentication_results: Option<String>,
+    pub delivery_result: Option<String>,
+    pub dkim_domain: Option<String>,
+    pub dkim_selector: Option<String>,
+    pub identity_alignment: Option<String>,
+    pub original_from: Option<String>,
+    pub original_subject: Option<String>,
+    pub original_headers: Vec<Header>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+impl ForensicReport {
+    pub fn original_header(&self, name: &str) -> Option<String> {
+        self.original_headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value.clone())
+    }
+}
@@ -1,7 +1,16 @@
+#[cfg(test)]
+pub mod fixture;
+pub mod forensic;
 pub mod serde_defs;
 
+use crate::analysis;
+
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Report {
+    // id of the stored report, 0 until it is stored
+    #[serde(default)]
+    pub id: i64,
+    pub account: Option<String>,
     pub blob: Option<Vec<u8>>,
     pub org_name: String,
     pub email: String,
@@ -9,12 +18,15 @@ pub struct Report {
     pub report_id: String,
     pub date_begin: i64,
     pub date_end: i64,
+    pub errors: Vec<String>,
     pub policy_domain: Option<String>,
     pub policy_adkim: Option<String>,
     pub policy_aspf: Option<String>,
     pub policy_p: Option<String>,
     pub policy_sp: Option<String>,
     pub policy_pct: Option<i8>,
+    pub policy_fo: Option<String>,
+    pub policy_np: Option<String>,
     pub records: Vec<Record>,
 }
 
@@ -25,12 +37,39 @@ pub struct Record {
     pub policy_evaluated_disposition: String,
     pub policy_evaluated_dkim: String,
     pub policy_evaluated_spf: String,
+    pub policy_evaluated_reasons: Vec<PolicyOverrideReason>,
+    pub identifiers_envelope_to: Option<String>,
+    pub identifiers_envelope_from: Option<String>,
     pub identifiers_header_from: String,
-    pub auth_results_dkim_domain: Option<String>,
-    pub auth_results_dkim_result: Option<String>,
-    pub auth_results_dkim_selector: Option<String>,
-    pub auth_results_spf_domain: Option<String>,
-    pub auth_results_spf_result: Option<String>,
+    pub auth_results_dkim: Vec<DkimResult>,
+    pub auth_results_spf: Vec<SpfResult>,
+    // None for records stored before the alignment analysis existed
+    #[serde(default)]
+    pub alignment: Option<analysis::Alignment>,
+    // ISO code of the country of the source IP, if a country database was configured on import
+    #[serde(default)]
+    pub country: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct DkimResult {
+    pub domain: Option<String>,
+    pub selector: Option<String>,
+    pub result: Option<String>,
+    pub human_result: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct SpfResult {
+    pub domain: Option<String>,
+    pub scope: Option<String>,
+    pub result: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct PolicyOverrideReason {
+    pub reason_type: String,
+    pub comment: Option<String>,
 }
 
 impl From<serde_defs::Feedback> for Report {
@@ -44,33 +83,61 @@ impl Report {
         let mut records = Vec::new();
 
         for i in &feedback.record {
-            let dkim = &i.auth_results.dkim.clone().unwrap_or_else(|| {
-                vec![serde_defs::Dkim {
-                    domain: None,
-                    result: None,
-                    selector: None,
-                }]
-            })[0];
-            let spf = &i.auth_results.spf.clone().unwrap_or(serde_defs::Spf {
-                domain: None,
-                result: None,
-            });
+            let dkim = i
+                .auth_results
+                .dkim
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|d| DkimResult {
+                    domain: d.domain,
+                    selector: d.selector,
+                    result: d.result,
+                    human_result: d.human_result,
+                })
+                .collect();
+            let spf = i
+                .auth_results
+                .spf
+                .iter()
+                .map(|s| SpfResult {
+                    domain: s.domain.clone(),
+                    scope: s.scope.clone(),
+                    result: s.result.clone(),
+                })
+                .collect();
+            let reasons = i
+                .row
+                .policy_evaluated
+                .reason
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|r| PolicyOverrideReason {
+                    reason_type: r.reason_type,
+                    comment: r.comment,
+                })
+                .collect();
             records.push(Record {
                 source_ip: i.row.source_ip.clone(),
                 count: i.row.count,
                 policy_evaluated_disposition: i.row.policy_evaluated.disposition.clone(),
                 policy_evaluated_dkim: i.row.policy_evaluated.dkim.clone(),
                 policy_evaluated_spf: i.row.policy_evaluated.spf.clone(),
+                policy_evaluated_reasons: reasons,
+                identifiers_envelope_to: i.identifiers.envelope_to.clone(),
+                identifiers_envelope_from: i.identifiers.envelope_from.clone(),
                 identifiers_header_from: i.identifiers.header_from.clone(),
-                auth_results_dkim_domain: dkim.domain.clone(),
-                auth_results_dkim_result: dkim.result.clone(),
-                auth_results_dkim_selector: dkim.selector.clone(),
-                auth_results_spf_domain: spf.domain.clone(),
-                auth_results_spf_result: spf.result.clone(),
+                auth_results_dkim: dkim,
+                auth_results_spf: spf,
+                alignment: None,
+                country: None,
             });
         }
 
-        Report {
+        let mut report = Report {
+            id: 0,
+            account: None,
             blob,
             org_name: feedback.report_metadata.org_name,
             email: feedback.report_metadata.email,
@@ -78,13 +145,174 @@ impl Report {
             report_id: feedback.report_metadata.report_id,
             date_begin: feedback.report_metadata.date_range.begin,
             date_end: feedback.report_metadata.date_range.end,
+            errors: feedback.report_metadata.error.unwrap_or_default(),
             policy_domain: feedback.policy_published.domain,
             policy_adkim: feedback.policy_published.adkim,
             policy_aspf: feedback.policy_published.aspf,
             policy_p: feedback.policy_published.p,
             policy_sp: feedback.policy_published.sp,
             policy_pct: feedback.policy_published.pct,
+            policy_fo: feedback.policy_published.fo,
+            policy_np: feedback.policy_published.np,
             records,
-        }
+        };
+        analysis::analyze(&mut report);
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_xml_rs::from_reader;
+
+    const FULL_REPORT: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
+<feedback>
+  <report_metadata>
+    <org_name>Example Receiver</org_name>
+    <email>dmarc@receiver.example</email>
+    <extra_contact_info>https://receiver.example/dmarc</extra_contact_info>
+    <report_id>full-1</report_id>
+    <date_range>
+      <begin>1612137600</begin>
+      <end>1612223999</end>
+    </date_range>
+    <error>Policy record could not be parsed</error>
+    <error>Second error</error>
+  </report_metadata>
+  <policy_published>
+    <domain>example.com</domain>
+    <adkim>s</adkim>
+    <aspf>r</aspf>
+    <p>reject</p>
+    <sp>quarantine</sp>
+    <np>reject</np>
+    <pct>100</pct>
+    <fo>1</fo>
+  </policy_published>
+  <record>
+    <row>
+      <source_ip>192.0.2.10</source_ip>
+      <count>3</count>
+      <policy_evaluated>
+        <disposition>none</disposition>
+        <dkim>fail</dkim>
+        <spf>fail</spf>
+        <reason>
+          <type>mailing_list</type>
+          <comment>list.example.org</comment>
+        </reason>
+        <reason>
+          <type>local_policy</type>
+        </reason>
+      </policy_evaluated>
+    </row>
+    <identifiers>
+      <envelope_to>receiver.example</envelope_to>
+      <envelope_from>list.example.org</envelope_from>
+      <header_from>example.com</header_from>
+    </identifiers>
+    <auth_results>
+      <dkim>
+        <domain>esp.example.net</domain>
+        <selector>esp1</selector>
+        <result>pass</result>
+      </dkim>
+      <dkim>
+        <domain>example.com</domain>
+        <selector>mail</selector>
+        <result>fail</result>
+        <human_result>body hash did not verify</human_result>
+      </dkim>
+      <spf>
+        <domain>list.example.org</domain>
+        <scope>mfrom</scope>
+        <result>pass</result>
+      </spf>
+      <spf>
+        <domain>mx.list.example.org</domain>
+        <scope>helo</scope>
+        <result>pass</result>
+      </spf>
+    </auth_results>
+  </record>
+</feedback>
+"#;
+
+    #[test]
+    fn test_full_schema() {
+        let feedback: serde_defs::Feedback =
+            from_reader(std::io::Cursor::new(FULL_REPORT.as_bytes())).unwrap();
+        let report = Report::from(feedback);
+
+        assert_eq!(
+            vec![
+                String::from("Policy record could not be parsed"),
+                String::from("Second error")
+            ],
+            report.errors
+        );
+        assert_eq!(Some(String::from("1")), report.policy_fo);
+        assert_eq!(Some(String::from("reject")), report.policy_np);
+
+        let record = &report.records[0];
+        assert_eq!(
+            vec![
+                PolicyOverrideReason {
+                    reason_type: String::from("mailing_list"),
+                    comment: Some(String::from("list.example.org")),
+                },
+                PolicyOverrideReason {
+                    reason_type: String::from("local_policy"),
+                    comment: None,
+                },
+            ],
+            record.policy_evaluated_reasons
+        );
+        assert_eq!(
+            Some(String::from("receiver.example")),
+            record.identifiers_envelope_to
+        );
+        assert_eq!(
+            Some(String::from("list.example.org")),
+            record.identifiers_envelope_from
+        );
+        assert_eq!(
+            vec![
+                SpfResult {
+                    domain: Some(String::from("list.example.org")),
+                    scope: Some(String::from("mfrom")),
+                    result: Some(String::from("pass")),
+                },
+                SpfResult {
+                    domain: Some(String::from("mx.list.example.org")),
+                    scope: Some(String::from("helo")),
+                    result: Some(String::from("pass")),
+                },
+            ],
+            record.auth_results_spf
+        );
+        assert_eq!(
+            vec![
+                DkimResult {
+                    domain: Some(String::from("esp.example.net")),
+                    selector: Some(String::from("esp1")),
+                    result: Some(String::from("pass")),
+                    human_result: None,
+                },
+                DkimResult {
+                    domain: Some(String::from("example.com")),
+                    selector: Some(String::from("mail")),
+                    result: Some(String::from("fail")),
+                    human_result: Some(String::from("body hash did not verify")),
+                },
+            ],
+            record.auth_results_dkim
+        );
+        // Both pass, but for other domains than example.com
+        assert_eq!(
+            Some(analysis::Verdict::NotAligned),
+            record.alignment.as_ref().map(|a| a.verdict)
+        );
     }
 }
@@ -12,6 +12,7 @@ pub struct ReportMetadata {
     pub extra_contact_info: Option<String>,
     pub report_id: String,
     pub date_range: DateRange,
+    pub error: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
@@ -28,6 +29,8 @@ pub struct PolicyPublished {
     pub p: Option<String>,
     pub sp: Option<String>,
     pub pct: Option<i8>,
+    pub fo: Option<String>,
+    pub np: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
@@ -49,17 +52,29 @@ pub struct PolicyEvaluated {
     pub disposition: String,
     pub dkim: String,
     pub spf: String,
+    pub reason: Option<Vec<PolicyOverrideReason>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct PolicyOverrideReason {
+    #[serde(rename = "type")]
+    pub reason_type: String,
+    pub comment: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Identifiers {
+    pub envelope_to: Option<String>,
+    pub envelope_from: Option<String>,
     pub header_from: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct AuthResults {
     pub dkim: Option<Vec<Dkim>>,
-    pub spf: Option<Spf>,
+    // At least one by the schema, but some reporters leave it out
+    #[serde(default)]
+    pub spf: Vec<Spf>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
@@ -67,10 +82,12 @@ pub struct Dkim {
     pub domain: Option<String>,
     pub result: Option<String>,
     pub selector: Option<String>,
+    pub human_result: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Spf {
     pub domain: Option<String>,
+    pub scope: Option<String>,
     pub result: Option<String>,
 }
@@ -0,0 +1,163 @@
+use chrono::Utc;
+use log::error;
+use rocket::fairing::AdHoc;
+use rocket::tokio::time::MissedTickBehavior;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::alerts;
+use crate::config::Config;
+use crate::db;
+use crate::enrichment;
+use crate::imap_extract;
+
+// Triggers recorded with a fetch run
+pub const TRIGGER_SCHEDULE: &str = "schedule";
+pub const TRIGGER_MANUAL: &str = "manual";
+pub const TRIGGER_CLI: &str = "cli";
+
+// Only one fetch may talk to the IMAP server at a time
+static FETCH_RUNNING: AtomicBool = AtomicBool::new(false);
+
+// Clears FETCH_RUNNING when the fetch ends, even if it panicked
+struct FetchGuard;
+
+impl FetchGuard {
+    fn acquire() -> Option<Self> {
+        FETCH_RUNNING
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_previous| FetchGuard)
+    }
+}
+
+impl Drop for FetchGuard {
+    fn drop(&mut self) {
+        FETCH_RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+fn add_error(run: &mut db::FetchRun, e: anyhow::Error) {
+    let error = format!("{:#}", e);
+    run.error = Some(match run.error.take() {
+        Some(previous) => format!("{}; {}", previous, error),
+        None => error,
+    });
+}
+
+pub fn run_fetch(
+    config: &Config,
+    database: &dyn db::Storage,
+    ip_databases: &enrichment::IpDatabases,
+    trigger: &str,
+) -> db::FetchRun {
+    let started_at = Utc::now().timestamp();
+    let mut run = db::FetchRun {
+        id: 0,
+        trigger: String::from(trigger),
+        started_at,
+        finished_at: None,
+        imported: 0,
+        error: None,
+        log: String::new(),
+    };
+
+    let _guard = match FetchGuard::acquire() {
+        Some(guard) => guard,
+        None => {
+            run.finished_at = Some(started_at);
+            run.error = Some(String::from("Another fetch is already running"));
+            return run;
+        }
+    };
+
+    match database.start_fetch_run(trigger, started_at) {
+        Ok(id) => run.id = id,
+        Err(e) => error!("Could not record fetch run: {}", e),
+    }
+
+    let mut logbuf = Vec::new();
+    let mut imap_extract = imap_extract::ImapExtract::new(config);
+    if let Err(e) = imap_extract.fetch_reports(database, ip_databases, &mut logbuf) {
+        add_error(&mut run, e);
+    }
+    // reports imported before an error are enriched and checked as well, the alerts do not
+    // depend on the enrichment
+    if let Err(e) = enrichment::enrich(config, database, ip_databases, &mut logbuf) {
+        add_error(&mut run, e);
+    }
+    if let Err(e) = alerts::evaluate(config, database, started_at, &mut logbuf) {
+        add_error(&mut run, e);
+    }
+    run.imported = imap_extract.imported();
+    run.log = String::from_utf8_lossy(&logbuf).to_string();
+    run.finished_at = Some(Utc::now().timestamp());
+
+    if run.id != 0 {
+        if let Err(e) = database.finish_fetch_run(&run) {
+            error!("Could not record fetch run: {}", e);
+        }
+    }
+
+    run
+}
+
+pub fn fairing(
+    config: Config,
+    database: Arc<dyn db::Storage>,
+    ip_databases: Arc<enrichment::IpDatabases>,
+) -> AdHoc {
+    AdHoc::on_liftoff("Fetch scheduler", move |_rocket| {
+        Box::pin(async move {
+            if config.fetch_interval == 0 {
+                return;
+            }
+            let period = std::time::Duration::from_secs(config.fetch_interval * 60);
+
+            rocket::tokio::spawn(async move {
+                let mut interval = rocket::tokio::time::interval(period);
+                // A fetch that takes longer than the period is not followed by the missed ones
+                interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                // The first tick completes immediately, the first fetch happens after one period
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let config = config.clone();
+                    let database = database.clone();
+                    let ip_databases = ip_databases.clone();
+                    let result = rocket::tokio::task::spawn_blocking(move || {
+                        run_fetch(&config, database.as_ref(), &ip_databases, TRIGGER_SCHEDULE)
+                    })
+                    .await;
+                    match result {
+                        Ok(run) => {
+                            if let Some(e) = run.error {
+                                error!("Scheduled fetch failed: {}", e);
+                            }
+                        }
+                        Err(e) => error!("Scheduled fetch failed: {}", e),
+                    }
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch_guard() {
+        let guard = FetchGuard::acquire().unwrap();
+        assert!(FetchGuard::acquire().is_none());
+        drop(guard);
+
+        let result = std::panic::catch_unwind(|| {
+            let _guard = FetchGuard::acquire().unwrap();
+            panic!("fetch failed");
+        });
+        assert!(result.is_err());
+        assert!(FetchGuard::acquire().is_some());
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::config;
+use crate::db;
+
+const BUNDLED_PROVIDERS: &str = include_str!("providers.txt");
+
+// Prefix lengths of the networks senders are grouped by
+const IPV4_NETWORK_PREFIX: u8 = 24;
+const IPV6_NETWORK_PREFIX: u8 = 64;
+
+// Provider of a network whose IPs belong to different providers
+const MIXED_PROVIDER: &str = "mixed";
+
+// Networks of known mail providers
+#[derive(Debug)]
+pub struct Providers {
+    networks: Vec<(IpNet, String)>,
+}
+
+impl Providers {
+    pub fn bundled() -> Self {
+        Self::parse(BUNDLED_PROVIDERS).expect("bundled provider list is valid")
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut networks = Vec::new();
+        let mut provider: Option<&str> = None;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                provider = Some(name.trim());
+                continue;
+            }
+            let provider =
+                provider.ok_or_else(|| anyhow!("Line {}: network without provider", number + 1))?;
+            let network: IpNet = line
+                .parse()
+                .with_context(|| format!("Line {}: invalid network '{}'", number + 1, line))?;
+            networks.push((network, String::from(provider)));
+        }
+        // The most specific network is found first
+        networks.sort_by_key(|(network, _provider)| std::cmp::Reverse(network.prefix_len()));
+
+        Ok(Self { networks })
+    }
+
+    pub fn classify(&self, ip: &IpAddr) -> Option<&str> {
+        self.networks
+            .iter()
+            .find(|(network, _provider)| network.contains(ip))
+            .map(|(_network, provider)| provider.as_str())
+    }
+}
+
+pub fn load_providers(config: &config::Config) -> Result<Providers> {
+    match &config.providers_file {
+        Some(path) => Providers::from_file(path),
+        None => Ok(Providers::bundled()),
+    }
+}
+
+// Records of a source IP or network
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Sender {
+    pub source: String,
+    pub provider: Option<String>,
+    // Number of distinct IPs in the network
+    pub ips: usize,
+    pub count: u64,
+    // Pass rates in percent, as evaluated by the receivers
+    pub dmarc_rate: u32,
+    pub dkim_rate: u32,
+    pub spf_rate: u32,
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+fn network(ip: &IpAddr) -> IpNet {
+    let prefix = match ip {
+        IpAddr::V4(_v4) => IPV4_NETWORK_PREFIX,
+        IpAddr::V6(_v6) => IPV6_NETWORK_PREFIX,
+    };
+    IpNet::new(*ip, prefix)
+        .expect("prefix length is valid")
+        .trunc()
+}
+
+fn percent(part: u64, total: u64) -> u32 {
+    if total == 0 {
+        0
+    } else {
+        (u128::from(part) * 100 / u128::from(total)) as u32
+    }
+}
+
+// Sums up the rows per source IP or per network, the biggest senders first
+pub fn aggregate(rows: Vec<db::SenderRow>, by_network: bool, providers: &Providers) -> Vec<Sender> {
+    struct Group {
+        provider: Option<String>,
+        ips: HashSet<String>,
+        row: db::SenderRow,
+    }
+
+    let mut groups: BTreeMap<String, Group> = BTreeMap::new();
+    for row in rows {
+        let ip: Option<IpAddr> = row.source_ip.parse().ok();
+        let source = match (by_network, &ip) {
+            (true, Some(ip)) => network(ip).to_string(),
+            _ => row.source_ip.clone(),
+        };
+        let provider = ip.and_then(|ip| providers.classify(&ip)).map(String::from);
+        match groups.get_mut(&source) {
+            Some(group) => {
+                // A network is only attributed to a provider if all of its IPs are
+                if group.provider != provider {
+                    group.provider = Some(String::from(MIXED_PROVIDER));
+                }
+                group.ips.insert(row.source_ip.clone());
+                group.row.count = group.row.count.saturating_add(row.count);
+                group.row.dmarc_pass = group.row.dmarc_pass.saturating_add(row.dmarc_pass);
+                group.row.dkim_pass = group.row.dkim_pass.saturating_add(row.dkim_pass);
+                group.row.spf_pass = group.row.spf_pass.saturating_add(row.spf_pass);
+                group.row.first_seen = group.row.first_seen.min(row.first_seen);
+                group.row.last_seen = group.row.last_seen.max(row.last_seen);
+            }
+            None => {
+                let group = Group {
+                    provider,
+                    ips: HashSet::from([row.source_ip.clone()]),
+                    row,
+                };
+                groups.insert(source, group);
+            }
+        }
+    }
+
+    let mut senders: Vec<Sender> = groups
+        .into_iter()
+        .map(|(source, group)| Sender {
+            source,
+            provider: group.provider,
+            ips: group.ips.len(),
+            count: group.row.count,
+            dmarc_rate: percent(group.row.dmarc_pass, group.row.count),
+            dkim_rate: percent(group.row.dkim_pass, group.row.count),
+            spf_rate: percent(group.row.spf_pass, group.row.count),
+            first_seen: group.row.first_seen,
+            last_seen: group.row.last_seen,
+        })
+        .collect();
+    senders.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.source.cmp(&b.source)));
+    senders
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(source_ip: &str, count: u64, dmarc_pass: u64, first_seen: i64) -> db::SenderRow {
+        db::SenderRow {
+            source_ip: String::from(source_ip),
+            count,
+            dmarc_pass,
+            dkim_pass: dmarc_pass,
+            spf_pass: 0,
+            first_seen,
+            last_seen: first_seen + 86400,
+        }
+    }
+
+    #[test]
+    fn test_providers() {
+        let providers = Providers::bundled();
+        let classify = |ip: &str| providers.classify(&ip.parse().unwrap());
+        assert_eq!(Some("Google"), classify("209.85.220.41"));
+        assert_eq!(Some("Microsoft 365"), classify("2a01:111:f400:7e1b::1"));
+        assert_eq!(None, classify("192.0.2.1"));
+
+        let providers = Providers::parse("[Test]\n192.0.2.0/24\n[Inner]\n192.0.2.128/25").unwrap();
+        assert_eq!(
+            Some("Inner"),
+            providers.classify(&"192.0.2.200".parse().unwrap())
+        );
+        assert!(Providers::parse("192.0.2.0/24").is_err());
+        assert!(Providers::parse("[Test]\n192.0.2.0/33").is_err());
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let providers = Providers::bundled();
+        let rows = || {
+            vec![
+                row("192.0.2.1", 10, 10, 100),
+                row("192.0.2.7", 30, 0, 50),
+                row("209.85.220.41", 5, 5, 200),
+                // the same IP from a second domain
+                row("192.0.2.1", 10, 5, 300),
+            ]
+        };
+
+        let senders = aggregate(rows(), false, &providers);
+        assert_eq!(3, senders.len());
+        assert_eq!("192.0.2.7", senders[0].source);
+        assert_eq!(20, senders[1].count);
+        assert_eq!(75, senders[1].dmarc_rate);
+        assert_eq!((100, 86700), (senders[1].first_seen, senders[1].last_seen));
+        assert_eq!(Some(String::from("Google")), senders[2].provider);
+
+        let senders = aggregate(rows(), true, &providers);
+        assert_eq!(2, senders.len());
+        assert_eq!("192.0.2.0/24", senders[0].source);
+        assert_eq!((2, 50), (senders[0].ips, senders[0].count));
+        assert_eq!(30, senders[0].dmarc_rate);
+
+        // Counts beyond 32 bits and a network shared by two providers
+        let providers = Providers::parse("[Inner]\n192.0.2.128/25").unwrap();
+        let senders = aggregate(
+            vec![
+                row("192.0.2.1", u64::from(u32::MAX), 0, 100),
+                row("192.0.2.200", u64::from(u32::MAX), 0, 100),
+            ],
+            true,
+            &providers,
+        );
+        assert_eq!(2 * u64::from(u32::MAX), senders[0].count);
+        assert_eq!(Some(String::from(MIXED_PROVIDER)), senders[0].provider);
+    }
+}